@@ -0,0 +1,144 @@
+// Command line arguments
+use std::{net::Ipv4Addr, process::exit, str::FromStr};
+
+use crate::{http::Url, ip::{parse_dscp, IdMode, SendOptions}};
+
+/// The parsed command line arguments.
+pub struct Args {
+    /// What to request
+    pub url: Url,
+    /// The ethernet interface to use
+    pub interface: String,
+    /// The IPv4 address of the gateway
+    pub gateway: Ipv4Addr,
+    /// The TCP port to connect from, a free ephemeral port if None
+    pub port: Option<u16>,
+    /// Install firewall rules keeping the kernel off the TCP port
+    pub guard: bool,
+    /// Print the firewall rules instead of installing them
+    pub dry_run: bool,
+    /// Options for the IP header of each packet we send
+    pub send_options: SendOptions,
+}
+
+/// Parse the command line arguments.
+///
+/// Options (starting with `--`) may appear anywhere, everything
+/// else are the positional arguments `<URL> <interface> <gateway> [<TCP port>]`.
+///
+/// Prints the help message and exits on `--help`, or if no arguments given.
+///
+pub fn parse() -> Args {
+    let mut send_options = SendOptions::default();
+    let mut guard = true;
+    let mut dry_run = false;
+    let mut positional = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => help_message(0),
+            "--no-guard" => guard = false,
+            "--dry-run" => dry_run = true,
+            "--ttl" => send_options.ttl = value(&mut args, "--ttl"),
+            "--dscp" => {
+                let dscp: String = value(&mut args, "--dscp");
+                send_options.dscp = parse_dscp(&dscp).unwrap_or_else(|| invalid("--dscp", &dscp));
+            },
+            "--ecn" => {
+                let ecn: u8 = value(&mut args, "--ecn");
+                if ecn > 3 {
+                    invalid("--ecn", &ecn.to_string());
+                }
+                send_options.ecn = ecn;
+            },
+            "--df" => send_options.dont_fragment = true,
+            "--ip-id" => {
+                let mode: String = value(&mut args, "--ip-id");
+                send_options.id_mode = match mode.as_str() {
+                    "seq" | "sequential" => IdMode::Sequential,
+                    "random" => IdMode::Random,
+                    id => IdMode::Fixed(parse_u16(id).unwrap_or_else(|| invalid("--ip-id", id))),
+                };
+            },
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+
+    // Get the URL, or else display help and return
+    let url = positional.next().unwrap_or_else(|| help_message(1));
+    let url = Url::parse(&url).unwrap_or_else(|| invalid("<URL>", &url));
+
+    // interface name
+    let interface = positional.next().expect("Expected interface name");
+
+    // gateway IP
+    let gateway: Ipv4Addr = positional.next().expect("Expected gateway IP").parse().expect("Expected IPv4 address");
+
+    // binding port
+    let port: Option<u16> = positional.next().map(|p| p.parse().expect("Cannot parse port"));
+
+    Args {
+        url,
+        interface,
+        gateway,
+        port,
+        guard,
+        dry_run,
+        send_options,
+    }
+}
+
+/// Get the value following an option, or exit if missing or not parseable.
+fn value<T: FromStr>(args: &mut impl Iterator<Item = String>, option: &str) -> T {
+    let Some(v) = args.next() else {
+        println!("{}: Missing value.", option);
+        exit(1);
+    };
+
+    v.parse().unwrap_or_else(|_| invalid(option, &v))
+}
+
+/// Report an invalid option value and exit.
+fn invalid(option: &str, value: &str) -> ! {
+    println!("{}: Invalid value '{}'.", option, value);
+    exit(1)
+}
+
+/// Parse a u16 either decimal or hexadecimal (0x prefix).
+fn parse_u16(s: &str) -> Option<u16> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    }else{
+        s.parse().ok()
+    }
+}
+
+/// Help message.
+///
+fn help_message(code: i32) -> ! {
+    println!("Usage:    cargo run --release -p demo-http-tcp-ipv4 -- [options] <URL> <interface> <gateway> [<TCP port>]\n");
+    println!("  --help, -h    Display this help message");
+    println!("  <URL>         What to GET, e.g. http://example.com/ (https is not supported).");
+    println!("  <interface>   The ethernet interface to send through.");
+    println!("  <gateway>     The gateway IP address. The IPv4 address of your internet router.");
+    println!("                Normally something like 192.168.x.1 or similar.");
+    println!("  <TCP port>    Port to connect from (or better said steel the port).");
+    println!("                Must not be in use by the kernel. If not given, a free port of");
+    println!("                the ephemeral range is chosen.");
+    println!("  --no-guard    Do not install firewall rules dropping the traffic to the port");
+    println!("                before the kernel answers it with a reset.");
+    println!("  --dry-run     Print the firewall rules instead of installing them.\n");
+    println!("IP header options:");
+    println!("  --ttl <n>               Time to live (default 60).");
+    println!("  --dscp <class>          DSCP as number or name, e.g. EF, CS6, AF41 (default 0).");
+    println!("  --ecn <0-3>             ECN bits (default 0).");
+    println!("  --df                    Set the don't fragment flag.");
+    println!("  --ip-id <id|seq|random> Fixed ID, sequential or random IDs (default 0xcc80).\n");
+    println!("Bug reports issue at https://github.com/RossAdrian/raw-networking-demo/.");
+
+
+    exit(code)
+}
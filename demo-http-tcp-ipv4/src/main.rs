@@ -0,0 +1,256 @@
+use std::{io::{self, ErrorKind, Write}, net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs}, process::exit};
+
+use link::{create_arp, unwrap_arp};
+use pnet::datalink::{self, Channel};
+use std::time::{Duration, Instant};
+
+use raw_stack_ipv4::{firewall, ip, link, port, tcp}; // Ethernet, IP, TCP, ports and firewall guard
+use raw_http as http; // Application layer
+
+mod args; // Command line arguments
+
+/// How long to wait for data of the server before giving up.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The main function.
+/// 
+/// Command line arguments:
+/// 
+/// 1. URL (What to GET, like http://example.com/)
+/// 2. Interface name (The ethernet interface to use, normaly something like eth0 or enp1s0)
+/// 3. Gateway IP (Commonly something like 192.168.x.1)
+/// 4. Bind port (optional, some free TCP port to connect from)
+/// 
+/// Options for the IP header (TTL, DSCP/ECN, DF, ID) may be given
+/// anywhere in between, see `--help`.
+/// 
+/// # Security
+/// 
+/// Do not consider this as a security note for rust safty.
+/// 
+/// Consider this rather as a operating system security note.
+/// 
+/// Do make sure the bind port is free and not allocated while usage
+/// of this program. Since we kind of *steal* the port, that would be
+/// allocated and managed by the operating system, it could conflict
+/// with other services that currently uses this port, and cause
+/// undefined behavior.
+/// 
+/// The port is checked against the sockets of the kernel, and if
+/// left out, a free ephemeral port is chosen.
+/// 
+fn main() {
+    // Get and parse commandline arguments
+    let args::Args { url, interface: interface_name, gateway: gateway_ip, port, guard, dry_run, send_options } = args::parse();
+
+    // Resolve the host first, with the resolver of the system
+    let remote = resolve(&url.host, url.port).unwrap_or_else(|e| {
+        println!("Could not resolve {}: {}", url.host, e);
+        exit(-1);
+    });
+
+    // Get the interface we search
+    let interface = {
+        let interfaces = pnet::datalink::interfaces();
+        let mut interface = None;
+
+        for i in interfaces {
+            if i.name == interface_name {
+                interface = Some(i);
+                break;
+            }
+        }
+
+        if let Some(i) = interface {
+            i
+        }else{
+            println!("{}: No such interface.", interface_name);
+            exit(-1);
+        }
+    };
+
+    let this_mac = interface.mac.expect("Interface has no mac address");
+    let this_ip = {
+        let i: Vec<_> = interface.ips.iter().filter(|i| i.is_ipv4()).collect();
+        let i = i.first().expect("Interface has no IP address assigned").ip();
+
+        if let IpAddr::V4(addr) = i {
+            addr
+        }else{
+            println!("Wrong IP address found, expected IPv4 address.");
+            exit(0);
+        }
+    };
+
+    // Create the channel, with a read timeout so we can check our own timeouts
+    let config = datalink::Config {
+        read_timeout: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let channel = datalink::channel(&interface, config);
+    let (mut tx,mut rx) = match channel {
+        Ok(Channel::Ethernet(tx,rx)) => (tx,rx),
+        Ok(_) => panic!("Unhandled channel type"),
+        Err(e) => panic!("Error when creating raw socket: {}",e),
+    };
+
+    // first, setup ARP request to get gateway MAC
+    let gateway_mac = {
+        let mut res;
+
+        // Create ARP packet to send
+        let packet = create_arp(this_mac.octets(), this_ip.octets(), gateway_ip.octets());
+
+        // send ARP packet
+        tx.send_to(&packet, None);
+
+        // current time
+        let t_prev = Instant::now();
+
+        // loop to get requests
+        loop {
+            match rx.next() {
+                Ok(frame) => {
+                    // try to parse ethernet frame.
+                    res = unwrap_arp(frame, this_mac.octets(), this_ip.octets(), gateway_ip.octets());
+                },
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    res = None;
+                },
+                Err(e) => {
+                    panic!("Error occured: {}.", e);
+                }
+            }
+
+            // break loop if we found mac
+            if res.is_some() {
+                break;
+            }
+
+            // if not, check if timeout, else continue
+            if (Instant::now() - t_prev).as_secs_f32() > 3.0 {
+                // timeout
+                println!("ARP request timeout!");
+                exit(-1);
+            }
+        }
+
+        // safety: It would be never callen if not satisfied above as Some(_)
+        res.unwrap()
+    };
+
+    // HTTP runs on top of the stack
+    let stack = ip::Stack {
+        tx,
+        rx,
+        this_mac: this_mac.octets(),
+        this_ip,
+        gateway_mac,
+        mtu: link::interface_mtu(&interface.name),
+        send_options,
+        neighbors: None,
+    };
+
+    let port = local_port(port);
+
+    // keep the kernel from resetting the connection
+    if guard {
+        install_guard(dry_run, this_ip, port);
+    }
+
+    let result = get(stack, port, remote, &url);
+    firewall::remove();
+
+    if let Err(e) = result {
+        println!("HTTP request failed: {}", e);
+        exit(-1);
+    }
+}
+
+/// Connect to the server, GET the URL and print the response.
+///
+/// The status line and the headers go to stdout as received, followed
+/// by an empty line and the body, decoded if it was chunked.
+///
+fn get(stack: ip::Stack, port: u16, remote: SocketAddrV4, url: &http::Url) -> io::Result<()> {
+    let mut stream = tcp::RawTcpStream::connect(stack, port, remote)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT));
+
+    println!("Connected to {} from {}.\n", stream.peer_addr(), stream.local_addr());
+
+    let response = http::request(&mut stream, "GET", url)?;
+
+    println!("{} {} {}", response.version, response.status, response.reason);
+    for (name, value) in &response.headers {
+        println!("{}: {}", name, value);
+    }
+    println!();
+
+    let mut stdout = io::stdout();
+    stdout.write_all(&response.body)?;
+    stdout.flush()
+}
+
+/// Resolve a host name (or address) to its first IPv4 address.
+fn resolve(host: &str, port: u16) -> io::Result<SocketAddrV4> {
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        return Ok(SocketAddrV4::new(ip, port));
+    }
+
+    (host, port).to_socket_addrs()?
+        .find_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "No IPv4 address"))
+}
+
+/// Get the port to steal.
+///
+/// Checks the given port is not in use by the kernel, or chooses a free
+/// ephemeral port if none given. Exits if the port is in use.
+///
+fn local_port(port: Option<u16>) -> u16 {
+    match port {
+        Some(port) => {
+            match port::ports_in_use(port::Protocol::Tcp) {
+                Ok(ports) if ports.contains(&port) => {
+                    println!("TCP port {} is in use by the kernel, choose another one or leave it out.", port);
+                    exit(-1);
+                },
+                Ok(_) => {},
+                Err(e) => println!("Could not check if TCP port {} is free: {}", port, e),
+            }
+
+            port
+        },
+        None => {
+            let port = port::choose_ephemeral_port(port::Protocol::Tcp).unwrap_or_else(|e| {
+                println!("Could not choose a TCP port: {}", e);
+                exit(-1);
+            });
+
+            println!("Using TCP port {}.", port);
+            port
+        },
+    }
+}
+
+/// Install the firewall rules keeping the kernel off our port, or print
+/// them on a dry run.
+///
+/// Only reports if this fails, we try anyway.
+///
+fn install_guard(dry_run: bool, this_ip: Ipv4Addr, port: u16) {
+    match firewall::detect() {
+        // without firewall, show the nftables rules
+        None if dry_run => firewall::rules(firewall::Backend::Nft, this_ip, port::Protocol::Tcp, port).print(),
+        Some(backend) if dry_run => firewall::rules(backend, this_ip, port::Protocol::Tcp, port).print(),
+        Some(backend) => {
+            if let Err(e) = firewall::install(firewall::rules(backend, this_ip, port::Protocol::Tcp, port)) {
+                println!("Could not install firewall rules: {}", e);
+            }
+        },
+        None => println!("Neither nft nor iptables found, the kernel may reset our TCP connection."),
+    }
+}
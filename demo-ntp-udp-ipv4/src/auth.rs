@@ -0,0 +1,325 @@
+// NTP symmetric key authentication (MAC trailers with keys of an ntp.keys file)
+use std::{fmt, fs, io::{self, ErrorKind}};
+
+use aes::Aes128;
+use cmac::{Cmac, Mac as _};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+use crate::ntp::{Mac, NtpPacket};
+
+/// Length of an AES-128 key, in bytes
+const AES_128_KEY_LEN: usize = 16;
+
+/// Longest key ntpd takes as ASCII, longer keys are hexadecimal
+const MAX_ASCII_KEY_LEN: usize = 20;
+
+/// The digest algorithm of a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    /// MD5 over key and packet, 16 bytes (RFC 5905, Section 7.3)
+    Md5,
+    /// SHA-1 over key and packet, 20 bytes, like ntpd and chronyd compute it
+    Sha1,
+    /// AES-CMAC with a 128 bit key over the packet, 16 bytes (RFC 8573)
+    AesCmac,
+}
+
+impl Algorithm {
+    /// The algorithm of a key type in a keys file, as ntpd or chronyd write it.
+    fn parse(name: &str) -> Option<Algorithm> {
+        match name.to_ascii_uppercase().as_str() {
+            "M" | "MD5" => Some(Algorithm::Md5),
+            "SHA1" | "SHA-1" => Some(Algorithm::Sha1),
+            "AES128CMAC" | "AES-128-CMAC" | "AES128" | "CMAC" => Some(Algorithm::AesCmac),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Algorithm::Md5 => write!(f, "MD5"),
+            Algorithm::Sha1 => write!(f, "SHA-1"),
+            Algorithm::AesCmac => write!(f, "AES-CMAC"),
+        }
+    }
+}
+
+/// A symmetric key shared with a server.
+#[derive(Clone)]
+pub struct Key {
+    /// The key ID, sent with the MAC so the other side knows the key
+    pub id: u32,
+    /// The digest algorithm
+    pub algorithm: Algorithm,
+    /// The secret
+    secret: Vec<u8>,
+}
+
+impl Key {
+    /// The digest of a packet, without its MAC.
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            // the legacy digests hash the key in front of the packet
+            Algorithm::Md5 => Md5::new().chain_update(&self.secret).chain_update(data).finalize().to_vec(),
+            Algorithm::Sha1 => Sha1::new().chain_update(&self.secret).chain_update(data).finalize().to_vec(),
+            Algorithm::AesCmac => {
+                let mut mac = <Cmac<Aes128> as cmac::Mac>::new_from_slice(&self.secret).expect("AES-128 key of wrong length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            },
+        }
+    }
+
+    /// Add our MAC to a packet.
+    ///
+    /// Returns the packet encoded with the MAC, which covers everything
+    /// in front of it.
+    ///
+    pub fn sign(&self, packet: &NtpPacket) -> Vec<u8> {
+        let mut data = NtpPacket { mac: None, ..packet.clone() }.encode();
+
+        let digest = self.digest(&data);
+        data.extend_from_slice(&self.id.to_be_bytes());
+        data.extend_from_slice(&digest);
+
+        data
+    }
+
+    /// Check the MAC of a received packet was computed with this key.
+    ///
+    /// data: The packet as received
+    /// packet: The packet decoded
+    ///
+    /// Returns an error of kind InvalidData naming why if the MAC is missing,
+    /// a crypto-NAK, of another key or does not verify.
+    ///
+    pub fn verify(&self, data: &[u8], packet: &NtpPacket) -> io::Result<()> {
+        let invalid = |message: String| Err(io::Error::new(ErrorKind::InvalidData, message));
+
+        let Some(mac) = &packet.mac else {
+            return invalid(format!("Reply is not authenticated, but we sent key {}", self.id));
+        };
+
+        // a MAC of just a zero key ID: the server does not know our key, or the digest did not verify
+        if mac.key_id == 0 && mac.digest.is_empty() {
+            return invalid(format!("Server sent a crypto-NAK, it does not accept key {}", self.id));
+        }
+
+        if mac.key_id != self.id {
+            return invalid(format!("Reply is authenticated with key {}, not {}", mac.key_id, self.id));
+        }
+
+        let covered = &data[..data.len() - 4 - mac.digest.len()];
+        if !constant_time_eq(&self.digest(covered), &mac.digest) {
+            return invalid(format!("Reply MAC does not verify with key {} ({})", self.id, self.algorithm));
+        }
+
+        Ok(())
+    }
+}
+
+/// The MAC of a crypto-NAK, a key ID of 0 without digest (RFC 5905, Section 7.4).
+pub fn crypto_nak() -> Mac {
+    Mac {
+        key_id: 0,
+        digest: Vec::new(),
+    }
+}
+
+/// Compare two digests, taking the same time wherever they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Read the keys of an ntp.keys file, as ntpd or chronyd use it.
+///
+/// Each line has a key ID (1 to 65535), a type and the key, `#` starts a
+/// comment:
+///
+/// ```text
+/// 1 MD5 secret
+/// 2 SHA1 HEX:2bfc7ee6bb3c7ba1e8a2b1d6f74d5b4c1e0a9f33
+/// 3 AES128CMAC 0123456789abcdef0123456789abcdef
+/// ```
+///
+/// The key is ASCII, or hexadecimal if longer than 20 characters (ntpd),
+/// chronyd's `ASCII:` and `HEX:` prefixes say it explicitly.
+///
+pub fn read_keys(path: &str) -> io::Result<Vec<Key>> {
+    parse_keys(&fs::read_to_string(path)?)
+}
+
+/// Parse the keys of a keys file, see `read_keys`.
+fn parse_keys(text: &str) -> io::Result<Vec<Key>> {
+    let mut keys = Vec::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+
+        let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, format!("Line {}: {}", n + 1, message));
+
+        let [id, algorithm, secret] = fields[..] else {
+            return Err(invalid("Expected <key ID> <type> <key>"));
+        };
+        let id: u32 = id.parse().ok().filter(|id| (1..=65535).contains(id)).ok_or_else(|| invalid("Invalid key ID"))?;
+        let algorithm = Algorithm::parse(algorithm).ok_or_else(|| invalid(&format!("Unsupported key type '{}'", algorithm)))?;
+        let secret = parse_secret(secret).ok_or_else(|| invalid("Invalid key"))?;
+
+        if algorithm == Algorithm::AesCmac && secret.len() != AES_128_KEY_LEN {
+            return Err(invalid("AES-CMAC needs a key of 16 bytes (32 hexadecimal digits)"));
+        }
+
+        keys.push(Key { id, algorithm, secret });
+    }
+
+    Ok(keys)
+}
+
+/// Parse the key of a keys file line.
+fn parse_secret(secret: &str) -> Option<Vec<u8>> {
+    if let Some(ascii) = secret.strip_prefix("ASCII:") {
+        return Some(ascii.as_bytes().to_vec());
+    }
+
+    let hex = match secret.strip_prefix("HEX:") {
+        Some(hex) => hex,
+        None if secret.len() <= MAX_ASCII_KEY_LEN => return Some(secret.as_bytes().to_vec()),
+        None => secret,
+    };
+
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A key from hexadecimal digits.
+    fn key(id: u32, algorithm: Algorithm, hex: &str) -> Key {
+        Key { id, algorithm, secret: parse_secret(&format!("HEX:{}", hex)).expect("Invalid test key") }
+    }
+
+    /// Bytes from hexadecimal digits.
+    fn bytes(hex: &str) -> Vec<u8> {
+        parse_secret(&format!("HEX:{}", hex)).expect("Invalid test data")
+    }
+
+    #[test]
+    fn aes_cmac_rfc_4493() {
+        // RFC 4493, Section 4, examples 1 to 4
+        let key = key(1, Algorithm::AesCmac, "2b7e151628aed2a6abf7158809cf4f3c");
+        let message = bytes(concat!(
+            "6bc1bee22e409f96e93d7e117393172a", "ae2d8a571e03ac9c9eb76fac45af8e51",
+            "30c81c46a35ce411e5fbc1191a0a52ef", "f69f2445df4f9b17ad2b417be66c3710",
+        ));
+
+        for (len, mac) in [
+            (0, "bb1d6929e95937287fa37d129b756746"),
+            (16, "070a16b46b4d4144f79bdd9dd04a287c"),
+            (40, "dfa66747de9ae63030ca32611497c827"),
+            (64, "51f0bebf7e3b9d92fc49741779363cfe"),
+        ] {
+            assert_eq!(key.digest(&message[..len]), bytes(mac), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn legacy_digests_over_key_and_packet() {
+        let request = NtpPacket::client_request().encode();
+
+        let md5 = Key { id: 1, algorithm: Algorithm::Md5, secret: b"secret".to_vec() };
+        assert_eq!(md5.digest(&request), bytes("e090dd0f2a35e38783a54445cb1e6933"));
+
+        let sha1 = Key { id: 1, algorithm: Algorithm::Sha1, secret: b"secret".to_vec() };
+        assert_eq!(sha1.digest(&request), bytes("ddf086dcbfd187aefb251326ca916acfcdc50225"));
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        for algorithm in [Algorithm::Md5, Algorithm::Sha1, Algorithm::AesCmac] {
+            let key = key(42, algorithm, "000102030405060708090a0b0c0d0e0f");
+
+            let data = key.sign(&NtpPacket::client_request());
+            let packet = NtpPacket::decode(&data).expect("Signed packet does not decode");
+            assert_eq!(packet.mac.as_ref().map(|mac| mac.key_id), Some(42));
+            assert!(key.verify(&data, &packet).is_ok(), "{}", algorithm);
+
+            // a bit flipped in the header
+            let mut tampered = data.clone();
+            tampered[47] ^= 0x01;
+            let packet = NtpPacket::decode(&tampered).unwrap();
+            assert!(key.verify(&tampered, &packet).is_err(), "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn crypto_nak_and_other_keys_rejected() {
+        let key = key(1, Algorithm::Md5, "73656372657421");
+
+        let nak = NtpPacket { mac: Some(crypto_nak()), ..NtpPacket::client_request() };
+        let data = nak.encode();
+        let e = key.verify(&data, &NtpPacket::decode(&data).unwrap()).unwrap_err();
+        assert!(e.to_string().contains("crypto-NAK"), "{}", e);
+
+        // the same secret, but another key ID
+        let other = Key { id: 2, ..key.clone() };
+        let data = other.sign(&NtpPacket::client_request());
+        let e = key.verify(&data, &NtpPacket::decode(&data).unwrap()).unwrap_err();
+        assert!(e.to_string().contains("key 2, not 1"), "{}", e);
+
+        // no MAC at all
+        let data = NtpPacket::client_request().encode();
+        assert!(key.verify(&data, &NtpPacket::decode(&data).unwrap()).is_err());
+    }
+
+    #[test]
+    fn keys_file() {
+        let keys = parse_keys("\
+# comment
+1 MD5 abcdefghijklmnopqrst
+
+2 SHA1 2bfc7ee6bb3c7ba1e8a2b1d6f74d5b4c1e0a9f33  # 40 digits
+3 M HEX:6162
+4 MD5 ASCII:0123456789abcdef0123456789
+65535 AES128CMAC 2b7e151628aed2a6abf7158809cf4f3c
+").unwrap();
+
+        let parsed: Vec<(u32, Algorithm, &[u8])> = keys.iter().map(|key| (key.id, key.algorithm, &key.secret[..])).collect();
+        assert_eq!(parsed, [
+            // up to 20 characters are ASCII, longer keys hexadecimal
+            (1, Algorithm::Md5, &b"abcdefghijklmnopqrst"[..]),
+            (2, Algorithm::Sha1, &bytes("2bfc7ee6bb3c7ba1e8a2b1d6f74d5b4c1e0a9f33")[..]),
+            (3, Algorithm::Md5, &b"ab"[..]),
+            (4, Algorithm::Md5, &b"0123456789abcdef0123456789"[..]),
+            (65535, Algorithm::AesCmac, &bytes("2b7e151628aed2a6abf7158809cf4f3c")[..]),
+        ]);
+    }
+
+    #[test]
+    fn keys_file_errors() {
+        for line in [
+            "0 MD5 secret",
+            "65536 MD5 secret",
+            "1 AES128CMAC HEX:00112233",
+            "1 AES128CMAC 2b7e151628aed2a6abf7158809cf4f",
+            "1 MD5 0123456789abcdef0123xy",
+            "1 SHA256 secret",
+            "1 MD5",
+        ] {
+            assert!(parse_keys(line).is_err(), "{}", line);
+        }
+    }
+}
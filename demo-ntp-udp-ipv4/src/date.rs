@@ -0,0 +1,118 @@
+// HTTP Date (time from the Date header of a web server, if NTP is blocked)
+use std::{io::{self, ErrorKind}, net::SocketAddrV4, time::Duration};
+
+use chrono::{DateTime, NaiveDateTime};
+use raw_http::Url;
+
+use crate::{ip::{self, Stack}, ntp, tcp::RawTcpStream, timestamp::NtpTimestamp};
+
+/// Resolve `host[:port]` to the first IPv4 address of the host, port 80 if
+/// left out.
+///
+/// Returns the address and the URL to ask, `/` of the host.
+///
+pub fn resolve(server: &str) -> io::Result<(SocketAddrV4, Url)> {
+    let url = Url::parse(server).ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("Invalid server '{}'", server)))?;
+
+    Ok((ip::resolve(server, 80)?, url))
+}
+
+/// Request the time from the Date header of a web server.
+///
+/// Sends `HEAD /` and reads the header of the response, whatever its status,
+/// since servers send the Date header with every response (RFC 7231,
+/// Section 7.1.1.2).
+///
+/// The Date header has whole seconds, and the server truncates its clock to
+/// them, so the time is only precise to one second (and half the round trip
+/// time), unlike the fraction NTP gives.
+///
+/// stack: The stack to send through
+/// port: Our TCP port
+/// server: Address and port of the web server
+/// url: What to ask, the host for the Host header
+/// timeout: How long to wait for data of the server
+///
+/// Returns the timestamp in the same format as `ntp::get_timestamp`.
+///
+pub fn request_time(stack: Stack, port: u16, server: SocketAddrV4, url: &Url, timeout: Duration) -> io::Result<String> {
+    let mut stream = RawTcpStream::connect(stack, port, server)?;
+    stream.set_read_timeout(Some(timeout));
+
+    let response = raw_http::request(&mut stream, "HEAD", url)?;
+    stream.shutdown()?;
+
+    // the first Date field counts
+    let Some((_, date)) = response.headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("Date")) else {
+        return Err(io::Error::new(ErrorKind::NotFound, format!("No Date header in the response '{} {}'", response.status, response.reason)));
+    };
+
+    let time = parse_http_date(date).ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("Invalid Date header '{}'", date)))?;
+
+    // whole seconds, through the same output as the NTP time
+    let time = DateTime::from_timestamp(time, 0).expect("Invalid timestamp");
+    Ok(ntp::get_timestamp(NtpTimestamp::from(time)))
+}
+
+/// Parse an HTTP date to a Unix timestamp (RFC 7231, Section 7.1.1.1).
+///
+/// Servers send the IMF-fixdate format, `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// but the obsolete RFC 850 format `Sunday, 06-Nov-94 08:49:37 GMT` and
+/// the asctime format `Sun Nov  6 08:49:37 1994` must be accepted as well.
+/// The two-digit years of RFC 850 are taken as 1970 to 2069.
+///
+fn parse_http_date(date: &str) -> Option<i64> {
+    // IMF-fixdate, RFC 850 and asctime, all in UTC
+    let formats = ["%a, %d %b %Y %H:%M:%S GMT", "%A, %d-%b-%y %H:%M:%S GMT", "%a %b %e %H:%M:%S %Y"];
+
+    formats.iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        .map(|time| time.and_utc().timestamp())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example of RFC 7231, Section 7.1.1.1, as Unix timestamp.
+    const EXAMPLE: i64 = 784111777;
+
+    #[test]
+    fn imf_fixdate() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(EXAMPLE));
+    }
+
+    #[test]
+    fn rfc_850() {
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(EXAMPLE));
+    }
+
+    #[test]
+    fn asctime() {
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(EXAMPLE));
+        assert_eq!(parse_http_date("Wed Nov 16 08:49:37 1994"), Some(EXAMPLE + 10 * 86400));
+    }
+
+    #[test]
+    fn two_digit_year_pivot() {
+        // 70 to 99 are 1970 to 1999, 00 to 69 are 2000 to 2069
+        assert_eq!(parse_http_date("Thursday, 01-Jan-70 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Friday, 31-Dec-99 00:00:00 GMT"), Some(946598400));
+        assert_eq!(parse_http_date("Tuesday, 31-Dec-69 00:00:00 GMT"), Some(3155673600));
+    }
+
+    #[test]
+    fn malformed_dates_rejected() {
+        for date in [
+            "",
+            "Sun, 06 Nov 1994 08:49:37 PST",
+            "Mon, 06 Nov 1994 08:49:37 GMT",
+            "Sun, 31 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:49:37 GMT",
+            "1994-11-06T08:49:37Z",
+        ] {
+            assert_eq!(parse_http_date(date), None, "{}", date);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// ICMP (internet layer, carried in IPv4)
+use std::{io::{self, ErrorKind}, net::{Ipv4Addr, SocketAddrV4}};
+
+use crate::ip::{compute_checksum, Ipv4Packet, PROTOCOL_ICMP, PROTOCOL_UDP};
+
+/// ICMP type Echo Reply
+pub const ECHO_REPLY: u8 = 0;
+
+/// ICMP type Destination Unreachable
+pub const DESTINATION_UNREACHABLE: u8 = 3;
+
+/// ICMP type Echo Request
+pub const ECHO_REQUEST: u8 = 8;
+
+/// ICMP type Time Exceeded
+pub const TIME_EXCEEDED: u8 = 11;
+
+/// Create an ICMP message.
+///
+/// icmp_type: The ICMP message type
+/// code: The ICMP message code
+/// rest: The 4 bytes following the checksum (meaning depends on the type)
+/// payload: The message body
+///
+pub fn create_message(icmp_type: u8, code: u8, rest: [u8; 4], payload: &[u8]) -> Vec<u8> {
+    // type, code, checksum 0 for now
+    let mut data = vec![icmp_type, code, 0, 0];
+
+    // rest of header
+    data.extend_from_slice(&rest);
+
+    // message body
+    data.extend_from_slice(payload);
+
+    // checksum over the whole message (RFC 792)
+    let checksum = checksum(&data).to_be_bytes();
+    data[2] = checksum[0];
+    data[3] = checksum[1];
+
+    data
+}
+
+/// Compute the ICMP checksum of a message.
+///
+/// The checksum field is expected to be zero. Same as the IPv4
+/// header checksum, but the message may have an odd length.
+///
+pub fn checksum(message: &[u8]) -> u16 {
+    if message.len() % 2 == 1 {
+        // padd
+        let mut padded = message.to_vec();
+        padded.push(0x00);
+        compute_checksum(&padded)
+    }else{
+        compute_checksum(message)
+    }
+}
+
+/// Check the checksum of a received ICMP message.
+pub fn verify_checksum(message: &[u8]) -> bool {
+    if message.len() < 4 {
+        return false;
+    }
+
+    let mut check = message.to_vec();
+    check[2] = 0;
+    check[3] = 0;
+
+    checksum(&check).to_be_bytes() == message[2..4]
+}
+
+/// Create an ICMP Echo Request (RFC 792).
+///
+/// identifier: Identifies our ping session
+/// sequence: The sequence number of this request
+/// payload: Arbitrary data, echoed back by the target
+///
+pub fn create_echo_request(identifier: u16, sequence: u16, payload: &[u8]) -> Vec<u8> {
+    let id = identifier.to_be_bytes();
+    let seq = sequence.to_be_bytes();
+
+    create_message(ECHO_REQUEST, 0, [id[0], id[1], seq[0], seq[1]], payload)
+}
+
+/// A received ICMP Echo Reply.
+pub struct EchoReply<'a> {
+    /// Identifier copied from the request
+    pub identifier: u16,
+    /// Sequence number copied from the request
+    pub sequence: u16,
+    /// Data copied from the request
+    pub payload: &'a [u8],
+}
+
+/// Unpack an ICMP Echo Reply.
+///
+/// Returns None if this is not a valid echo reply.
+///
+pub fn unpack_echo_reply(message: &[u8]) -> Option<EchoReply<'_>> {
+    if message.len() < 8 || message[0] != ECHO_REPLY || message[1] != 0 {
+        return None;
+    }
+
+    if !verify_checksum(message) {
+        println!("Received corrupted ICMP message: Dropping it.");
+        return None;
+    }
+
+    Some(EchoReply {
+        identifier: u16::from_be_bytes([message[4], message[5]]),
+        sequence: u16::from_be_bytes([message[6], message[7]]),
+        payload: &message[8..],
+    })
+}
+
+/// The start of the packet quoted in an ICMP error message.
+pub struct Quoted<'a> {
+    /// Source address of the original packet
+    pub src: Ipv4Addr,
+    /// Destination address of the original packet
+    pub dest: Ipv4Addr,
+    /// Protocol number of the original packet
+    pub protocol: u8,
+    /// The first bytes (at least 8) of the original transport layer packet
+    pub transport: &'a [u8],
+}
+
+/// A received ICMP error message.
+pub struct IcmpError<'a> {
+    /// Destination Unreachable or Time Exceeded
+    pub icmp_type: u8,
+    /// Reason of the error
+    pub code: u8,
+    /// The packet that caused the error
+    pub quoted: Quoted<'a>,
+}
+
+/// Unpack an ICMP Destination Unreachable or Time Exceeded message.
+///
+/// These carry the IP header and at least the first 8 bytes of the
+/// packet that caused the error (RFC 792), so it can be matched to
+/// what we sent.
+///
+/// Returns None if this is not a valid ICMP error message.
+///
+pub fn unpack_error(message: &[u8]) -> Option<IcmpError<'_>> {
+    if message.len() < 8 || (message[0] != DESTINATION_UNREACHABLE && message[0] != TIME_EXCEEDED) {
+        return None;
+    }
+
+    if !verify_checksum(message) {
+        println!("Received corrupted ICMP message: Dropping it.");
+        return None;
+    }
+
+    // original IP header, followed by at least 8 bytes
+    let quoted = &message[8..];
+    if quoted.len() < 20 || quoted[0] >> 4 != 4 {
+        return None;
+    }
+
+    let header_len = ((quoted[0] & 0x0F) * 4) as usize;
+    if header_len < 20 || quoted.len() < header_len + 8 {
+        return None;
+    }
+
+    Some(IcmpError {
+        icmp_type: message[0],
+        code: message[1],
+        quoted: Quoted {
+            src: Ipv4Addr::from(<[u8; 4]>::try_from(&quoted[12..16]).unwrap()),
+            dest: Ipv4Addr::from(<[u8; 4]>::try_from(&quoted[16..20]).unwrap()),
+            protocol: quoted[9],
+            transport: &quoted[header_len..],
+        },
+    })
+}
+
+/// Unpack an ICMP error about a UDP datagram we sent.
+///
+/// packet: The received IPv4 packet
+/// src: Source address and port of our datagrams
+///
+/// Returns None if this is no ICMP error, or it quotes some other datagram.
+///
+pub fn unpack_udp_error<'a>(packet: &Ipv4Packet<'a>, src: SocketAddrV4) -> Option<IcmpError<'a>> {
+    if packet.protocol != PROTOCOL_ICMP {
+        return None;
+    }
+
+    let error = unpack_error(packet.payload)?;
+    let quoted = &error.quoted;
+
+    // check the quoted datagram is ours
+    if quoted.protocol != PROTOCOL_UDP || quoted.src != *src.ip() || quoted.transport[0..2] != src.port().to_be_bytes() {
+        return None;
+    }
+
+    Some(error)
+}
+
+/// Turn an ICMP error into an I/O error, like the kernel reports them on sockets.
+///
+/// from: Who sent the ICMP error
+///
+pub fn to_io_error(error: &IcmpError, from: Ipv4Addr) -> io::Error {
+    let kind = match (error.icmp_type, error.code) {
+        (DESTINATION_UNREACHABLE, 0 | 6) => ErrorKind::NetworkUnreachable,
+        (DESTINATION_UNREACHABLE, 1 | 7) => ErrorKind::HostUnreachable,
+        (DESTINATION_UNREACHABLE, 2 | 3) => ErrorKind::ConnectionRefused,
+        (DESTINATION_UNREACHABLE, 9 | 10 | 13) => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+
+    io::Error::new(kind, format!("{} (reported by {})", describe_error(error.icmp_type, error.code), from))
+}
+
+/// Describe an ICMP error message for the user (RFC 792, RFC 1812).
+pub fn describe_error(icmp_type: u8, code: u8) -> String {
+    match (icmp_type, code) {
+        (DESTINATION_UNREACHABLE, 0) => String::from("Destination network unreachable"),
+        (DESTINATION_UNREACHABLE, 1) => String::from("Destination host unreachable"),
+        (DESTINATION_UNREACHABLE, 2) => String::from("Destination protocol unreachable"),
+        (DESTINATION_UNREACHABLE, 3) => String::from("Destination port unreachable"),
+        (DESTINATION_UNREACHABLE, 4) => String::from("Fragmentation needed but don't fragment set"),
+        (DESTINATION_UNREACHABLE, 5) => String::from("Source route failed"),
+        (DESTINATION_UNREACHABLE, 6) => String::from("Destination network unknown"),
+        (DESTINATION_UNREACHABLE, 7) => String::from("Destination host unknown"),
+        (DESTINATION_UNREACHABLE, 9) => String::from("Destination network administratively prohibited"),
+        (DESTINATION_UNREACHABLE, 10) => String::from("Destination host administratively prohibited"),
+        (DESTINATION_UNREACHABLE, 13) => String::from("Communication administratively prohibited"),
+        (DESTINATION_UNREACHABLE, code) => format!("Destination unreachable (code {})", code),
+        (TIME_EXCEEDED, 0) => String::from("Time to live exceeded in transit"),
+        (TIME_EXCEEDED, 1) => String::from("Fragment reassembly time exceeded"),
+        (TIME_EXCEEDED, code) => format!("Time exceeded (code {})", code),
+        (icmp_type, code) => format!("ICMP type {} code {}", icmp_type, code),
+    }
+}
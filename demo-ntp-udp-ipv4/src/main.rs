@@ -1,13 +1,27 @@
-use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, process::exit};
-
-use link::{create_arp, create_ethernet_packet, unwrap_arp, unwrap_ethernet_packet};
-use pnet::datalink::{self, Channel};
-use std::time::Instant;
-
+use std::{collections::HashMap, io::ErrorKind, net::{IpAddr, Ipv4Addr, SocketAddrV4}, process::exit};
+
+use link::{create_arp, unwrap_arp};
+use pnet::datalink::{self, Channel, NetworkInterface};
+use std::time::{Duration, Instant};
+
+use raw_stack_ipv4::{firewall, ip, link, port, tcp}; // Ethernet, IP, TCP, ports and firewall guard
+
+mod args; // Command line arguments
+mod auth; // NTP symmetric key authentication
+mod ping; // Ping tool
+mod stats; // Ping statistics
+mod traceroute; // Traceroute tool
+mod connect; // TCP client tool
+mod listen; // TCP server tool
+mod date; // Time from HTTP Date headers
 mod ntp; // Application layer
+mod nts; // Network Time Security
+mod select; // NTP clock selection
+mod serve; // NTP server tool
+mod siv; // AEAD of NTS
+mod timestamp; // NTP time formats
 mod udp; // Transport layer
-mod ip;  // IP layer
-mod link;// Link layer
+mod icmp;// ICMP (IP layer)
 
 /// The main function.
 /// 
@@ -15,7 +29,10 @@ mod link;// Link layer
 /// 
 /// 1. Interface name (The ethernet interface to use, normaly something like eth0 or enp1s0)
 /// 2. Gateway IP (Commonly something like 192.168.x.1)
-/// 3. Bind port (some open port to bind our host on)
+/// 3. Bind port (optional, some open UDP port to bind our host on, 123 with `--serve`, TCP with `--connect` or `--listen`)
+/// 
+/// Options for the IP header (TTL, DSCP/ECN, DF, ID) may be given
+/// anywhere in between, see `--help`.
 /// 
 /// # Security
 /// 
@@ -30,28 +47,13 @@ mod link;// Link layer
 /// services that currently uses this port, and cause undefined
 /// behavior.
 /// 
+/// The port is checked against the sockets of the kernel, and if
+/// left out, a free ephemeral port is chosen. With `--reserve-port`
+/// a kernel socket holds the port while we steal it.
+/// 
 fn main() {
-    // Get interface name, or else display help and return
-    let interface_name = if let Some(s) = std::env::args().nth(1) {
-        if s == "--help" || s == "-h" {
-            help_message(0);
-        }
-
-        String::from(s)
-    }else{
-        help_message(1);
-    };
-
     // Get and parse commandline arguments
-
-    // gateway IP
-    let gateway_ip = {
-        let ip: Ipv4Addr = std::env::args().nth(2).expect("Expected gateway IP").parse().expect("Expected IPv4 address");
-        ip
-    };
-
-    let port = std::env::args().nth(3).expect("Expected binding port");
-    let port: u16 = port.parse().expect("Cannot parse port");
+    let args::Args { interface: interface_name, gateway: gateway_ip, port, reserve_port, guard, dry_run, mut send_options, mode } = args::parse();
 
     // Get the interface we search
     let interface = {
@@ -76,7 +78,7 @@ fn main() {
     let this_mac = interface.mac.expect("Interface has no mac address");
     let this_ip = {
         let i: Vec<_> = interface.ips.iter().filter(|i| i.is_ipv4()).collect();
-        let i = i.get(0).expect("Interface has no IP address assigned").ip();
+        let i = i.first().expect("Interface has no IP address assigned").ip();
 
         if let IpAddr::V4(addr) = i {
             addr
@@ -86,8 +88,12 @@ fn main() {
         }
     };
 
-    // Create the channel
-    let channel = datalink::channel(&interface, Default::default());
+    // Create the channel, with a read timeout so we can check our own timeouts
+    let config = datalink::Config {
+        read_timeout: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let channel = datalink::channel(&interface, config);
     let (mut tx,mut rx) = match channel {
         Ok(Channel::Ethernet(tx,rx)) => (tx,rx),
         Ok(_) => panic!("Unhandled channel type"),
@@ -114,13 +120,16 @@ fn main() {
                     // try to parse ethernet frame.
                     res = unwrap_arp(frame, this_mac.octets(), this_ip.octets(), gateway_ip.octets());
                 },
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    res = None;
+                },
                 Err(e) => {
                     panic!("Error occured: {}.", e);
                 }
             }
 
             // break loop if we found mac
-            if let Some(_) = res {
+            if res.is_some() {
                 break;
             }
 
@@ -136,65 +145,366 @@ fn main() {
         res.unwrap()
     };
 
-    // Now send ethernet packet
-    let timestamp = {
-        let mut res;
+    // Ping instead of NTP?
+    if let args::Mode::Ping(options) = mode {
+        let stats = ping::run(&mut *tx, &mut *rx, this_mac.octets(), this_ip, gateway_mac, &mut send_options, &options);
+
+        // like ping, fail if there was no reply at all
+        exit(if stats.received() > 0 { 0 } else { 1 });
+    }
+
+    // Traceroute instead of NTP?
+    if let args::Mode::Traceroute(options) = mode {
+        traceroute::run(&mut *tx, &mut *rx, this_mac.octets(), this_ip, gateway_mac, &send_options, &options);
+        exit(0);
+    }
+
+    // Everything else runs on top of the stack
+    let stack = ip::Stack {
+        tx,
+        rx,
+        this_mac: this_mac.octets(),
+        this_ip,
+        gateway_mac,
+        mtu: link::interface_mtu(&interface.name),
+        send_options,
+        // a server answers the clients on our link too, not only through the gateway
+        neighbors: matches!(mode, args::Mode::Serve(_)).then(HashMap::new),
+    };
 
-        let dest: SocketAddr = format!("216.239.35.12:{}", 123).parse().expect("Could not resolve time.google.com");
-        let src = SocketAddr::new(IpAddr::V4(this_ip), port);
+    // TCP client instead of NTP?
+    if let args::Mode::Connect(remote) = mode {
+        let port = local_port(port::Protocol::Tcp, port);
 
-        let packet = create_ethernet_packet(&this_mac.octets(), &gateway_mac, src, dest);
+        // keep the kernel from resetting the connection
+        if guard {
+            install_guard(dry_run, this_ip, port::Protocol::Tcp, port);
+        }
 
-        // Send NTP packet
-        tx.send_to(&packet, None);
+        let result = connect::run(stack, port, remote);
+        firewall::remove();
 
-        // current time
-        let t_prev = Instant::now();
+        if let Err(e) = result {
+            println!("TCP connection failed: {}", e);
+            exit(-1);
+        }
+        exit(0);
+    }
 
-        // loop to get requests
-        loop {
-            match rx.next() {
-                Ok(frame) => {
-                    // try to parse bottom-up the NTP packet to get timestamp
-                    res = unwrap_ethernet_packet(frame, &this_mac.octets(), &gateway_mac, dest, src);
-                },
-                Err(e) => {
-                    panic!("Error occured: {}", e)
-                }
-            }
+    // TCP server instead of NTP?
+    if let args::Mode::Listen = mode {
+        let port = local_port(port::Protocol::Tcp, port);
 
-            // break loop if we found timestamp
-            if let Some(_) = res {
-                break;
-            }
+        // keep the kernel from answering the SYNs with a reset
+        if guard {
+            install_guard(dry_run, this_ip, port::Protocol::Tcp, port);
+        }
 
-            // Check if timeout, else continue
-            if (Instant::now() - t_prev).as_secs_f32() > 7.0 {
-                // timeout
-                println!("NTP request timeout!");
+        let result = listen::run(stack, port);
+        firewall::remove();
+
+        if let Err(e) = result {
+            println!("TCP server failed: {}", e);
+            exit(-1);
+        }
+        exit(0);
+    }
+
+    // Time from the Date header of a web server instead of NTP?
+    if let args::Mode::HttpDate(server) = mode {
+        let (server, url) = date::resolve(&server).unwrap_or_else(|e| {
+            println!("Could not resolve {}: {}", server, e);
+            exit(-1);
+        });
+        let port = local_port(port::Protocol::Tcp, port);
+
+        // keep the kernel from resetting the connection
+        if guard {
+            install_guard(dry_run, this_ip, port::Protocol::Tcp, port);
+        }
+
+        let result = date::request_time(stack, port, server, &url, Duration::from_secs(7));
+        firewall::remove();
+
+        match result {
+            Ok(timestamp) => {
+                println!("Current time (UTC): {}", timestamp);
+                println!("Precision: 1 second, from the HTTP Date header of {} instead of NTP.", url.authority());
+            },
+            Err(e) => {
+                println!("HTTP Date request failed: {}", e);
                 exit(-1);
+            },
+        }
+        exit(0);
+    }
+
+    // NTP server instead of the client?
+    if let args::Mode::Serve(options) = mode {
+        let keys = options.keys.as_deref().map(read_keys).unwrap_or_default();
+
+        // the NTP port, unless told otherwise
+        let port = local_port(port::Protocol::Udp, Some(port.unwrap_or(123)));
+        let _reservation = reserve_port.then(|| udp::reserve_port(this_ip, port).unwrap_or_else(|e| {
+            println!("Could not reserve UDP port {}: {}", port, e);
+            exit(-1);
+        }));
+        let mut socket = udp::RawUdpSocket::bind(stack, port);
+
+        // keep the kernel from answering the requests with port unreachable
+        if guard {
+            install_guard(dry_run, this_ip, port::Protocol::Udp, port);
+        }
+
+        let result = serve::run(&mut socket, &options, &keys);
+        firewall::remove();
+
+        if let Err(e) = result {
+            println!("NTP server failed: {}", e);
+            exit(-1);
+        }
+        exit(0);
+    }
+
+    let args::Mode::Ntp(options) = mode else {
+        unreachable!("All other modes exit above");
+    };
+    let keys = options.key.map(|_| read_keys(&options.keys)).unwrap_or_default();
+    let key = options.key.map(|id| keys.iter().find(|key| key.id == id).unwrap_or_else(|| {
+        println!("No key {} in {}.", id, options.keys);
+        exit(1);
+    }));
+
+    // the UDP port before any firewall rule, so exiting here leaves none behind;
+    // keep the reservation (if any) until we are done
+    let port = local_port(port::Protocol::Udp, port);
+    let _reservation = reserve_port.then(|| udp::reserve_port(this_ip, port).unwrap_or_else(|e| {
+        println!("Could not reserve UDP port {}: {}", port, e);
+        exit(-1);
+    }));
+
+    // with NTS, the key exchange names the NTP server and gives keys and cookies for it
+    let (servers, sessions): (Vec<SocketAddrV4>, _) = if options.nts {
+        let config = nts::tls_config(options.nts_ca.as_deref()).unwrap_or_else(|e| {
+            println!("Could not read the CA certificates: {}", e);
+            exit(-1);
+        });
+        let ke_port = local_port(port::Protocol::Tcp, None);
+
+        // keep the kernel from resetting the connections
+        if guard {
+            install_guard(dry_run, this_ip, port::Protocol::Tcp, ke_port);
+        }
+
+        // over TCP on a channel of its own each, the stack stays for the UDP socket
+        let mut sessions = Vec::new();
+        for server in &options.servers {
+            match nts::resolve(server).and_then(|(ke_server, name)| nts::key_exchange(another_stack(&interface, &stack), ke_port, ke_server, &name, config.clone(), Duration::from_secs(7))) {
+                Ok(session) => {
+                    println!("NTS key exchange with {}: NTP server {}, {} cookies.", server, session.server, session.cookies());
+                    sessions.push(session);
+                },
+                Err(e) => println!("NTS key exchange with {} failed: {}", server, e),
             }
         }
 
-        // safety: It would be never callen if not satisfied above as Some(_)
-        res.unwrap()
+        if sessions.is_empty() {
+            firewall::remove();
+            exit(-1);
+        }
+        (sessions.iter().map(|session| session.server).collect(), sessions)
+    }else{
+        let servers = options.servers.iter().map(|server| ip::resolve(server, 123).unwrap_or_else(|e| {
+            println!("Could not resolve {}: {}", server, e);
+            exit(-1);
+        })).collect();
+        (servers, Vec::new())
     };
 
-    println!("Current time (UTC): {}", timestamp);
+    // NTP over our UDP socket
+    let mut socket = udp::RawUdpSocket::bind(stack, port);
+
+    // keep the kernel from answering the NTP replies with port unreachable
+    if guard {
+        install_guard(dry_run, this_ip, port::Protocol::Udp, port);
+    }
+
+    let peers = ntp::query(&mut socket, &servers, options.samples, Duration::from_secs(7), key, sessions);
+    firewall::remove();
+
+    // clock filter of each server, then the selection over all of them
+    let now = Instant::now();
+    let candidates: Vec<_> = peers.iter().map(|peer| select::clock_filter(peer, now)).collect();
+    let (verdicts, estimate) = select::select(&candidates);
+
+    print_peers(&peers, &candidates, &verdicts);
+
+    let Some(estimate) = estimate else {
+        if peers.iter().all(|peer| peer.samples.is_empty() && peer.error.is_none()) {
+            println!("NTP request timeout!");
+            println!("If UDP port 123 is blocked, try --http-date <host> instead.");
+        }else if candidates.iter().all(Option::is_none) {
+            println!("NTP request failed: no server sent a usable reply.");
+        }else if verdicts.iter().flatten().all(|verdict| *verdict == select::Verdict::Unfit) {
+            println!("No server can be selected, their root distance is over {} s.", select::MAX_DISTANCE);
+            if options.samples < select::MIN_SAMPLES {
+                println!("Ask each at least {} times with --samples {}.", select::MIN_SAMPLES, select::MIN_SAMPLES);
+            }
+        }else{
+            println!("No majority of the servers agrees on the time.");
+        }
+        print_dropped();
+        exit(-1);
+    };
+
+    let time = timestamp::NtpTimestamp::now().add_seconds(estimate.offset);
+    let system_peer = candidates[estimate.peer].as_ref().expect("System peer without samples");
 
+    println!("Current time (UTC): {}", ntp::get_timestamp(time));
+    println!("Clock offset: {:+.6} s (the local clock is {})", estimate.offset, if estimate.offset >= 0.0 { "behind" } else { "ahead" });
+    println!("Jitter: {:.6} s, combined from {} of {} servers", estimate.jitter, estimate.survivors, peers.len());
+    println!("Round trip delay: {:.6} s to the system peer {}", system_peer.delay, peers[estimate.peer].server);
+    print_dropped();
 }
 
-/// Help message.
+/// Print a line per server: its tally code, offset, delay and jitter, or
+/// why there are none.
+///
+/// The tally codes are those of ntpq: `*` system peer, `+` survivor, `-`
+/// outlier, `x` falseticker. `!` marks a server that refused us with a
+/// Kiss-o'-Death, `?` one without usable reply.
 ///
-fn help_message(code: i32) -> ! {
-    println!("Usage:    cargo run --release --bin demo-ipv4 <interface> <gateway> <UDP port>\n");
-    println!("  --help, -h    Display this help message");
-    println!("  <interface>   The ethernet interface to send through.");
-    println!("  <gateway>     The gateway IP address. The IPv4 address of your internet router.");
-    println!("                Normally something like 192.168.x.1 or similar.");
-    println!("  <UDP port>    Port to bind the UDP socket on (or better said steel the port).\n");
-    println!("Bug reports issue at https://github.com/RossAdrian/raw-networking-demo/.");
-
-
-    exit(code)
-}
\ No newline at end of file
+fn print_peers(peers: &[ntp::Peer], candidates: &[Option<select::Filtered>], verdicts: &[Option<select::Verdict>]) {
+    println!("  {:<22} {:>7} {:>12} {:>10} {:>10}", "Server", "Stratum", "Offset", "Delay", "Jitter");
+
+    for ((peer, candidate), verdict) in peers.iter().zip(candidates).zip(verdicts) {
+        let server = peer.server.to_string();
+
+        match (candidate, verdict) {
+            (Some(c), Some(verdict)) => {
+                print!("{} {:<22} {:>7} {:>+12.6} {:>10.6} {:>10.6}", verdict.tally(), server, c.stratum, c.offset, c.delay, c.jitter);
+
+                // say why a server cannot be selected
+                if *verdict == select::Verdict::Unfit {
+                    if peer.samples.len() < select::MIN_SAMPLES {
+                        print!("  Unfit: {} sample(s), {} needed", peer.samples.len(), select::MIN_SAMPLES);
+                    }else{
+                        print!("  Unfit: root distance {:.3} s over {} s", c.distance, select::MAX_DISTANCE);
+                    }
+                }
+                println!();
+            },
+            _ => {
+                let tally = if peer.kissed { '!' } else { '?' };
+                match &peer.error {
+                    Some(e) => println!("{} {:<22} {}", tally, server, e),
+                    None => println!("{} {:<22} No reply to {} request(s)", tally, server, peer.unanswered),
+                }
+            },
+        }
+    }
+
+    println!("(* system peer, + survivor, - outlier, x falseticker, ! refused, ? no usable reply)\n");
+}
+
+/// Print how many datagrams were dropped because of a wrong UDP checksum, if any.
+fn print_dropped() {
+    let dropped = udp::dropped_datagrams();
+    if dropped > 0 {
+        println!("Dropped {} UDP datagram(s) with a wrong checksum.", dropped);
+    }
+}
+
+/// Get the port to steal.
+///
+/// Checks the given port is not in use by the kernel, or chooses a free
+/// random port if none given: any from 1024 for UDP, since NTP replies are
+/// told apart by the port, and an ephemeral one for TCP. Exits if the port
+/// is in use.
+///
+fn local_port(protocol: port::Protocol, port: Option<u16>) -> u16 {
+    let name = protocol.name().to_uppercase();
+
+    match port {
+        Some(port) => {
+            match port::ports_in_use(protocol) {
+                Ok(ports) if ports.contains(&port) => {
+                    println!("{} port {} is in use by the kernel, choose another one or leave it out.", name, port);
+                    exit(-1);
+                },
+                Ok(_) => {},
+                Err(e) => println!("Could not check if {} port {} is free: {}", name, port, e),
+            }
+
+            port
+        },
+        None => {
+            let port = match protocol {
+                port::Protocol::Udp => port::choose_random_port(protocol),
+                port::Protocol::Tcp => port::choose_ephemeral_port(protocol),
+            };
+            let port = port.unwrap_or_else(|e| {
+                println!("Could not choose a {} port: {}", name, e);
+                exit(-1);
+            });
+
+            println!("Using {} port {}.", name, port);
+            port
+        },
+    }
+}
+
+/// Open another stack on the interface, with an ethernet channel of its own.
+///
+/// A TCP connection takes the stack it runs on, this one keeps the first
+/// stack for the sockets after it.
+///
+fn another_stack(interface: &NetworkInterface, stack: &ip::Stack) -> ip::Stack {
+    let config = datalink::Config {
+        read_timeout: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let (tx, rx) = match datalink::channel(interface, config) {
+        Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
+        Ok(_) => panic!("Unhandled channel type"),
+        Err(e) => panic!("Error when creating raw socket: {}", e),
+    };
+
+    ip::Stack {
+        tx,
+        rx,
+        this_mac: stack.this_mac,
+        this_ip: stack.this_ip,
+        gateway_mac: stack.gateway_mac,
+        mtu: stack.mtu,
+        send_options: stack.send_options.clone(),
+        neighbors: None,
+    }
+}
+
+/// Install the firewall rules keeping the kernel off our port, or print
+/// them on a dry run.
+///
+/// Only reports if this fails, we try anyway.
+///
+fn install_guard(dry_run: bool, this_ip: Ipv4Addr, protocol: port::Protocol, port: u16) {
+    match firewall::detect() {
+        // without firewall, show the nftables rules
+        None if dry_run => firewall::rules(firewall::Backend::Nft, this_ip, protocol, port).print(),
+        Some(backend) if dry_run => firewall::rules(backend, this_ip, protocol, port).print(),
+        Some(backend) => {
+            if let Err(e) = firewall::install(firewall::rules(backend, this_ip, protocol, port)) {
+                println!("Could not install firewall rules: {}", e);
+            }
+        },
+        None => println!("Neither nft nor iptables found, the kernel may answer our {} traffic itself.", protocol.name().to_uppercase()),
+    }
+}
+
+/// Read the keys of an ntp.keys file, or exit if that fails.
+fn read_keys(path: &str) -> Vec<auth::Key> {
+    auth::read_keys(path).unwrap_or_else(|e| {
+        println!("Could not read the keys from {}: {}", path, e);
+        exit(-1);
+    })
+}
@@ -0,0 +1,107 @@
+// Ping (ICMP echo on top of the raw stack)
+use std::{collections::HashMap, io::ErrorKind, net::Ipv4Addr, time::{Duration, Instant}};
+
+use pnet::datalink::{DataLinkReceiver, DataLinkSender};
+
+use crate::{icmp, ip::{self, SendOptions}, link, stats::Statistics};
+
+/// How long to wait for outstanding replies after the last request.
+const LINGER: Duration = Duration::from_secs(2);
+
+/// Options of a ping session.
+pub struct PingOptions {
+    /// The host to ping
+    pub target: Ipv4Addr,
+    /// Number of echo requests to send
+    pub count: u16,
+    /// Time between two echo requests
+    pub interval: Duration,
+    /// Number of data bytes in each echo request
+    pub size: usize,
+}
+
+/// Run a ping session.
+///
+/// Sends `count` echo requests through the gateway, matches the replies
+/// by identifier and sequence number and prints the round trip times.
+///
+pub fn run(tx: &mut dyn DataLinkSender, rx: &mut dyn DataLinkReceiver, this_mac: [u8; 6], this_ip: Ipv4Addr, gateway_mac: [u8; 6], send_options: &mut SendOptions, options: &PingOptions) -> Statistics {
+    // identify our session by the process ID, like ping does
+    let identifier = std::process::id() as u16;
+
+    // payload pattern 0x00, 0x01, ...
+    let payload: Vec<u8> = (0..options.size).map(|i| i as u8).collect();
+
+    println!("PING {} {}({}) bytes of data.", options.target, options.size, options.size + 28);
+
+    let mut stats = Statistics::default();
+    let mut pending: HashMap<u16, Instant> = HashMap::new();
+    let mut sequence: u16 = 0;
+
+    let start = Instant::now();
+    let mut next_send = start;
+    let mut last_send = start;
+
+    loop {
+        let now = Instant::now();
+
+        // send next request if it is time
+        if stats.transmitted < u32::from(options.count) && now >= next_send {
+            sequence = sequence.wrapping_add(1);
+
+            let message = icmp::create_echo_request(identifier, sequence, &payload);
+            let packet = ip::create_ip_packet(this_ip, options.target, ip::PROTOCOL_ICMP, &message, send_options);
+            let frame = link::create_ethernet_packet(&this_mac, &gateway_mac, &packet);
+
+            if let Some(Err(e)) = tx.send_to(&frame, None) {
+                println!("Could not send echo request: {}", e);
+            }
+
+            pending.insert(sequence, Instant::now());
+            stats.transmitted += 1;
+            next_send += options.interval;
+            last_send = now;
+        }
+
+        // all sent, wait for the remaining replies
+        if stats.transmitted == u32::from(options.count) && (stats.received() == stats.transmitted || now - last_send > LINGER) {
+            break;
+        }
+
+        let frame = match rx.next() {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => panic!("Error occured: {}", e),
+        };
+
+        // parse bottom-up
+        let Some(packet) = link::unwrap_ip_packet(frame, &this_mac, &gateway_mac).and_then(ip::parse) else {
+            continue;
+        };
+
+        if packet.protocol != ip::PROTOCOL_ICMP || packet.src != options.target || packet.dest != this_ip {
+            continue;
+        }
+
+        let Some(reply) = icmp::unpack_echo_reply(packet.payload) else {
+            continue;
+        };
+
+        if reply.identifier != identifier {
+            continue;
+        }
+
+        // keep the requests pending, a reply may come twice
+        if let Some(&sent) = pending.get(&reply.sequence) {
+            let rtt = sent.elapsed();
+            let duplicate = stats.add(reply.sequence, rtt);
+
+            println!("{} bytes from {}: icmp_seq={} ttl={} time={:.3} ms{}",
+                reply.payload.len() + 8, packet.src, reply.sequence, packet.ttl, rtt.as_secs_f64() * 1000.0, if duplicate { " (DUP!)" } else { "" });
+        }
+    }
+
+    stats.print(&options.target.to_string(), start.elapsed());
+
+    stats
+}
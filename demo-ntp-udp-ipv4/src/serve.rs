@@ -0,0 +1,77 @@
+// Serve (NTP server on top of the raw stack)
+use std::{io, net::Ipv4Addr};
+
+use crate::{auth::{self, Key}, ntp::{NtpPacket, ServerOptions}, timestamp::NtpTimestamp, udp::{self, RawUdpSocket}};
+
+/// Answer the NTP client requests arriving at the socket with our clock
+/// (RFC 5905, Section 9.2), until interrupted.
+///
+/// Each request is stamped with our clock when it arrives (T2), and the
+/// reply again right before it leaves (T3). Anything but client requests
+/// is ignored.
+///
+/// A request with a MAC of one of our keys gets a reply with a MAC of the
+/// same key, like ntpd and chronyd answer. If we do not know the key, or the
+/// MAC does not verify, the reply is a crypto-NAK (RFC 5905, Section 7.4).
+/// Requests without MAC get replies without MAC.
+///
+/// socket: The UDP socket to serve on, normally port 123
+/// options: Stratum and reference ID to claim
+/// keys: The keys we share with clients
+///
+pub fn run(socket: &mut RawUdpSocket, options: &ServerOptions, keys: &[Key]) -> io::Result<()> {
+    println!("Serving NTP on {} as stratum {}, reference ID {}.", socket.local_addr(), options.stratum, reference_name(options));
+
+    // we do not set our clock, it counts as set when we start
+    let reference = NtpTimestamp::now();
+
+    loop {
+        let (data, from) = match socket.recv_from() {
+            Ok(received) => received,
+            // a client went away before our reply arrived
+            Err(e) if udp::error_destination(&e).is_some() => {
+                println!("Reply not delivered: {}", e);
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+        let t2 = NtpTimestamp::now();
+
+        let Some(request) = NtpPacket::decode(&data) else {
+            continue;
+        };
+        let Some(mut reply) = NtpPacket::server_reply(&request, t2, reference, options) else {
+            continue;
+        };
+
+        // the key of the request, if its MAC verifies
+        let key = request.mac.as_ref().and_then(|mac| keys.iter().find(|key| key.id == mac.key_id && key.verify(&data, &request).is_ok()));
+        if request.mac.is_some() && key.is_none() {
+            reply.mac = Some(auth::crypto_nak());
+        }
+
+        // as late as possible
+        reply.transmit_timestamp = NtpTimestamp::now();
+        let data = match key {
+            Some(key) => key.sign(&reply),
+            None => reply.encode(),
+        };
+        socket.send_to(&data, from)?;
+
+        match key {
+            Some(key) => println!("Answered {} (version {}, key {}).", from, reply.version, key.id),
+            None if reply.mac.is_some() => println!("Answered {} with a crypto-NAK, unknown key or wrong MAC.", from),
+            None => println!("Answered {} (version {}).", from, reply.version),
+        }
+    }
+}
+
+/// The reference ID as it is meant: four ASCII characters for stratum 1, an
+/// IPv4 address else.
+fn reference_name(options: &ServerOptions) -> String {
+    if options.stratum == 1 {
+        String::from_utf8_lossy(&options.reference_id).trim_end_matches('\0').to_string()
+    }else{
+        Ipv4Addr::from(options.reference_id).to_string()
+    }
+}
@@ -0,0 +1,205 @@
+// AES-SIV-CMAC-256 (the AEAD algorithm of NTS, RFC 5297)
+use aes::{Aes128, cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray}};
+use cmac::{Cmac, Mac};
+
+/// Length of the key: one AES-128 key for S2V, one for CTR
+pub const KEY_LEN: usize = 32;
+
+/// Length of the synthetic IV in front of the ciphertext
+pub const TAG_LEN: usize = 16;
+
+/// AES-SIV with two AES-128 keys (RFC 5297).
+///
+/// SIV derives the IV from the associated data and the plaintext (S2V, a
+/// CMAC over all of them), and encrypts the plaintext with it in counter
+/// mode. The IV doubles as the tag, so a reused nonce reveals no more than
+/// that the same message was sent twice.
+///
+pub struct AesSiv {
+    /// K1, the CMAC key of S2V
+    mac_key: [u8; 16],
+    /// K2, the key of the counter mode
+    ctr_cipher: Aes128,
+}
+
+impl AesSiv {
+    /// K1 is the first half of the key, K2 the second (RFC 5297, Section 2.6).
+    pub fn new(key: &[u8; KEY_LEN]) -> AesSiv {
+        let mut mac_key = [0; 16];
+        mac_key.copy_from_slice(&key[..16]);
+
+        AesSiv {
+            mac_key,
+            ctr_cipher: Aes128::new(GenericArray::from_slice(&key[16..])),
+        }
+    }
+
+    /// Encrypt and authenticate (RFC 5297, Section 2.6).
+    ///
+    /// associated_data: The components authenticated but not encrypted,
+    /// for an AEAD the associated data followed by the nonce
+    /// plaintext: The data to encrypt
+    ///
+    /// Returns the synthetic IV followed by the ciphertext.
+    ///
+    pub fn seal(&self, associated_data: &[&[u8]], plaintext: &[u8]) -> Vec<u8> {
+        let iv = self.s2v(associated_data, plaintext);
+
+        let mut sealed = iv.to_vec();
+        sealed.extend_from_slice(plaintext);
+        self.ctr(iv, &mut sealed[TAG_LEN..]);
+
+        sealed
+    }
+
+    /// Decrypt and verify (RFC 5297, Section 2.7).
+    ///
+    /// associated_data: The same components as given to `seal`
+    /// sealed: The synthetic IV followed by the ciphertext
+    ///
+    /// Returns the plaintext, None if the IV does not match.
+    ///
+    pub fn open(&self, associated_data: &[&[u8]], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < TAG_LEN {
+            return None;
+        }
+        let (iv, ciphertext) = sealed.split_at(TAG_LEN);
+        let iv: [u8; TAG_LEN] = iv.try_into().ok()?;
+
+        let mut plaintext = ciphertext.to_vec();
+        self.ctr(iv, &mut plaintext);
+
+        // compare in constant time
+        let expected = self.s2v(associated_data, &plaintext);
+        if expected.iter().zip(iv).fold(0, |diff, (x, y)| diff | (x ^ y)) != 0 {
+            return None;
+        }
+
+        Some(plaintext)
+    }
+
+    /// CMAC of one string with K1.
+    fn cmac(&self, data: &[u8]) -> [u8; 16] {
+        let mut mac = <Cmac<Aes128> as Mac>::new_from_slice(&self.mac_key).expect("AES-128 key of wrong length");
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// S2V, the CMAC of a vector of strings, the plaintext last (RFC 5297, Section 2.4).
+    fn s2v(&self, associated_data: &[&[u8]], plaintext: &[u8]) -> [u8; 16] {
+        let mut d = self.cmac(&[0; 16]);
+        for component in associated_data {
+            d = xor(dbl(d), self.cmac(component));
+        }
+
+        // xorend for a full block or more, else doubled and padded with 10*
+        if plaintext.len() >= 16 {
+            let mut t = plaintext.to_vec();
+            let start = t.len() - 16;
+            for (b, x) in t[start..].iter_mut().zip(d) {
+                *b ^= x;
+            }
+            self.cmac(&t)
+        }else{
+            let mut padded = [0; 16];
+            padded[..plaintext.len()].copy_from_slice(plaintext);
+            padded[plaintext.len()] = 0x80;
+            self.cmac(&xor(dbl(d), padded))
+        }
+    }
+
+    /// Counter mode with K2, starting at the IV with bits 31 and 63 cleared
+    /// (RFC 5297, Section 2.5).
+    fn ctr(&self, iv: [u8; 16], data: &mut [u8]) {
+        let mut q = iv;
+        q[8] &= 0x7f;
+        q[12] &= 0x7f;
+        let mut counter = u128::from_be_bytes(q);
+
+        for chunk in data.chunks_mut(16) {
+            let mut block = GenericArray::from(counter.to_be_bytes());
+            self.ctr_cipher.encrypt_block(&mut block);
+            for (b, k) in chunk.iter_mut().zip(block) {
+                *b ^= k;
+            }
+            counter = counter.wrapping_add(1);
+        }
+    }
+}
+
+/// Multiply by x in GF(2^128), a shift left with the carry folded back (RFC 5297, Section 2.3).
+fn dbl(block: [u8; 16]) -> [u8; 16] {
+    let value = u128::from_be_bytes(block);
+    let doubled = (value << 1) ^ if value >> 127 == 1 { 0x87 } else { 0 };
+    doubled.to_be_bytes()
+}
+
+/// XOR of two blocks.
+fn xor(a: [u8; 16], b: [u8; 16]) -> [u8; 16] {
+    (u128::from_be_bytes(a) ^ u128::from_be_bytes(b)).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes from hexadecimal digits, spaces ignored.
+    fn hex(digits: &str) -> Vec<u8> {
+        let digits: Vec<u8> = digits.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits.chunks(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap()).collect()
+    }
+
+    /// The deterministic example of RFC 5297, Appendix A.1.
+    fn example() -> (AesSiv, Vec<u8>, Vec<u8>, Vec<u8>) {
+        let key = hex("fffefdfc fbfaf9f8 f7f6f5f4 f3f2f1f0 f0f1f2f3 f4f5f6f7 f8f9fafb fcfdfeff");
+        let siv = AesSiv::new(&key.try_into().unwrap());
+        let associated_data = hex("10111213 14151617 18191a1b 1c1d1e1f 20212223 24252627");
+        let plaintext = hex("11223344 55667788 99aabbcc ddee");
+        let sealed = hex("85632d07 c6e8f37f 950acd32 0a2ecc93 40c02b96 90c4dc04 daef7f6a fe5c");
+
+        (siv, associated_data, plaintext, sealed)
+    }
+
+    #[test]
+    fn rfc_5297_deterministic() {
+        let (siv, associated_data, plaintext, sealed) = example();
+
+        assert_eq!(siv.seal(&[&associated_data], &plaintext), sealed);
+        assert_eq!(siv.open(&[&associated_data], &sealed), Some(plaintext));
+    }
+
+    #[test]
+    fn rfc_5297_nonce_based() {
+        // RFC 5297, Appendix A.2: two components of associated data, the nonce last, a longer plaintext
+        let key = hex("7f7e7d7c 7b7a7978 77767574 73727170 40414243 44454647 48494a4b 4c4d4e4f");
+        let siv = AesSiv::new(&key.try_into().unwrap());
+        let ad1 = hex("00112233 44556677 8899aabb ccddeeff deaddada deaddada ffeeddcc bbaa9988 77665544 33221100");
+        let ad2 = hex("10203040 50607080 90a0");
+        let nonce = hex("09f91102 9d74e35b d84156c5 635688c0");
+        let plaintext = hex("74686973 20697320 736f6d65 20706c61 696e7465 78742074 6f20656e 63727970 74207573 696e6720 5349562d 414553");
+        let sealed = hex("7bdb6e3b 432667eb 06f4d14b ff2fbd0f cb900f2f ddbe4043 26601965 c889bf17 dba77ceb 094fa663 b7a3f748 ba8af829 ea64ad54 4a272e9c 485b62a3 fd5c0d");
+
+        assert_eq!(siv.seal(&[&ad1, &ad2, &nonce], &plaintext), sealed);
+        assert_eq!(siv.open(&[&ad1, &ad2, &nonce], &sealed), Some(plaintext));
+    }
+
+    #[test]
+    fn tampering_detected() {
+        let (siv, associated_data, _, sealed) = example();
+
+        // a bit of the tag, of the ciphertext, and of the associated data
+        let mut tag_flipped = sealed.clone();
+        tag_flipped[0] ^= 0x01;
+        assert_eq!(siv.open(&[&associated_data], &tag_flipped), None);
+
+        let mut ciphertext_flipped = sealed.clone();
+        ciphertext_flipped[TAG_LEN] ^= 0x80;
+        assert_eq!(siv.open(&[&associated_data], &ciphertext_flipped), None);
+
+        let mut other_data = associated_data.clone();
+        other_data[0] ^= 0x01;
+        assert_eq!(siv.open(&[&other_data], &sealed), None);
+
+        assert_eq!(siv.open(&[&associated_data], &sealed[..TAG_LEN - 1]), None);
+    }
+}
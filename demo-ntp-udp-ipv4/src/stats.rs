@@ -0,0 +1,128 @@
+// Ping statistics (the same file in both NTP crates, keep them in sync)
+use std::{collections::HashSet, time::Duration};
+
+/// Round trip time statistics of a ping session.
+#[derive(Default)]
+pub struct Statistics {
+    /// Number of requests sent
+    pub transmitted: u32,
+    /// Number of replies beyond the first for a sequence number
+    pub duplicates: u32,
+    /// Sequence numbers answered at least once
+    answered: HashSet<u16>,
+    /// Round trip times of all replies received, duplicates included, in milliseconds
+    rtts: Vec<f64>,
+}
+
+impl Statistics {
+    /// Record a reply and its round trip time.
+    ///
+    /// The first reply to a request counts as received, any further one
+    /// (another host answering a multicast request, or a duplicated packet)
+    /// as duplicate. The round trip times of both count, like iputils does.
+    ///
+    /// Returns true if the reply is a duplicate.
+    ///
+    pub fn add(&mut self, sequence: u16, rtt: Duration) -> bool {
+        self.rtts.push(rtt.as_secs_f64() * 1000.0);
+
+        let duplicate = !self.answered.insert(sequence);
+        if duplicate {
+            self.duplicates += 1;
+        }
+        duplicate
+    }
+
+    /// Number of requests answered.
+    pub fn received(&self) -> u32 {
+        self.answered.len() as u32
+    }
+
+    /// Packet loss in percent.
+    pub fn loss(&self) -> f64 {
+        if self.transmitted == 0 {
+            return 0.0;
+        }
+
+        100.0 * f64::from(self.transmitted - self.received()) / f64::from(self.transmitted)
+    }
+
+    /// Minimum, average, maximum and mean deviation of the round trip times in ms.
+    ///
+    /// The mean deviation is computed like iputils ping does: sqrt(avg(rtt²) - avg(rtt)²).
+    ///
+    pub fn rtt(&self) -> Option<(f64, f64, f64, f64)> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+
+        let n = self.rtts.len() as f64;
+        let min = self.rtts.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = self.rtts.iter().copied().fold(0.0, f64::max);
+        let avg = self.rtts.iter().sum::<f64>() / n;
+        let avg2 = self.rtts.iter().map(|r| r * r).sum::<f64>() / n;
+        let mdev = (avg2 - avg * avg).max(0.0).sqrt();
+
+        Some((min, avg, max, mdev))
+    }
+
+    /// Print the summary.
+    pub fn print(&self, target: &str, elapsed: Duration) {
+        let duplicates = if self.duplicates > 0 {
+            format!(", +{} duplicates", self.duplicates)
+        }else{
+            String::new()
+        };
+
+        println!("\n--- {} ping statistics ---", target);
+        println!("{} packets transmitted, {} received{}, {:.0}% packet loss, time {}ms",
+            self.transmitted, self.received(), duplicates, self.loss(), elapsed.as_millis());
+
+        if let Some((min, avg, max, mdev)) = self.rtt() {
+            println!("rtt min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms", min, avg, max, mdev);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_do_not_count_as_received() {
+        let mut stats = Statistics { transmitted: 3, ..Statistics::default() };
+
+        // three hosts answer the first request, one the second, none the third
+        assert!(!stats.add(1, Duration::from_millis(1)));
+        assert!(stats.add(1, Duration::from_millis(2)));
+        assert!(stats.add(1, Duration::from_millis(3)));
+        assert!(!stats.add(2, Duration::from_millis(4)));
+
+        assert_eq!(stats.received(), 2);
+        assert_eq!(stats.duplicates, 2);
+        assert!((stats.loss() - 100.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rtt_summary() {
+        let mut stats = Statistics { transmitted: 4, ..Statistics::default() };
+        for (sequence, ms) in [(1, 1), (2, 2), (3, 3), (3, 6)] {
+            stats.add(sequence, Duration::from_millis(ms));
+        }
+
+        // duplicates count for the times: avg 3, avg(rtt²) 12.5
+        let (min, avg, max, mdev) = stats.rtt().unwrap();
+        assert_eq!((min, avg, max), (1.0, 3.0, 6.0));
+        assert!((mdev - 3.5f64.sqrt()).abs() < 1e-9);
+        assert_eq!(stats.loss(), 25.0);
+    }
+
+    #[test]
+    fn nothing_received() {
+        let stats = Statistics { transmitted: 2, ..Statistics::default() };
+
+        assert_eq!(stats.received(), 0);
+        assert_eq!(stats.loss(), 100.0);
+        assert!(stats.rtt().is_none());
+    }
+}
@@ -1,21 +1,26 @@
 // UDP (transport layer)
-use crate::ntp::*;
+use std::{error::Error, fmt, io::{self, ErrorKind}, net::{Ipv4Addr, SocketAddrV4, UdpSocket}, sync::atomic::{AtomicU32, Ordering}, time::{Duration, Instant}};
 
-/// Create the UDP packet
-pub fn create_packet(dest: u16, src: u16) -> Vec<u8> {
+use crate::{icmp, ip::{Ipv4Packet, Stack, PROTOCOL_ICMP, PROTOCOL_UDP}};
+
+/// Number of received datagrams dropped because of a wrong checksum.
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Create a UDP datagram with arbitrary payload
+pub fn create_datagram(dest: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
     let mut data = Vec::new();
 
     // source port
-    data.extend_from_slice(&src.to_be_bytes());
+    data.extend_from_slice(&src.port().to_be_bytes());
 
     // destination port
-    data.extend_from_slice(&dest.to_be_bytes());
+    data.extend_from_slice(&dest.port().to_be_bytes());
 
-    // length and checksum currently ignored
+    // length and checksum set below
     data.extend_from_slice(&[0x00u8; 4]);
     
-    // setup ntp payload
-    add_request_payload(&mut data);
+    // the payload
+    data.extend_from_slice(payload);
 
     // set length
     let length = u16::try_from(data.len()).expect("Payload to big");
@@ -23,20 +28,238 @@ pub fn create_packet(dest: u16, src: u16) -> Vec<u8> {
     data[4] = bytes[0];
     data[5] = bytes[1];
 
+    // set checksum, a computed 0 is sent as 0xFFFF since 0 means no checksum
+    let checksum = match compute_checksum(*src.ip(), *dest.ip(), &data) {
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+    let bytes = checksum.to_be_bytes();
+    data[6] = bytes[0];
+    data[7] = bytes[1];
+
     // And return the frame
     data
 }
 
-/// Unwraps the UDP packet and return the NTP timestamp
-pub fn unpack(data: &[u8], src: u16, dest: u16) -> Option<String> {
-    // check port
-    let src = src.to_be_bytes();
-    let dest = dest.to_be_bytes();
+/// Compute the UDP checksum over the IPv4 pseudo header and the datagram (RFC 768).
+///
+/// The checksum field of the datagram is expected to be zero.
+///
+pub fn compute_checksum(src: Ipv4Addr, dest: Ipv4Addr, datagram: &[u8]) -> u16 {
+    let mut check = Vec::new();
+
+    // IPv4 pseudo header: source, destination, zero, protocol, UDP length
+    check.extend_from_slice(&src.octets());
+    check.extend_from_slice(&dest.octets());
+    check.push(0);
+    check.push(PROTOCOL_UDP);
+    check.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+
+    // push UDP header and payload
+    check.extend_from_slice(datagram);
+
+    if check.len() % 2 == 1 {
+        // align 2
+        check.push(0x00);
+    }
+
+    crate::ip::compute_checksum(&check)
+}
+
+/// Verify the checksum of a received datagram (RFC 768).
+///
+/// A checksum of 0 means the sender did not compute one, so it is accepted.
+///
+pub fn verify_checksum(src: Ipv4Addr, dest: Ipv4Addr, datagram: &[u8]) -> bool {
+    let received = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if received == 0 {
+        return true;
+    }
+
+    // compute with the checksum field zeroed
+    let mut check = datagram.to_vec();
+    check[6] = 0;
+    check[7] = 0;
+
+    let checksum = match compute_checksum(src, dest, &check) {
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+
+    checksum == received
+}
+
+/// Number of received datagrams dropped because of a wrong checksum.
+pub fn dropped_datagrams() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// A received UDP datagram.
+pub struct Datagram<'a> {
+    /// Source port
+    pub src_port: u16,
+    /// Destination port
+    pub dest_port: u16,
+    /// The payload
+    pub payload: &'a [u8],
+}
+
+/// Parse the UDP datagram carried in an IPv4 packet and verify its checksum.
+///
+/// Datagrams with a wrong checksum are dropped and counted.
+///
+/// Returns None if the packet carries no valid UDP datagram.
+///
+pub fn parse<'a>(packet: &Ipv4Packet<'a>) -> Option<Datagram<'a>> {
+    let data = packet.payload;
+    if packet.protocol != PROTOCOL_UDP || data.len() < 8 {
+        return None;
+    }
+
+    // check length
+    let length = usize::from(u16::from_be_bytes([data[4], data[5]]));
+    if length < 8 || length > data.len() {
+        return None;
+    }
+    let data = &data[..length];
+
+    // Verify checksum
+    if !verify_checksum(packet.src, packet.dest, data) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        println!("Received corrupted IPv4/UDP packet: Dropping it.");
+        return None;
+    }
+
+    Some(Datagram {
+        src_port: u16::from_be_bytes([data[0], data[1]]),
+        dest_port: u16::from_be_bytes([data[2], data[3]]),
+        payload: &data[8..],
+    })
+}
 
-    if src == data[0..2] && dest == data[2..4] {
-        // ignore checksum, length, ..., just delegate payload to NTP
-        Some(get_timestamp(&data[8..]))
-    }else{
-        None
-    }    
-}
\ No newline at end of file
+/// Reserve a port by binding a kernel socket on it.
+///
+/// As long as the returned socket lives, the kernel will not hand out the
+/// port to anyone else, and does not answer datagrams to it with ICMP port
+/// unreachable. The socket is never read, we receive on the raw stack.
+///
+pub fn reserve_port(ip: Ipv4Addr, port: u16) -> io::Result<UdpSocket> {
+    UdpSocket::bind(SocketAddrV4::new(ip, port))
+}
+
+/// An ICMP error quoting one of our datagrams, as `recv_from` returns it.
+#[derive(Debug)]
+struct IcmpReport {
+    /// Destination address and port of the datagram
+    dest: SocketAddrV4,
+    /// What went wrong, and who reported it
+    message: String,
+}
+
+impl fmt::Display for IcmpReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for IcmpReport {}
+
+/// The destination of the datagram an error of `RawUdpSocket::recv_from`
+/// is about.
+///
+/// An unconnected socket gets the ICMP errors for all its peers, this tells
+/// them apart. None if the error is no ICMP error.
+///
+pub fn error_destination(error: &io::Error) -> Option<SocketAddrV4> {
+    error.get_ref()?.downcast_ref::<IcmpReport>().map(|report| report.dest)
+}
+
+/// A UDP socket on top of the raw stack.
+///
+/// Works like `std::net::UdpSocket`, but the kernel does not know about
+/// it, so the port is only *stolen* (see the security note of `main`).
+///
+pub struct RawUdpSocket {
+    /// The stack below
+    stack: Stack,
+    /// The port we are bound on
+    port: u16,
+    /// How long `recv_from` waits, None to wait forever
+    read_timeout: Option<Duration>,
+}
+
+impl RawUdpSocket {
+    /// Bind a socket on the given port of the stack.
+    pub fn bind(stack: Stack, port: u16) -> RawUdpSocket {
+        RawUdpSocket {
+            stack,
+            port,
+            read_timeout: None,
+        }
+    }
+
+    /// The address and port the socket is bound on.
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.stack.this_ip, self.port)
+    }
+
+    /// Set how long `recv_from` waits for a datagram, None to wait forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Send a datagram to the given address.
+    ///
+    /// Returns the number of payload bytes sent.
+    ///
+    pub fn send_to(&mut self, payload: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+        let datagram = create_datagram(addr, self.local_addr(), payload);
+        self.stack.send(*addr.ip(), PROTOCOL_UDP, &datagram)?;
+
+        Ok(payload.len())
+    }
+
+    /// Receive the next datagram sent to our port.
+    ///
+    /// Returns the payload and who sent it. Fails with an error of kind TimedOut
+    /// if the read timeout elapsed, or with the reported error if an ICMP error
+    /// quoting one of our datagrams arrives (see `error_destination`).
+    ///
+    pub fn recv_from(&mut self) -> io::Result<(Vec<u8>, SocketAddrV4)> {
+        let local = self.local_addr();
+        let start = Instant::now();
+
+        loop {
+            if let Some(timeout) = self.read_timeout && start.elapsed() >= timeout {
+                return Err(io::Error::new(ErrorKind::TimedOut, "UDP receive timed out"));
+            }
+
+            let packet = match self.stack.recv() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                // interrupted by a signal, its handler decides what happens
+                Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            match packet.protocol {
+                PROTOCOL_UDP => {
+                    if let Some(datagram) = parse(&packet) && datagram.dest_port == self.port {
+                        return Ok((datagram.payload.to_vec(), SocketAddrV4::new(packet.src, datagram.src_port)));
+                    }
+                },
+                // routers or the peer may answer with an ICMP error instead
+                PROTOCOL_ICMP => {
+                    if let Some(error) = icmp::unpack_udp_error(&packet, local) {
+                        let transport = error.quoted.transport;
+                        let dest = SocketAddrV4::new(error.quoted.dest, u16::from_be_bytes([transport[2], transport[3]]));
+
+                        let e = icmp::to_io_error(&error, packet.src);
+                        return Err(io::Error::new(e.kind(), IcmpReport { dest, message: e.to_string() }));
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+}
@@ -0,0 +1,344 @@
+// Command line arguments
+use std::{net::{Ipv4Addr, Ipv6Addr, SocketAddrV6}, process::exit, str::FromStr, time::Duration};
+
+use crate::{ip::{parse_dscp, FlowLabel, SendOptions}, ntp::{NtpOptions, ServerOptions}, ping::PingOptions, traceroute::{ProbeMode, TracerouteOptions}};
+
+/// What the program should do.
+pub enum Mode {
+    /// Query the time with NTP (default)
+    Ntp(NtpOptions),
+    /// Ping a host with ICMPv6 echo requests
+    Ping(PingOptions),
+    /// Trace the route to a host
+    Traceroute(TracerouteOptions),
+    /// Connect to a TCP server, send stdin and print the response
+    Connect(SocketAddrV6),
+    /// Accept TCP connections and echo what they send
+    Listen,
+    /// Query the time from the Date header of a web server (host[:port])
+    HttpDate(String),
+    /// Answer NTP client requests with our clock
+    Serve(ServerOptions),
+}
+
+/// NTP servers asked if none given
+const DEFAULT_SERVERS: [&str; 4] = ["2001:4860:4806::", "2001:4860:4806:4::", "2001:4860:4806:8::", "2001:4860:4806:c::"];
+
+/// NTS-KE server asked with --nts if none given
+const DEFAULT_NTS_SERVERS: [&str; 1] = ["time.cloudflare.com"];
+
+/// Keys file read for --key if none given, where ntpd keeps it
+const DEFAULT_KEYS: &str = "/etc/ntp.keys";
+
+/// Reference ID of a server above stratum 1 if none given: the address ntpd
+/// gives its local clock
+const LOCAL_CLOCK: [u8; 4] = [127, 127, 1, 0];
+
+/// The parsed command line arguments.
+pub struct Args {
+    /// The ethernet interface to use
+    pub interface: String,
+    /// The UDP (or TCP) port to bind on, if None a random free one:
+    /// of 1024-65535 for UDP, of the ephemeral range for TCP
+    pub port: Option<u16>,
+    /// Reserve the NTP port with a kernel socket while running
+    pub reserve_port: bool,
+    /// Install firewall rules keeping the kernel off the NTP port
+    pub guard: bool,
+    /// Print the firewall rules instead of installing them
+    pub dry_run: bool,
+    /// Options for the IP header of each packet we send
+    pub send_options: SendOptions,
+    /// What to do
+    pub mode: Mode,
+}
+
+/// Parse the command line arguments.
+///
+/// Options (starting with `--`) may appear anywhere, everything
+/// else are the positional arguments `<interface> [<UDP port>]`.
+///
+/// Prints the help message and exits on `--help`, or if no arguments given.
+///
+pub fn parse() -> Args {
+    let mut send_options = SendOptions::default();
+    let mut reserve_port = false;
+    let mut guard = true;
+    let mut dry_run = false;
+    let mut positional = Vec::new();
+    let mut ping_target: Option<Ipv6Addr> = None;
+    let mut count: u16 = 4;
+    let mut interval = Duration::from_secs(1);
+    let mut size: usize = 56;
+    let mut traceroute_target: Option<Ipv6Addr> = None;
+    let mut connect_target: Option<SocketAddrV6> = None;
+    let mut listen = false;
+    let mut http_date: Option<String> = None;
+    let mut servers = Vec::new();
+    let mut samples: usize = 4;
+    let mut serve = false;
+    let mut stratum: u8 = 1;
+    let mut reference_id: Option<[u8; 4]> = None;
+    let mut key: Option<u32> = None;
+    let mut keys: Option<String> = None;
+    let mut nts = false;
+    let mut nts_ca: Option<String> = None;
+    let mut probe_mode = ProbeMode::Udp;
+    let mut max_hops: u8 = 30;
+    let mut wait = Duration::from_secs(2);
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => help_message(0),
+            "--reserve-port" => reserve_port = true,
+            "--no-guard" => guard = false,
+            "--dry-run" => dry_run = true,
+            "--hop-limit" => send_options.hop_limit = value(&mut args, "--hop-limit"),
+            "--dscp" => {
+                let dscp: String = value(&mut args, "--dscp");
+                send_options.dscp = parse_dscp(&dscp).unwrap_or_else(|| invalid("--dscp", &dscp));
+            },
+            "--ecn" => {
+                let ecn: u8 = value(&mut args, "--ecn");
+                if ecn > 3 {
+                    invalid("--ecn", &ecn.to_string());
+                }
+                send_options.ecn = ecn;
+            },
+            "--flow-label" => {
+                let label: String = value(&mut args, "--flow-label");
+                send_options.flow_label = match label.as_str() {
+                    "auto" => FlowLabel::Auto,
+                    label => match label.parse::<u32>() {
+                        Ok(n) if n <= 0xfffff => FlowLabel::Fixed(n),
+                        _ => invalid("--flow-label", label),
+                    },
+                };
+            },
+            "--ping" => ping_target = Some(value(&mut args, "--ping")),
+            "--count" | "-c" => count = value(&mut args, "--count"),
+            "--interval" | "-i" => {
+                let secs: f64 = value(&mut args, "--interval");
+                interval = Duration::try_from_secs_f64(secs).unwrap_or_else(|_| invalid("--interval", &secs.to_string()));
+            },
+            "--size" | "-s" => {
+                size = value(&mut args, "--size");
+                // IPv6 payload length is 16 bit
+                if size > 65535 - 8 {
+                    invalid("--size", &size.to_string());
+                }
+            },
+            "--traceroute" => traceroute_target = Some(value(&mut args, "--traceroute")),
+            "--probe" => {
+                let mode: String = value(&mut args, "--probe");
+                probe_mode = match mode.as_str() {
+                    "udp" => ProbeMode::Udp,
+                    "icmp" => ProbeMode::Icmp,
+                    "paris" => ProbeMode::Paris,
+                    mode => invalid("--probe", mode),
+                };
+            },
+            "--max-hops" => max_hops = value(&mut args, "--max-hops"),
+            "--wait" => {
+                let secs: f64 = value(&mut args, "--wait");
+                wait = Duration::try_from_secs_f64(secs).unwrap_or_else(|_| invalid("--wait", &secs.to_string()));
+            },
+            "--connect" => connect_target = Some(value(&mut args, "--connect")),
+            "--listen" => listen = true,
+            "--http-date" => http_date = Some(value(&mut args, "--http-date")),
+            "--serve" => serve = true,
+            "--stratum" => {
+                stratum = value(&mut args, "--stratum");
+                if stratum == 0 || stratum > 15 {
+                    invalid("--stratum", &stratum.to_string());
+                }
+            },
+            "--refid" => {
+                let id: String = value(&mut args, "--refid");
+                reference_id = Some(parse_reference_id(&id).unwrap_or_else(|| invalid("--refid", &id)));
+            },
+            "--server" => servers.push(value(&mut args, "--server")),
+            "--samples" => {
+                samples = value(&mut args, "--samples");
+                // fewer than 4 leave the servers unfit, but are still shown
+                if samples == 0 {
+                    invalid("--samples", &samples.to_string());
+                }
+            },
+            "--key" => {
+                let id: u32 = value(&mut args, "--key");
+                // key ID 0 is reserved for the crypto-NAK
+                if id == 0 || id > 65535 {
+                    invalid("--key", &id.to_string());
+                }
+                key = Some(id);
+            },
+            "--keys" => keys = Some(value(&mut args, "--keys")),
+            "--nts" => nts = true,
+            "--nts-ca" => nts_ca = Some(value(&mut args, "--nts-ca")),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+
+    // Get interface name, or else display help and return
+    let interface = positional.next().unwrap_or_else(|| help_message(1));
+
+    // Port to bind UDP on
+    let port: Option<u16> = positional.next().map(|p| p.parse().expect("Cannot parse port"));
+
+    let mode = if let Some(target) = ping_target {
+        Mode::Ping(PingOptions { target, count, interval, size })
+    }else if let Some(target) = traceroute_target {
+        // like traceroute, use a source port derived from the process ID if none given
+        let port = port.unwrap_or(0x8000 | std::process::id() as u16);
+        Mode::Traceroute(TracerouteOptions { target, mode: probe_mode, max_hops, wait, port })
+    }else if let Some(target) = connect_target {
+        Mode::Connect(target)
+    }else if listen {
+        Mode::Listen
+    }else if let Some(server) = http_date {
+        Mode::HttpDate(server)
+    }else if serve {
+        // a primary server names its source, above the address of its server
+        let reference_id = reference_id.unwrap_or(if stratum == 1 { *b"LOCL" } else { LOCAL_CLOCK });
+        Mode::Serve(ServerOptions { stratum, reference_id, keys })
+    }else{
+        // NTS protects the requests already
+        if nts && key.is_some() {
+            println!("--key: Not together with --nts.");
+            exit(1);
+        }
+
+        // the four addresses of time.google.com, which has no NTS
+        if servers.is_empty() {
+            servers = if nts { DEFAULT_NTS_SERVERS.to_vec() } else { DEFAULT_SERVERS.to_vec() }.iter().map(|server| server.to_string()).collect();
+        }
+        let keys = keys.unwrap_or_else(|| DEFAULT_KEYS.to_string());
+        Mode::Ntp(NtpOptions { servers, samples, key, keys, nts, nts_ca })
+    };
+
+    Args {
+        interface,
+        port,
+        reserve_port,
+        guard,
+        dry_run,
+        send_options,
+        mode,
+    }
+}
+
+/// Get the value following an option, or exit if missing or not parseable.
+fn value<T: FromStr>(args: &mut impl Iterator<Item = String>, option: &str) -> T {
+    let Some(v) = args.next() else {
+        println!("{}: Missing value.", option);
+        exit(1);
+    };
+
+    v.parse().unwrap_or_else(|_| invalid(option, &v))
+}
+
+/// Report an invalid option value and exit.
+fn invalid(option: &str, value: &str) -> ! {
+    println!("{}: Invalid value '{}'.", option, value);
+    exit(1)
+}
+
+/// Parse a reference ID, either an IPv4 address or up to four ASCII
+/// characters like `GPS`, padded with zeros (RFC 5905, Section 7.3).
+///
+/// For an IPv6 server the reference ID is a hash of its address, which
+/// is given as IPv4 address as well.
+///
+fn parse_reference_id(s: &str) -> Option<[u8; 4]> {
+    if let Ok(addr) = s.parse::<Ipv4Addr>() {
+        return Some(addr.octets());
+    }
+
+    if s.is_empty() || s.len() > 4 || !s.bytes().all(|b| b.is_ascii_graphic()) {
+        return None;
+    }
+
+    let mut id = [0u8; 4];
+    id[..s.len()].copy_from_slice(s.as_bytes());
+    Some(id)
+}
+
+/// Help message.
+///
+fn help_message(code: i32) -> ! {
+    println!("Usage:    cargo run --release -p demo-ntp-udp-ipv6 -- [options] [--server <host[:port]>]... <interface> [<UDP port>]");
+    println!("          cargo run --release -p demo-ntp-udp-ipv6 -- [options] --ping <host> <interface>");
+    println!("          cargo run --release -p demo-ntp-udp-ipv6 -- [options] --traceroute <host> <interface> [<UDP port>]");
+    println!("          cargo run --release -p demo-ntp-udp-ipv6 -- [options] --connect <[host]:port> <interface> [<TCP port>]");
+    println!("          cargo run --release -p demo-ntp-udp-ipv6 -- [options] --listen <interface> [<TCP port>]");
+    println!("          cargo run --release -p demo-ntp-udp-ipv6 -- [options] --http-date <host[:port]> <interface> [<TCP port>]");
+    println!("          cargo run --release -p demo-ntp-udp-ipv6 -- [options] --serve <interface> [<UDP port>]\n");
+    println!("  --help, -h    Display this help message");
+    println!("  <interface>   The ethernet interface to send through.");
+    println!("  <UDP port>    Port to bind the UDP socket on (or better said steel the port).");
+    println!("                Must not be in use by the kernel. If not given, a random free");
+    println!("                port is chosen: of 1024-65535 for UDP, of the ephemeral range");
+    println!("                for TCP.");
+    println!("  --reserve-port Bind a kernel socket on the port while running, so the kernel");
+    println!("                neither hands it out nor answers with port unreachable.");
+    println!("  --no-guard    Do not install firewall rules dropping the traffic to the port");
+    println!("                before the kernel answers it with port unreachable.");
+    println!("  --dry-run     Print the firewall rules instead of installing them.\n");
+    println!("Ping:");
+    println!("  --ping <host>           Send ICMPv6 echo requests to <host> instead of querying NTP.");
+    println!("                          Use ff02::1 to find all IPv6 hosts on the link.");
+    println!("  --count, -c <n>         Number of echo requests (default 4).");
+    println!("  --interval, -i <s>      Seconds between echo requests (default 1).");
+    println!("  --size, -s <bytes>      Data bytes per echo request (default 56).\n");
+    println!("Traceroute:");
+    println!("  --traceroute <host>     Trace the route to <host> instead of querying NTP.");
+    println!("  --probe <udp|icmp|paris> Probe with UDP, ICMPv6 echo, or UDP with constant flow (default udp).");
+    println!("  --max-hops <n>          Maximum hop limit to probe (default 30).");
+    println!("  --wait <s>              Seconds to wait for the responses of a hop (default 2).\n");
+    println!("TCP:");
+    println!("  --connect <[host]:port> Connect to a TCP server instead of querying NTP, send stdin");
+    println!("                          and print everything received until the server closes.");
+    println!("  --listen                Accept TCP connections on <TCP port> instead of querying NTP,");
+    println!("                          and echo everything each client sends.\n");
+    println!("NTP:");
+    println!("  --server <host[:port]>  NTP server to ask, may be given several times (default the");
+    println!("                          four addresses of time.google.com). All are asked at once,");
+    println!("                          and the majority decides which ones tell the right time.");
+    println!("                          Addresses with port are written in brackets, [2001:db8::1]:123.");
+    println!("  --samples <n>           Requests per server, 2 seconds apart (default 4, the fewest");
+    println!("                          a server can be selected with, 8 fill the clock filter).");
+    println!("  --key <id>              Authenticate requests and replies with this key of the keys");
+    println!("                          file (MD5, SHA1 or AES128CMAC, like ntpd and chronyd).");
+    println!("  --keys <file>           Keys file in the ntp.keys format (default /etc/ntp.keys).");
+    println!("  --nts                   Network Time Security: the servers are NTS-KE servers");
+    println!("                          (host[:port], port 4460, default time.cloudflare.com), a");
+    println!("                          TLS key exchange gives the keys and cookies for NTP.");
+    println!("  --nts-ca <file>         Trust these CA certificates (PEM) for the NTS-KE as well.\n");
+    println!("NTP server:");
+    println!("  --serve                 Answer NTP client requests on <UDP port> (default 123) with");
+    println!("                          the local clock instead of querying NTP. Clients on our link");
+    println!("                          get their replies directly, others through the router.");
+    println!("  --stratum <1-15>        Stratum to claim (default 1).");
+    println!("  --refid <id>            Reference ID: up to four characters like GPS for stratum 1,");
+    println!("                          the upstream server as IPv4 address else (default LOCL,");
+    println!("                          127.127.1.0 above stratum 1).");
+    println!("  --keys <file>           Answer requests with a MAC of a key of this file with a MAC");
+    println!("                          of the same key (default no keys, a crypto-NAK to each).\n");
+    println!("HTTP Date:");
+    println!("  --http-date <host[:port]> Take the time from the Date header of a web server instead");
+    println!("                          of NTP, for networks blocking UDP port 123. Precise to one");
+    println!("                          second only.\n");
+    println!("IP header options:");
+    println!("  --hop-limit <n>         Hop limit (default 60).");
+    println!("  --dscp <class>          DSCP as number or name, e.g. EF, CS6, AF41 (default 0).");
+    println!("  --ecn <0-3>             ECN bits (default 0).");
+    println!("  --flow-label <n|auto>   Fixed flow label, or per-flow hash (RFC 6437) (default 0).\n");
+    println!("Bug reports issue at https://github.com/RossAdrian/raw-networking-demo/.");
+
+
+    exit(code)
+}
@@ -0,0 +1,44 @@
+// Connect (netcat like TCP client on top of the raw stack)
+use std::{io::{self, Read, Write}, net::SocketAddrV6, time::Duration};
+
+use crate::{ip::Stack, tcp::RawTcpStream};
+
+/// How long to wait for data of the server before giving up.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connect to a TCP server, send all of stdin and print everything received.
+///
+/// stdin is read until EOF first, then sent, then our direction is closed.
+/// Everything the server sends is printed until it closes its direction.
+///
+/// stack: The stack to send through
+/// port: Our TCP port
+/// remote: Address and port of the server
+///
+pub fn run(stack: Stack, port: u16, remote: SocketAddrV6) -> io::Result<()> {
+    let mut request = Vec::new();
+    io::stdin().read_to_end(&mut request)?;
+
+    let mut stream = RawTcpStream::connect(stack, port, remote)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT));
+
+    println!("Connected to {} from {}.", stream.peer_addr(), stream.local_addr());
+
+    stream.write_all(&request)?;
+    stream.shutdown()?;
+
+    // print the response as it arrives
+    let mut stdout = io::stdout();
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        stdout.write_all(&buf[..len])?;
+        stdout.flush()?;
+    }
+
+    Ok(())
+}
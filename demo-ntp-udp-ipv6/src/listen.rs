@@ -0,0 +1,55 @@
+// Listen (echo server on top of the raw stack)
+use std::{io::{self, Read, Write}, time::Duration};
+
+use crate::{ip::Stack, tcp::{RawTcpListener, RawTcpStream}};
+
+/// How long to wait for data of a client before giving up on it.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Accept TCP connections on a port and echo everything received (RFC 862).
+///
+/// Clients are served one after the other, until interrupted. Others may
+/// connect meanwhile, their handshakes complete and their data is
+/// buffered until it is their turn.
+///
+/// stack: The stack to send through
+/// port: Our TCP port
+///
+pub fn run(stack: Stack, port: u16) -> io::Result<()> {
+    let mut listener = RawTcpListener::bind(stack, port);
+    println!("Listening on {}.", listener.local_addr());
+
+    loop {
+        let (mut stream, remote) = listener.accept()?;
+        println!("Connection from {}.", remote);
+
+        match echo(&mut stream) {
+            Ok(len) => println!("Connection from {} closed, echoed {} bytes.", remote, len),
+            Err(e) => println!("Connection from {} failed: {}", remote, e),
+        }
+    }
+}
+
+/// Send back everything the client sends, then close our direction once
+/// the client closed its.
+///
+/// Returns the number of bytes echoed.
+///
+fn echo(stream: &mut RawTcpStream) -> io::Result<usize> {
+    stream.set_read_timeout(Some(READ_TIMEOUT));
+
+    let mut total = 0;
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        stream.write_all(&buf[..len])?;
+        total += len;
+    }
+
+    stream.shutdown()?;
+    Ok(total)
+}
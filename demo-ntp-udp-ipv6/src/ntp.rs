@@ -1,29 +1,721 @@
 // NTP (application layer)
-use chrono::DateTime;
+use std::{fmt, io::{self, ErrorKind}, net::SocketAddrV6, thread, time::{Duration, Instant}};
 
-/// Gets the NTP payload and returns the timestamp as string
-pub fn get_timestamp(payload: &[u8]) -> String {
-    // extract transmit timestamp from payload
-    let ntp_timestamp: [u8; 8] = payload[40..48].try_into().unwrap();
+use chrono::{DateTime, Utc};
 
-    // Split into seconds (first 4 bytes) and fractional seconds (last 4 bytes)
-    let seconds_part = u32::from_be_bytes([ntp_timestamp[0], ntp_timestamp[1], ntp_timestamp[2], ntp_timestamp[3]]);
+use crate::{auth::Key, nts, select::PHI, timestamp::{NtpShort, NtpTimestamp}, udp::{self, RawUdpSocket}};
 
-    // Convert NTP seconds to Unix timestamp
-    let unix_time = (seconds_part - 2208988800) as i64;
+/// NTP version we speak
+pub const VERSION: u8 = 4;
 
-    // Format the Unix timestamp
-    let naive_datetime = DateTime::from_timestamp(unix_time, 0).expect("Invalid timestamp");
-    naive_datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+/// Association mode: client
+pub const MODE_CLIENT: u8 = 3;
+
+/// Association mode: server
+pub const MODE_SERVER: u8 = 4;
+
+/// Leap indicator: the clock is not synchronized (alarm)
+const LEAP_ALARM: u8 = 3;
+
+/// Highest stratum of a synchronized server, 16 means unsynchronized
+const MAX_STRATUM: u8 = 15;
+
+/// Shortest and longest poll interval, log2 seconds (RFC 5905, Section 7.2)
+const MIN_POLL: i8 = 4;
+const MAX_POLL: i8 = 17;
+
+/// Precision of our clock, log2 seconds: about a microsecond, what reading
+/// the system clock takes
+pub const PRECISION: i8 = -20;
+
+/// Time between the requests of a burst (RFC 5905, Section 13)
+const BURST_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Length of the NTP header, without extension fields and MAC
+pub const HEADER_LEN: usize = 48;
+
+/// Shortest extension field, with its type and length (RFC 7822, Section 3)
+const EXTENSION_MIN_LEN: usize = 16;
+
+/// An extension field (RFC 7822).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtensionField {
+    /// Field type
+    pub field_type: u16,
+    /// The value, with its padding
+    pub value: Vec<u8>,
+}
+
+/// The legacy MAC trailer (RFC 5905, Section 7.3).
+///
+/// The digest is 16 bytes for MD5 and AES-CMAC, 20 bytes for SHA-1, or
+/// empty for a crypto-NAK, which is just a key ID of 0.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mac {
+    /// The key the digest was computed with
+    pub key_id: u32,
+    /// The digest over the packet in front of the MAC
+    pub digest: Vec<u8>,
+}
+
+/// Options of the NTP query.
+pub struct NtpOptions {
+    /// The servers to ask, as `host[:port]`
+    pub servers: Vec<String>,
+    /// Requests to send to each server
+    pub samples: usize,
+    /// ID of the key to authenticate the requests and replies with
+    pub key: Option<u32>,
+    /// The keys file to take the key from
+    pub keys: String,
+    /// The servers are NTS-KE servers, ask them with NTS
+    pub nts: bool,
+    /// Further CA certificates to trust for the NTS-KE
+    pub nts_ca: Option<String>,
+}
+
+/// Options of the NTP server.
+pub struct ServerOptions {
+    /// Stratum we claim, 1 for a primary server
+    pub stratum: u8,
+    /// Reference ID: the source for stratum 1, the address of our server else
+    pub reference_id: [u8; 4],
+    /// The keys file with the keys we share with clients, if any
+    pub keys: Option<String>,
+}
+
+/// A NTP packet (RFC 5905, Section 7.3).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NtpPacket {
+    /// Leap indicator, 2 bits: 0 no warning, 1/2 last minute of the day has 61/59 seconds, 3 unsynchronized
+    pub leap: u8,
+    /// Version number, 3 bits
+    pub version: u8,
+    /// Association mode, 3 bits
+    pub mode: u8,
+    /// Stratum, 0 unspecified or kiss-o'-death, 1 primary server, 2-15 secondary server
+    pub stratum: u8,
+    /// Maximum interval between two messages, log2 seconds
+    pub poll: i8,
+    /// Precision of the clock, log2 seconds
+    pub precision: i8,
+    /// Round trip delay to the reference clock
+    pub root_delay: NtpShort,
+    /// Dispersion to the reference clock
+    pub root_dispersion: NtpShort,
+    /// Reference ID: the source for stratum 1, the kiss code for stratum 0, the server address else
+    pub reference_id: [u8; 4],
+    /// Time the clock was last set or corrected
+    pub reference_timestamp: NtpTimestamp,
+    /// Time the request left the client (T1)
+    pub origin_timestamp: NtpTimestamp,
+    /// Time the request arrived at the server (T2)
+    pub receive_timestamp: NtpTimestamp,
+    /// Time the reply left the server (T3)
+    pub transmit_timestamp: NtpTimestamp,
+    /// Extension fields, in order
+    pub extensions: Vec<ExtensionField>,
+    /// The legacy MAC trailer
+    pub mac: Option<Mac>,
+}
+
+impl NtpPacket {
+    /// A client request, all zero but the version and the mode.
+    pub fn client_request() -> NtpPacket {
+        NtpPacket {
+            version: VERSION,
+            mode: MODE_CLIENT,
+            ..Default::default()
+        }
+    }
+
+    /// The reply of our server to a client request (RFC 5905, Section 9.2).
+    ///
+    /// The transmit timestamp of the request is copied into the origin
+    /// timestamp, whatever it is, as the client matches the reply by it.
+    /// Version and poll are those of the request, like ntpd answers. The
+    /// transmit timestamp (T3) is left zero, to be stamped as late as
+    /// possible.
+    ///
+    /// request: The request of the client
+    /// received: Our clock when the request arrived (T2)
+    /// reference: Our clock when it was last set
+    /// options: Stratum and reference ID to claim
+    ///
+    /// Returns None if this is no client request of a version we know.
+    ///
+    pub fn server_reply(request: &NtpPacket, received: NtpTimestamp, reference: NtpTimestamp, options: &ServerOptions) -> Option<NtpPacket> {
+        if request.mode != MODE_CLIENT || request.version == 0 || request.version > VERSION {
+            return None;
+        }
+
+        Some(NtpPacket {
+            version: request.version,
+            mode: MODE_SERVER,
+            stratum: options.stratum,
+            poll: request.poll.clamp(MIN_POLL, MAX_POLL),
+            precision: PRECISION,
+            reference_id: options.reference_id,
+            reference_timestamp: reference,
+            origin_timestamp: request.transmit_timestamp,
+            receive_timestamp: received,
+            ..Default::default()
+        })
+    }
+
+    /// Encode the packet: the header, the extension fields and the MAC.
+    ///
+    /// Extension field values are padded with zeros to a multiple of 4 bytes
+    /// and to the shortest field length.
+    ///
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LEN);
+
+        // LI (2 bits), VN (3 bits), Mode (3 bits)
+        data.push((self.leap & 0x03) << 6 | (self.version & 0x07) << 3 | self.mode & 0x07);
+        data.push(self.stratum);
+        data.push(self.poll as u8);
+        data.push(self.precision as u8);
+
+        data.extend_from_slice(&self.root_delay.0.to_be_bytes());
+        data.extend_from_slice(&self.root_dispersion.0.to_be_bytes());
+        data.extend_from_slice(&self.reference_id);
+
+        data.extend_from_slice(&self.reference_timestamp.0.to_be_bytes());
+        data.extend_from_slice(&self.origin_timestamp.0.to_be_bytes());
+        data.extend_from_slice(&self.receive_timestamp.0.to_be_bytes());
+        data.extend_from_slice(&self.transmit_timestamp.0.to_be_bytes());
+
+        for extension in &self.extensions {
+            let start = data.len();
+
+            // type, length (of the whole field), value
+            data.extend_from_slice(&extension.field_type.to_be_bytes());
+            data.extend_from_slice(&[0, 0]);
+            data.extend_from_slice(&extension.value);
+
+            // align 4, at least the shortest field
+            while (data.len() - start) % 4 != 0 || data.len() - start < EXTENSION_MIN_LEN {
+                data.push(0x00);
+            }
+
+            let len = (data.len() - start) as u16;
+            data[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+        }
+
+        if let Some(mac) = &self.mac {
+            data.extend_from_slice(&mac.key_id.to_be_bytes());
+            data.extend_from_slice(&mac.digest);
+        }
+
+        data
+    }
+
+    /// Decode a packet.
+    ///
+    /// After the header, what is left is told apart as RFC 7822, Section 7.5
+    /// does: 4 bytes are a crypto-NAK, 20 or 24 bytes a MAC with MD5 or SHA-1
+    /// digest, else an extension field comes first.
+    ///
+    /// Returns None if the packet is shorter than the header, or an
+    /// extension field is malformed.
+    ///
+    pub fn decode(data: &[u8]) -> Option<NtpPacket> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+
+        let u32_at = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let u64_at = |i: usize| u64::from(u32_at(i)) << 32 | u64::from(u32_at(i + 4));
+
+        let mut packet = NtpPacket {
+            leap: data[0] >> 6,
+            version: (data[0] >> 3) & 0x07,
+            mode: data[0] & 0x07,
+            stratum: data[1],
+            poll: data[2] as i8,
+            precision: data[3] as i8,
+            root_delay: NtpShort(u32_at(4)),
+            root_dispersion: NtpShort(u32_at(8)),
+            reference_id: [data[12], data[13], data[14], data[15]],
+            reference_timestamp: NtpTimestamp(u64_at(16)),
+            origin_timestamp: NtpTimestamp(u64_at(24)),
+            receive_timestamp: NtpTimestamp(u64_at(32)),
+            transmit_timestamp: NtpTimestamp(u64_at(40)),
+            extensions: Vec::new(),
+            mac: None,
+        };
+
+        let mut rest = &data[HEADER_LEN..];
+        while !rest.is_empty() {
+            // key ID and digest
+            if matches!(rest.len(), 4 | 20 | 24) {
+                packet.mac = Some(Mac {
+                    key_id: u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]),
+                    digest: rest[4..].to_vec(),
+                });
+                break;
+            }
+
+            // type, length (of the whole field, a multiple of 4), value
+            if rest.len() < EXTENSION_MIN_LEN {
+                return None;
+            }
+            let len = usize::from(u16::from_be_bytes([rest[2], rest[3]]));
+            if len < EXTENSION_MIN_LEN || !len.is_multiple_of(4) || len > rest.len() {
+                return None;
+            }
+
+            packet.extensions.push(ExtensionField {
+                field_type: u16::from_be_bytes([rest[0], rest[1]]),
+                value: rest[4..len].to_vec(),
+            });
+            rest = &rest[len..];
+        }
+
+        Some(packet)
+    }
+}
+
+/// Gets a NTP timestamp and returns it as string, with nanoseconds
+pub fn get_timestamp(timestamp: NtpTimestamp) -> String {
+    let time = DateTime::<Utc>::from(timestamp);
+    time.format("%Y-%m-%d %H:%M:%S%.9f").to_string()
 }
 
-/// Gets the data to send, and adds the NTP request payload
-pub fn add_request_payload(data: &mut Vec<u8>) {
-    // Leap Indicator = 0, Version = 4, Mode = Client
-    data.push(0xe3);
+/// A Kiss-o'-Death code, the reference ID of a reply with stratum 0
+/// (RFC 5905, Section 7.4).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KissCode {
+    /// Rate exceeded: we asked too often and must back off
+    Rate,
+    /// Access denied: we must stop asking this server
+    Deny,
+    /// Access restricted: we must stop asking this server
+    Rstr,
+    /// Any other code, e.g. INIT while the server is not synchronized yet
+    Other([u8; 4]),
+}
 
-    // push 47 0x00 bytes
-    for _ in 0..47 {
-        data.push(0x00);
+impl KissCode {
+    /// The kiss code in a reference ID.
+    pub fn decode(reference_id: [u8; 4]) -> KissCode {
+        match &reference_id {
+            b"RATE" => KissCode::Rate,
+            b"DENY" => KissCode::Deny,
+            b"RSTR" => KissCode::Rstr,
+            _ => KissCode::Other(reference_id),
+        }
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Display for KissCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KissCode::Rate => write!(f, "RATE (rate exceeded)"),
+            KissCode::Deny => write!(f, "DENY (access denied)"),
+            KissCode::Rstr => write!(f, "RSTR (access restricted)"),
+            // four ASCII characters, padded with zeros
+            KissCode::Other(code) => write!(f, "{}", String::from_utf8_lossy(code).trim_end_matches('\0')),
+        }
+    }
+}
+
+/// Check a reply is fit to take the time from (RFC 5905, Sections 7.4 and 8).
+///
+/// Rejects replies of another mode or an unknown version, from servers
+/// that are not synchronized (leap indicator 3, stratum 16 or more) and
+/// with zero timestamps. A reply with stratum 0 is a Kiss-o'-Death:
+///
+/// - RATE: we must back off, the error has kind `QuotaExceeded` and names
+///   how long to wait, at least the poll interval of the reply (`backoff`)
+/// - DENY, RSTR: we must not ask the server again, the error has kind
+///   `PermissionDenied`
+///
+pub fn check_reply(reply: &NtpPacket) -> io::Result<()> {
+    let invalid = |message: String| Err(io::Error::new(ErrorKind::InvalidData, message));
+
+    if reply.mode != MODE_SERVER {
+        return invalid(format!("Reply has mode {}, not server mode", reply.mode));
+    }
+
+    if reply.version == 0 || reply.version > VERSION {
+        return invalid(format!("Reply has unsupported version {}", reply.version));
+    }
+
+    if reply.stratum == 0 {
+        let code = KissCode::decode(reply.reference_id);
+
+        return Err(match code {
+            KissCode::Rate => io::Error::new(ErrorKind::QuotaExceeded, format!("Kiss-o'-Death {}: asking too often, back off for at least {} s", code, backoff(reply).as_secs())),
+            KissCode::Deny | KissCode::Rstr => io::Error::new(ErrorKind::PermissionDenied, format!("Kiss-o'-Death {}: the server refuses to serve us", code)),
+            KissCode::Other(_) => io::Error::new(ErrorKind::InvalidData, format!("Server is not synchronized (stratum 0, kiss code {})", code)),
+        });
+    }
+
+    if reply.leap == LEAP_ALARM {
+        return invalid("Server is not synchronized (leap indicator 3)".to_string());
+    }
+
+    if reply.stratum > MAX_STRATUM {
+        return invalid(format!("Server is not synchronized (stratum {})", reply.stratum));
+    }
+
+    // a server without time sends zero timestamps, which are no time of any era
+    if reply.receive_timestamp.is_zero() || reply.transmit_timestamp.is_zero() {
+        return invalid("Reply has a zero timestamp".to_string());
+    }
+
+    Ok(())
+}
+
+/// How long to wait after a Kiss-o'-Death RATE: the poll interval of the
+/// reply, within the limits of RFC 5905, Section 7.3.
+fn backoff(reply: &NtpPacket) -> Duration {
+    Duration::from_secs(1 << reply.poll.clamp(MIN_POLL, MAX_POLL))
+}
+
+/// The result of a request: how far our clock is from the server.
+pub struct Sample {
+    /// Offset of the server clock to ours (theta) in seconds, positive if ours is behind
+    pub offset: f64,
+    /// Round trip delay (delta) in seconds, without the time the server took
+    pub delay: f64,
+    /// Maximum error (epsilon) in seconds, from the precision of both clocks and the time it took
+    pub dispersion: f64,
+    /// When the reply arrived, the dispersion grows from then on
+    pub received: Instant,
+}
+
+impl Sample {
+    /// Compute offset, delay and dispersion from the four timestamps
+    /// (RFC 5905, Section 8).
+    ///
+    /// t1: Our clock when the request left
+    /// t2: The server clock when the request arrived
+    /// t3: The server clock when the reply left
+    /// t4: Our clock when the reply arrived
+    /// precision: Precision of the server clock, log2 seconds
+    ///
+    pub fn new(t1: NtpTimestamp, t2: NtpTimestamp, t3: NtpTimestamp, t4: NtpTimestamp, precision: i8) -> Sample {
+        // theta = ((T2 - T1) + (T3 - T4)) / 2, delta = (T4 - T1) - (T3 - T2)
+        let offset = (t2.difference(t1) + t3.difference(t4)) / 2.0;
+        let delay = t4.difference(t1) - t3.difference(t2);
+
+        // epsilon = 2^rho.r + 2^rho + PHI * (T4 - T1)
+        let dispersion = 2f64.powi(precision.into()) + 2f64.powi(PRECISION.into()) + PHI * t4.difference(t1);
+
+        Sample { offset, delay, dispersion, received: Instant::now() }
+    }
+}
+
+/// A server we query, and what we got from it.
+pub struct Peer {
+    /// Address of the server
+    pub server: SocketAddrV6,
+    /// The samples of the replies, oldest first
+    pub samples: Vec<Sample>,
+    /// The last reply that passed `check_reply`
+    pub reply: Option<NtpPacket>,
+    /// The last error, if a request failed
+    pub error: Option<io::Error>,
+    /// Requests left unanswered
+    pub unanswered: usize,
+    /// The server sent a Kiss-o'-Death DENY or RSTR, we do not ask it again
+    pub kissed: bool,
+    /// The server sent a Kiss-o'-Death RATE, we do not ask it again before then
+    pub backoff: Option<Instant>,
+    /// Keys and cookies of the server, if we ask it with NTS
+    nts: Option<nts::Session>,
+}
+
+impl Peer {
+    /// A server not asked yet.
+    pub fn new(server: SocketAddrV6, nts: Option<nts::Session>) -> Peer {
+        Peer {
+            server,
+            samples: Vec::new(),
+            reply: None,
+            error: None,
+            unanswered: 0,
+            kissed: false,
+            backoff: None,
+            nts,
+        }
+    }
+}
+
+/// A request waiting for its reply.
+struct Pending {
+    /// Index of the peer we asked
+    peer: usize,
+    /// The nonce we sent as transmit timestamp
+    nonce: NtpTimestamp,
+    /// Our clock when the request left (T1)
+    t1: NtpTimestamp,
+    /// The NTS Unique Identifier we sent, if any
+    unique_id: Option<nts::UniqueId>,
+}
+
+/// Send a request with a random nonce as transmit timestamp, and with our
+/// MAC if we have a key, or the NTS extension fields if we have a session.
+///
+/// Returns the nonce, T1 and the NTS Unique Identifier.
+///
+fn send_request(socket: &mut RawUdpSocket, server: SocketAddrV6, key: Option<&Key>, nts: Option<&mut nts::Session>) -> io::Result<(NtpTimestamp, NtpTimestamp, Option<nts::UniqueId>)> {
+    let mut request = NtpPacket::client_request();
+
+    // 64 random bits, not our clock
+    let nonce = NtpTimestamp(rand::random());
+    request.transmit_timestamp = nonce;
+    let unique_id = nts.map(|session| session.protect(&mut request)).transpose()?;
+    let request = match key {
+        Some(key) => key.sign(&request),
+        None => request.encode(),
+    };
+
+    // as late as possible
+    let t1 = NtpTimestamp::now();
+    socket.send_to(&request, server)?;
+
+    Ok((nonce, t1, unique_id))
+}
+
+/// Query several NTP servers at once, over one socket.
+///
+/// Each round sends a request to every server, then collects the replies
+/// until the next round is due, like the burst ntpd sends at startup.
+///
+/// A request carries a random nonce as transmit timestamp instead of our
+/// clock, which we keep as T1 (draft-ietf-ntp-data-minimization). The
+/// server returns the nonce as origin timestamp with its receive (T2) and
+/// transmit (T3) timestamps, and we take our clock again once the reply is
+/// in (T4). Replies not echoing the nonce of their server are ignored, so
+/// who spoofs a reply has to see our request first.
+///
+/// A server answering with a Kiss-o'-Death DENY or RSTR is not asked again,
+/// one answering with RATE not before the poll interval of its reply is
+/// over (RFC 5905, Section 7.4).
+///
+/// With a key, the requests carry a MAC, and only replies with a MAC of the
+/// same key that verifies count (RFC 5905, Section 7.3, RFC 8573), even a
+/// Kiss-o'-Death. The same holds for NTS (RFC 8915), where the replies
+/// have to echo the Unique Identifier of the request, and carry an
+/// Authenticator that verifies with the key of the server.
+///
+/// socket: The UDP socket to send the requests from
+/// servers: Addresses of the NTP servers
+/// rounds: How many requests to send to each server
+/// timeout: How long to wait for the replies of the last round
+/// key: The key to authenticate with, if any
+/// sessions: The NTS sessions of the servers, in the same order, none without NTS
+///
+/// Returns what we got from each server, in the order given.
+///
+pub fn query(socket: &mut RawUdpSocket, servers: &[SocketAddrV6], rounds: usize, timeout: Duration, key: Option<&Key>, sessions: Vec<nts::Session>) -> Vec<Peer> {
+    let mut sessions = sessions.into_iter();
+    let mut peers: Vec<Peer> = servers.iter().map(|&server| Peer::new(server, sessions.next())).collect();
+
+    for round in 0..rounds {
+        let start = Instant::now();
+
+        // everyone we may still ask, at once
+        let mut pending = Vec::new();
+        for (i, peer) in peers.iter_mut().enumerate().filter(|(_, peer)| !peer.kissed && peer.backoff.is_none_or(|backoff| backoff <= start)) {
+            match send_request(socket, peer.server, key, peer.nts.as_mut()) {
+                Ok((nonce, t1, unique_id)) => pending.push(Pending { peer: i, nonce, t1, unique_id }),
+                Err(e) => peer.error = Some(e),
+            }
+        }
+
+        let wait = if round + 1 < rounds { BURST_INTERVAL } else { timeout };
+        while !pending.is_empty() {
+            // wait only what is left of the round
+            let Some(left) = wait.checked_sub(start.elapsed()) else {
+                break;
+            };
+            socket.set_read_timeout(Some(left));
+
+            let (data, from) = match socket.recv_from() {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::TimedOut => break,
+                // a ICMPv6 error about one of our requests, no reply to expect
+                Err(e) => {
+                    if let Some(dest) = udp::error_destination(&e) && let Some(p) = pending.iter().position(|p| peers[p.peer].server == dest) {
+                        let p = pending.swap_remove(p);
+                        peers[p.peer].unanswered += 1;
+                        peers[p.peer].error = Some(e);
+                    }
+                    continue;
+                },
+            };
+            let t4 = NtpTimestamp::now();
+
+            // ignore anything else sent to our port, and replies to other requests
+            let Some(reply) = NtpPacket::decode(&data) else {
+                continue;
+            };
+            let Some(p) = pending.iter().position(|p| peers[p.peer].server == from && reply.origin_timestamp == p.nonce) else {
+                continue;
+            };
+            let p = pending.swap_remove(p);
+            let peer = &mut peers[p.peer];
+
+            let checked = match (key, peer.nts.as_mut(), &p.unique_id) {
+                (Some(key), _, _) => key.verify(&data, &reply),
+                (None, Some(session), Some(unique_id)) => session.verify(&data, &reply, unique_id),
+                _ => Ok(()),
+            }.and_then(|()| check_reply(&reply));
+
+            match checked {
+                Ok(()) => {
+                    peer.samples.push(Sample::new(p.t1, reply.receive_timestamp, reply.transmit_timestamp, t4, reply.precision));
+                    peer.reply = Some(reply);
+                },
+                Err(e) => {
+                    match e.kind() {
+                        ErrorKind::QuotaExceeded => peer.backoff = Some(Instant::now() + backoff(&reply)),
+                        ErrorKind::PermissionDenied => peer.kissed = true,
+                        _ => {},
+                    }
+                    peer.error = Some(e);
+                },
+            }
+        }
+
+        for p in pending {
+            peers[p.peer].unanswered += 1;
+        }
+
+        if peers.iter().all(|peer| peer.kissed) {
+            break;
+        }
+
+        // keep the rounds apart, even if all replies are in
+        if round + 1 < rounds && let Some(left) = wait.checked_sub(start.elapsed()) {
+            thread::sleep(left);
+        }
+    }
+
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server reply with every header field set to something telling.
+    fn reply() -> NtpPacket {
+        NtpPacket {
+            leap: 1,
+            version: VERSION,
+            mode: MODE_SERVER,
+            stratum: 2,
+            poll: 6,
+            precision: -23,
+            root_delay: NtpShort(0x0001_8000),
+            root_dispersion: NtpShort(0x0000_4000),
+            reference_id: [192, 0, 2, 1],
+            reference_timestamp: NtpTimestamp(0xec5a_2b00_8000_0000),
+            origin_timestamp: NtpTimestamp(0xec5a_2b10_0000_0001),
+            receive_timestamp: NtpTimestamp(0xec5a_2b10_4000_0000),
+            transmit_timestamp: NtpTimestamp(0xec5a_2b10_4000_1000),
+            ..Default::default()
+        }
+    }
+
+    /// A packet with just a header, followed by the given bytes.
+    fn header_and(rest: &[u8]) -> Vec<u8> {
+        let mut data = NtpPacket::client_request().encode();
+        data.extend_from_slice(rest);
+        data
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let packet = reply();
+        let data = packet.encode();
+
+        assert_eq!(data, [
+            0x64, 0x02, 0x06, 0xe9,
+            0x00, 0x01, 0x80, 0x00,
+            0x00, 0x00, 0x40, 0x00,
+            0xc0, 0x00, 0x02, 0x01,
+            0xec, 0x5a, 0x2b, 0x00, 0x80, 0x00, 0x00, 0x00,
+            0xec, 0x5a, 0x2b, 0x10, 0x00, 0x00, 0x00, 0x01,
+            0xec, 0x5a, 0x2b, 0x10, 0x40, 0x00, 0x00, 0x00,
+            0xec, 0x5a, 0x2b, 0x10, 0x40, 0x00, 0x10, 0x00,
+        ]);
+        assert_eq!(NtpPacket::decode(&data), Some(packet));
+    }
+
+    #[test]
+    fn extension_fields_padded() {
+        let mut packet = reply();
+        packet.extensions = vec![
+            // 4 + 1 bytes, padded to the shortest field
+            ExtensionField { field_type: 0x0104, value: vec![0xaa] },
+            // 4 + 17 bytes, padded to a multiple of 4
+            ExtensionField { field_type: 0x0204, value: vec![0xbb; 17] },
+        ];
+        packet.mac = Some(Mac { key_id: 7, digest: vec![0xcc; 16] });
+
+        let data = packet.encode();
+        assert_eq!(data.len(), HEADER_LEN + 16 + 24 + 20);
+        assert_eq!(data[HEADER_LEN..HEADER_LEN + 6], [0x01, 0x04, 0x00, 0x10, 0xaa, 0x00]);
+        assert_eq!(data[HEADER_LEN + 16..HEADER_LEN + 20], [0x02, 0x04, 0x00, 0x18]);
+
+        // the padding comes back as part of the value
+        let decoded = NtpPacket::decode(&data).unwrap();
+        assert_eq!(decoded.extensions[0].value, [&[0xaa][..], &[0; 11]].concat());
+        assert_eq!(decoded.extensions[1].value, [&[0xbb; 17][..], &[0; 3]].concat());
+        assert_eq!(decoded.mac, packet.mac);
+    }
+
+    #[test]
+    fn mac_told_apart_by_length() {
+        // crypto-NAK
+        let packet = NtpPacket::decode(&header_and(&[0, 0, 0, 0])).unwrap();
+        assert_eq!(packet.mac, Some(Mac { key_id: 0, digest: Vec::new() }));
+        assert!(packet.extensions.is_empty());
+
+        // MD5 and SHA-1 digests
+        for digest_len in [16, 20] {
+            let mut mac = vec![0, 0, 0, 42];
+            mac.resize(4 + digest_len, 0x5a);
+
+            let packet = NtpPacket::decode(&header_and(&mac)).unwrap();
+            assert_eq!(packet.mac, Some(Mac { key_id: 42, digest: vec![0x5a; digest_len] }));
+            assert!(packet.extensions.is_empty());
+        }
+
+        // 16 bytes are no MAC, but an extension field
+        let mut field = vec![0x01, 0x04, 0x00, 0x10];
+        field.resize(16, 0);
+        let packet = NtpPacket::decode(&header_and(&field)).unwrap();
+        assert_eq!(packet.extensions, [ExtensionField { field_type: 0x0104, value: vec![0; 12] }]);
+        assert_eq!(packet.mac, None);
+    }
+
+    #[test]
+    fn malformed_extension_fields_rejected() {
+        // shorter than the header
+        assert_eq!(NtpPacket::decode(&[0; HEADER_LEN - 1]), None);
+
+        // shorter than the shortest field, and no MAC either
+        assert_eq!(NtpPacket::decode(&header_and(&[0x01, 0x04, 0x00, 0x08, 0, 0, 0, 0])), None);
+
+        for len in [
+            12u16, // below the shortest field
+            18,    // not a multiple of 4
+            32,    // longer than the packet
+        ] {
+            let mut field = vec![0x01, 0x04];
+            field.extend_from_slice(&len.to_be_bytes());
+            field.resize(28, 0);
+
+            assert_eq!(NtpPacket::decode(&header_and(&field)), None, "length {}", len);
+        }
+    }
+}
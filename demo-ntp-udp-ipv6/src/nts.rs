@@ -0,0 +1,450 @@
+// Network Time Security (NTS-KE over TLS and the NTS extension fields, RFC 8915)
+use std::{fs, io::{self, ErrorKind, Read, Write}, net::{Ipv6Addr, SocketAddrV6}, sync::Arc, time::Duration};
+
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned, pki_types::{CertificateDer, ServerName, pem::PemObject}};
+
+use crate::{ip::{self, Stack}, ntp::{ExtensionField, NtpPacket, HEADER_LEN}, siv::{self, AesSiv}, tcp::RawTcpStream};
+
+/// TCP port of NTS-KE servers
+pub const KE_PORT: u16 = 4460;
+
+/// ALPN protocol ID of NTS-KE
+const ALPN_NTS_KE: &[u8] = b"ntske/1";
+
+/// Label of the TLS exporter the NTS keys come from
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security";
+
+/// Protocol ID of NTPv4 in the Next Protocol Negotiation record
+const PROTOCOL_NTPV4: u16 = 0;
+
+/// AEAD algorithm ID of AEAD_AES_SIV_CMAC_256 (RFC 5297), the one all NTS servers support
+const AEAD_AES_SIV_CMAC_256: u16 = 15;
+
+/// NTS-KE record types (RFC 8915, Section 4)
+const RECORD_END_OF_MESSAGE: u16 = 0;
+const RECORD_NEXT_PROTOCOL: u16 = 1;
+const RECORD_ERROR: u16 = 2;
+const RECORD_WARNING: u16 = 3;
+const RECORD_AEAD_ALGORITHM: u16 = 4;
+const RECORD_NEW_COOKIE: u16 = 5;
+const RECORD_SERVER: u16 = 6;
+const RECORD_PORT: u16 = 7;
+
+/// The critical bit of the record type
+const CRITICAL: u16 = 0x8000;
+
+/// Most records we read from a NTS-KE server
+const MAX_RECORDS: usize = 64;
+
+/// NTS extension field types (RFC 8915, Section 5.7)
+const UNIQUE_IDENTIFIER: u16 = 0x0104;
+const NTS_COOKIE: u16 = 0x0204;
+const NTS_COOKIE_PLACEHOLDER: u16 = 0x0304;
+const NTS_AUTHENTICATOR: u16 = 0x0404;
+
+/// Length of the Unique Identifier we send
+const UNIQUE_ID_LEN: usize = 32;
+
+/// Length of the AEAD nonce we send
+const NONCE_LEN: usize = 16;
+
+/// Cookies we keep, what NTS-KE servers hand out
+const MAX_COOKIES: usize = 8;
+
+/// A random Unique Identifier, matching an NTS reply to its request
+pub type UniqueId = [u8; UNIQUE_ID_LEN];
+
+/// What the NTS-KE gave us to talk to a NTP server.
+pub struct Session {
+    /// The NTP server to ask, the one the NTS-KE server names
+    pub server: SocketAddrV6,
+    /// Key of the requests (client to server)
+    c2s: AesSiv,
+    /// Key of the replies (server to client)
+    s2c: AesSiv,
+    /// Cookies, each good for one request
+    cookies: Vec<Vec<u8>>,
+}
+
+/// Resolve `host[:port]` of a NTS-KE server, port 4460 if left out.
+///
+/// Returns the address and the name its TLS certificate has to show.
+///
+pub fn resolve(server: &str) -> io::Result<(SocketAddrV6, String)> {
+    let address = ip::resolve(server, KE_PORT)?;
+
+    // an address literal is checked against the IP addresses of the certificate
+    let literal = server.trim_start_matches('[').trim_end_matches(']');
+    let name = if server.parse::<SocketAddrV6>().is_ok() || literal.parse::<Ipv6Addr>().is_ok() {
+        address.ip().to_string()
+    }else{
+        server.rsplit_once(':').map_or(server, |(host, _)| host).to_string()
+    };
+    Ok((address, name))
+}
+
+/// The TLS settings of NTS-KE: TLS 1.3 only, ALPN `ntske/1`, and the
+/// certificate checked against the web PKI roots.
+///
+/// ca_file: Further CA certificates to trust (PEM), e.g. for a server with
+/// a certificate of its own
+///
+pub fn tls_config(ca_file: Option<&str>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    if let Some(path) = ca_file {
+        let pem = fs::read(path)?;
+        for certificate in CertificateDer::pem_slice_iter(&pem) {
+            let certificate = certificate.map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            roots.add(certificate).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        }
+    }
+
+    let mut config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![ALPN_NTS_KE.to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+/// Encode a NTS-KE record: type with the critical bit, body length, body.
+fn record(record_type: u16, critical: bool, body: &[u8]) -> Vec<u8> {
+    let record_type = if critical { record_type | CRITICAL } else { record_type };
+
+    let mut data = Vec::with_capacity(4 + body.len());
+    data.extend_from_slice(&record_type.to_be_bytes());
+    data.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    data.extend_from_slice(body);
+    data
+}
+
+/// The u16 values of a record body.
+fn u16_values(body: &[u8]) -> Vec<u16> {
+    body.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect()
+}
+
+/// Export a NTS key from the TLS session (RFC 8915, Section 5.1).
+///
+/// direction: 0 for the key of the requests, 1 for the replies
+///
+fn export_key(connection: &ClientConnection, direction: u8) -> io::Result<AesSiv> {
+    // protocol ID, AEAD algorithm ID, direction
+    let [p0, p1] = PROTOCOL_NTPV4.to_be_bytes();
+    let [a0, a1] = AEAD_AES_SIV_CMAC_256.to_be_bytes();
+    let context = [p0, p1, a0, a1, direction];
+
+    let key = connection.export_keying_material([0; siv::KEY_LEN], EXPORTER_LABEL, Some(&context)).map_err(io::Error::other)?;
+    Ok(AesSiv::new(&key))
+}
+
+/// Run the NTS key exchange with a NTS-KE server (RFC 8915, Section 4).
+///
+/// Over TLS 1.3 on our TCP stream, we ask for NTPv4 with AES-SIV-CMAC-256,
+/// and the server answers with the cookies and, optionally, the NTP server
+/// to ask and its port. Both keys are exported from the TLS session, so
+/// only we and the server know them.
+///
+/// stack: The stack to send through
+/// port: Our TCP port
+/// server: Address and port of the NTS-KE server
+/// name: The name its certificate has to show
+/// config: The TLS settings from `tls_config`
+/// timeout: How long to wait for data of the server
+///
+pub fn key_exchange(stack: Stack, port: u16, server: SocketAddrV6, name: &str, config: Arc<ClientConfig>, timeout: Duration) -> io::Result<Session> {
+    let name = ServerName::try_from(name.to_string()).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    let connection = ClientConnection::new(config, name).map_err(io::Error::other)?;
+
+    let mut stream = RawTcpStream::connect(stack, port, server)?;
+    stream.set_read_timeout(Some(timeout));
+    let mut tls = StreamOwned::new(connection, stream);
+
+    // NTPv4, AES-SIV-CMAC-256, end of message: the handshake runs on the first write
+    let mut request = record(RECORD_NEXT_PROTOCOL, true, &PROTOCOL_NTPV4.to_be_bytes());
+    request.extend(record(RECORD_AEAD_ALGORITHM, true, &AEAD_AES_SIV_CMAC_256.to_be_bytes()));
+    request.extend(record(RECORD_END_OF_MESSAGE, true, &[]));
+    tls.write_all(&request)?;
+    tls.flush()?;
+
+    // without ALPN the server does not speak NTS-KE
+    if tls.conn.alpn_protocol() != Some(ALPN_NTS_KE) {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Server did not negotiate NTS-KE (ALPN ntske/1)"));
+    }
+
+    let mut protocol = None;
+    let mut algorithm = None;
+    let mut cookies = Vec::new();
+    let mut ntp_server = None;
+    let mut ntp_port = None;
+
+    let mut records = 0;
+    loop {
+        records += 1;
+        if records > MAX_RECORDS {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Too many NTS-KE records"));
+        }
+
+        let mut header = [0; 4];
+        tls.read_exact(&mut header)?;
+        let record_type = u16::from_be_bytes([header[0], header[1]]);
+        let mut body = vec![0; usize::from(u16::from_be_bytes([header[2], header[3]]))];
+        tls.read_exact(&mut body)?;
+
+        match record_type & !CRITICAL {
+            RECORD_END_OF_MESSAGE => break,
+            RECORD_NEXT_PROTOCOL => protocol = u16_values(&body).first().copied(),
+            RECORD_AEAD_ALGORITHM => algorithm = u16_values(&body).first().copied(),
+            RECORD_NEW_COOKIE => cookies.push(body),
+            RECORD_SERVER => ntp_server = Some(String::from_utf8(body).map_err(|_| io::Error::new(ErrorKind::InvalidData, "Invalid NTP server name"))?),
+            RECORD_PORT => ntp_port = u16_values(&body).first().copied(),
+            RECORD_ERROR => {
+                let code = u16_values(&body).first().copied().unwrap_or(u16::MAX);
+                let reason = match code {
+                    0 => "unrecognized critical record",
+                    1 => "bad request",
+                    2 => "internal server error",
+                    _ => "unknown error",
+                };
+                return Err(io::Error::new(ErrorKind::ConnectionRefused, format!("NTS-KE server sent error {} ({})", code, reason)));
+            },
+            // no warnings are defined, so we know none of them
+            RECORD_WARNING => return Err(io::Error::new(ErrorKind::InvalidData, format!("NTS-KE server sent unknown warning {:?}", u16_values(&body)))),
+            other if record_type & CRITICAL != 0 => return Err(io::Error::new(ErrorKind::InvalidData, format!("Unknown critical NTS-KE record {}", other))),
+            // non-critical records we do not know are ignored
+            _ => {},
+        }
+    }
+
+    // the keys, before the connection goes
+    let c2s = export_key(&tls.conn, 0)?;
+    let s2c = export_key(&tls.conn, 1)?;
+
+    // the keys are ours, a close that fails does not matter
+    tls.conn.send_close_notify();
+    let _ = tls.flush().and_then(|()| tls.sock.shutdown());
+
+    if protocol != Some(PROTOCOL_NTPV4) {
+        return Err(io::Error::new(ErrorKind::InvalidData, "NTS-KE server does not offer NTPv4"));
+    }
+    if algorithm != Some(AEAD_AES_SIV_CMAC_256) {
+        return Err(io::Error::new(ErrorKind::InvalidData, "NTS-KE server does not offer AES-SIV-CMAC-256"));
+    }
+    if cookies.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "NTS-KE server sent no cookies"));
+    }
+    cookies.truncate(MAX_COOKIES);
+
+    // the NTP server is the NTS-KE server on port 123, unless it names another one
+    let ntp_port = ntp_port.unwrap_or(123);
+    let server = match ntp_server {
+        Some(host) => ip::resolve(&host, ntp_port)?,
+        None => SocketAddrV6::new(*server.ip(), ntp_port, 0, 0),
+    };
+
+    Ok(Session { server, c2s, s2c, cookies })
+}
+
+impl Session {
+    /// The cookies we have left.
+    pub fn cookies(&self) -> usize {
+        self.cookies.len()
+    }
+
+    /// Protect a request with NTS (RFC 8915, Section 5.7).
+    ///
+    /// Adds a random Unique Identifier, one of our cookies, a placeholder
+    /// for each cookie we miss, and the Authenticator: the AEAD tag over
+    /// the packet up to it, with the key of the requests.
+    ///
+    /// Returns the Unique Identifier the reply has to echo.
+    ///
+    pub fn protect(&mut self, request: &mut NtpPacket) -> io::Result<UniqueId> {
+        let cookie = self.cookies.pop().ok_or_else(|| io::Error::new(ErrorKind::NotFound, "No NTS cookies left, a new key exchange is needed"))?;
+        let unique_id: UniqueId = rand::random();
+
+        // a placeholder of the size of the cookie for each missing cookie, so the reply bringing them is no larger
+        let placeholder = ExtensionField { field_type: NTS_COOKIE_PLACEHOLDER, value: vec![0; cookie.len()] };
+        request.extensions.push(ExtensionField { field_type: UNIQUE_IDENTIFIER, value: unique_id.to_vec() });
+        request.extensions.push(ExtensionField { field_type: NTS_COOKIE, value: cookie });
+        for _ in self.cookies.len() + 1..MAX_COOKIES {
+            request.extensions.push(placeholder.clone());
+        }
+
+        // nonce length, ciphertext length, nonce, ciphertext: the tag alone, we encrypt nothing
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let sealed = self.c2s.seal(&[&request.encode(), &nonce], &[]);
+
+        let mut value = Vec::with_capacity(4 + NONCE_LEN + sealed.len());
+        value.extend_from_slice(&(NONCE_LEN as u16).to_be_bytes());
+        value.extend_from_slice(&(sealed.len() as u16).to_be_bytes());
+        value.extend_from_slice(&nonce);
+        value.extend_from_slice(&sealed);
+        request.extensions.push(ExtensionField { field_type: NTS_AUTHENTICATOR, value });
+
+        Ok(unique_id)
+    }
+
+    /// Verify the NTS of a reply (RFC 8915, Section 5.7), and keep the
+    /// cookies it brings.
+    ///
+    /// data: The reply as received
+    /// reply: The reply decoded
+    /// unique_id: The Unique Identifier of our request
+    ///
+    /// Returns an error of kind InvalidData naming why if the reply does
+    /// not echo our Unique Identifier, is a NTS NAK, or its Authenticator
+    /// is missing or does not verify.
+    ///
+    pub fn verify(&mut self, data: &[u8], reply: &NtpPacket, unique_id: &UniqueId) -> io::Result<()> {
+        let invalid = |message: &str| Err(io::Error::new(ErrorKind::InvalidData, message.to_string()));
+
+        if !reply.extensions.iter().any(|field| field.field_type == UNIQUE_IDENTIFIER && field.value == unique_id) {
+            return invalid("Reply does not echo our NTS Unique Identifier");
+        }
+
+        // the Authenticator comes last, it covers everything in front of it
+        let Some((authenticator, covered)) = reply.extensions.split_last().filter(|(last, _)| last.field_type == NTS_AUTHENTICATOR) else {
+            // a NTS NAK is not authenticated, the server could not decrypt our cookie
+            if reply.stratum == 0 && &reply.reference_id == b"NTSN" {
+                return invalid("Server sent a NTS NAK, it did not accept our cookie");
+            }
+            return invalid("Reply is not authenticated with NTS");
+        };
+        let covered_len = HEADER_LEN + covered.iter().map(|field| 4 + field.value.len()).sum::<usize>();
+
+        // nonce length, ciphertext length, nonce and ciphertext, each padded to 4 bytes
+        let value = &authenticator.value;
+        if value.len() < 4 {
+            return invalid("NTS Authenticator too short");
+        }
+        let nonce_len = usize::from(u16::from_be_bytes([value[0], value[1]]));
+        let sealed_len = usize::from(u16::from_be_bytes([value[2], value[3]]));
+        let sealed_start = 4 + nonce_len.next_multiple_of(4);
+        let (Some(nonce), Some(sealed)) = (value.get(4..4 + nonce_len), value.get(sealed_start..sealed_start + sealed_len)) else {
+            return invalid("NTS Authenticator too short");
+        };
+
+        let Some(plaintext) = self.s2c.open(&[&data[..covered_len], nonce], sealed) else {
+            return invalid("Reply does not verify with the NTS key");
+        };
+
+        // the encrypted extension fields carry the new cookies
+        let mut rest = &plaintext[..];
+        while rest.len() >= 4 {
+            let field_type = u16::from_be_bytes([rest[0], rest[1]]);
+            let len = usize::from(u16::from_be_bytes([rest[2], rest[3]]));
+            if len < 4 || len > rest.len() {
+                break;
+            }
+
+            if field_type == NTS_COOKIE && self.cookies.len() < MAX_COOKIES {
+                self.cookies.push(rest[4..len].to_vec());
+            }
+            rest = &rest[len..];
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ntp::{MODE_SERVER, VERSION};
+
+    use super::*;
+
+    /// A session with two cookies, keys of all 1 (requests) and all 2 (replies).
+    fn session() -> Session {
+        Session {
+            server: "[2001:db8::1]:123".parse().unwrap(),
+            c2s: AesSiv::new(&[1; siv::KEY_LEN]),
+            s2c: AesSiv::new(&[2; siv::KEY_LEN]),
+            cookies: vec![vec![0xc1; 24], vec![0xc2; 24]],
+        }
+    }
+
+    /// The reply of a server to a request with the given Unique Identifier,
+    /// with a field of our own padded to the shortest length, an odd nonce
+    /// length and a new cookie in the ciphertext.
+    fn reply(unique_id: &UniqueId) -> Vec<u8> {
+        let mut reply = NtpPacket { version: VERSION, mode: MODE_SERVER, stratum: 1, ..Default::default() };
+        reply.extensions.push(ExtensionField { field_type: 0x0f00, value: vec![0xee; 5] });
+        reply.extensions.push(ExtensionField { field_type: UNIQUE_IDENTIFIER, value: unique_id.to_vec() });
+
+        let nonce = [0x4e; 15];
+        let mut cookie = NTS_COOKIE.to_be_bytes().to_vec();
+        cookie.extend_from_slice(&20u16.to_be_bytes());
+        cookie.extend_from_slice(&[0xc3; 16]);
+        let sealed = AesSiv::new(&[2; siv::KEY_LEN]).seal(&[&reply.encode(), &nonce], &cookie);
+
+        // nonce padded to 16 bytes
+        let mut value = Vec::new();
+        value.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+        value.extend_from_slice(&(sealed.len() as u16).to_be_bytes());
+        value.extend_from_slice(&nonce);
+        value.push(0);
+        value.extend_from_slice(&sealed);
+        reply.extensions.push(ExtensionField { field_type: NTS_AUTHENTICATOR, value });
+
+        reply.encode()
+    }
+
+    #[test]
+    fn protect_and_verify() {
+        let mut session = session();
+        let mut request = NtpPacket::client_request();
+        let unique_id = session.protect(&mut request).unwrap();
+
+        // Unique Identifier, the last cookie, placeholders up to 8, the Authenticator
+        let types: Vec<u16> = request.extensions.iter().map(|field| field.field_type).collect();
+        assert_eq!(types, [&[UNIQUE_IDENTIFIER, NTS_COOKIE][..], &[NTS_COOKIE_PLACEHOLDER; 6], &[NTS_AUTHENTICATOR]].concat());
+        assert_eq!(request.extensions[1].value, [0xc2; 24]);
+        assert_eq!(session.cookies(), 1);
+
+        // the server opens the Authenticator over everything in front of it
+        let data = request.encode();
+        let request = NtpPacket::decode(&data).unwrap();
+        let (authenticator, covered) = request.extensions.split_last().unwrap();
+        let covered = NtpPacket { extensions: covered.to_vec(), ..request.clone() }.encode();
+        let value = &authenticator.value;
+        assert_eq!(value[..4], [0, NONCE_LEN as u8, 0, siv::TAG_LEN as u8]);
+        let nonce = &value[4..4 + NONCE_LEN];
+        assert_eq!(AesSiv::new(&[1; siv::KEY_LEN]).open(&[&covered, nonce], &value[4 + NONCE_LEN..]), Some(Vec::new()));
+
+        // the reply verifies and brings a cookie
+        let data = reply(&unique_id);
+        session.verify(&data, &NtpPacket::decode(&data).unwrap(), &unique_id).unwrap();
+        assert_eq!(session.cookies, [vec![0xc1; 24], vec![0xc3; 16]]);
+    }
+
+    #[test]
+    fn forged_replies_rejected() {
+        let mut session = session();
+        let unique_id = [0x55; UNIQUE_ID_LEN];
+        let data = reply(&unique_id);
+
+        // another Unique Identifier
+        let e = session.verify(&data, &NtpPacket::decode(&data).unwrap(), &[0x66; UNIQUE_ID_LEN]).unwrap_err();
+        assert!(e.to_string().contains("Unique Identifier"), "{}", e);
+
+        // a bit flipped in the header
+        let mut tampered = data.clone();
+        tampered[1] ^= 0x01;
+        let e = session.verify(&tampered, &NtpPacket::decode(&tampered).unwrap(), &unique_id).unwrap_err();
+        assert!(e.to_string().contains("does not verify"), "{}", e);
+
+        // a NTS NAK: stratum 0, kiss code NTSN, no Authenticator
+        let nak = NtpPacket {
+            reference_id: *b"NTSN",
+            extensions: vec![ExtensionField { field_type: UNIQUE_IDENTIFIER, value: unique_id.to_vec() }],
+            ..NtpPacket::client_request()
+        }.encode();
+        let e = session.verify(&nak, &NtpPacket::decode(&nak).unwrap(), &unique_id).unwrap_err();
+        assert!(e.to_string().contains("NTS NAK"), "{}", e);
+
+        assert_eq!(session.cookies(), 2);
+    }
+}
@@ -0,0 +1,128 @@
+// Ping (ICMPv6 echo on top of the raw stack)
+use std::{collections::HashMap, io::ErrorKind, net::Ipv6Addr, time::{Duration, Instant}};
+
+use pnet::datalink::{DataLinkReceiver, DataLinkSender};
+
+use crate::{ip::{self, SendOptions}, link, stats::Statistics};
+
+/// How long to wait for outstanding replies after the last request.
+const LINGER: Duration = Duration::from_secs(2);
+
+/// Options of a ping session.
+pub struct PingOptions {
+    /// The host to ping, may be a multicast address like ff02::1
+    pub target: Ipv6Addr,
+    /// Number of echo requests to send
+    pub count: u16,
+    /// Time between two echo requests
+    pub interval: Duration,
+    /// Number of data bytes in each echo request
+    pub size: usize,
+}
+
+/// Run a ping session.
+///
+/// Sends `count` echo requests, matches the replies by identifier and
+/// sequence number and prints the round trip times.
+///
+/// For multicast targets (like the all-nodes address ff02::1) every host
+/// answering is listed at the end, which enumerates the on-link IPv6 hosts.
+///
+/// this_ip: The source address, must be link-local for link-local targets
+/// dest_mac: The gateway MAC for unicast, or the multicast MAC of the target
+///
+pub fn run(tx: &mut dyn DataLinkSender, rx: &mut dyn DataLinkReceiver, this_mac: [u8; 6], this_ip: Ipv6Addr, dest_mac: [u8; 6], send_options: &SendOptions, options: &PingOptions) -> Statistics {
+    // identify our session by the process ID, like ping does
+    let identifier = std::process::id() as u16;
+
+    // payload pattern 0x00, 0x01, ...
+    let payload: Vec<u8> = (0..options.size).map(|i| i as u8).collect();
+
+    let multicast = options.target.is_multicast();
+
+    println!("PING {} from {}: {} data bytes", options.target, this_ip, options.size);
+
+    let mut stats = Statistics::default();
+    let mut pending: HashMap<u16, Instant> = HashMap::new();
+    let mut responders: Vec<(Ipv6Addr, [u8; 6])> = Vec::new();
+    let mut sequence: u16 = 0;
+
+    let start = Instant::now();
+    let mut next_send = start;
+    let mut last_send = start;
+
+    loop {
+        let now = Instant::now();
+
+        // send next request if it is time
+        if stats.transmitted < u32::from(options.count) && now >= next_send {
+            sequence = sequence.wrapping_add(1);
+
+            let mut frame = Vec::new();
+            link::push_ethernet_header(&mut frame, &this_mac, &dest_mac);
+            ip::icmpv6_create_echo_request(&mut frame, &this_ip, &options.target, identifier, sequence, &payload, send_options);
+
+            if let Some(Err(e)) = tx.send_to(&frame, None) {
+                println!("Could not send echo request: {}", e);
+            }
+
+            pending.insert(sequence, Instant::now());
+            stats.transmitted += 1;
+            next_send += options.interval;
+            last_send = now;
+        }
+
+        // all sent, wait for the remaining replies (multicast: always wait, anyone may answer)
+        if stats.transmitted == u32::from(options.count) && ((stats.received() == stats.transmitted && !multicast) || now - last_send > LINGER) {
+            break;
+        }
+
+        let frame = match rx.next() {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => panic!("Error occured: {}", e),
+        };
+
+        // parse bottom-up
+        let Some(packet) = link::unwrap_ip_packet(frame, &this_mac).and_then(ip::parse) else {
+            continue;
+        };
+
+        if packet.dest != this_ip || (!multicast && packet.src != options.target) {
+            continue;
+        }
+
+        let Some(reply) = ip::icmpv6_unpack_echo_reply(&packet) else {
+            continue;
+        };
+
+        if reply.identifier != identifier {
+            continue;
+        }
+
+        // multicast requests are answered by many hosts, so keep them pending
+        if let Some(&sent) = pending.get(&reply.sequence) {
+            let rtt = sent.elapsed();
+            let duplicate = stats.add(reply.sequence, rtt);
+
+            println!("{} bytes from {}: icmp_seq={} ttl={} time={:.3} ms{}",
+                reply.payload.len() + 8, packet.src, reply.sequence, packet.hop_limit, rtt.as_secs_f64() * 1000.0, if duplicate { " (DUP!)" } else { "" });
+
+            let src_mac: [u8; 6] = frame[6..12].try_into().unwrap();
+            if !responders.iter().any(|(ip, _)| *ip == packet.src) {
+                responders.push((packet.src, src_mac));
+            }
+        }
+    }
+
+    stats.print(&options.target.to_string(), start.elapsed());
+
+    if multicast {
+        println!("\n{} host(s) responded:", responders.len());
+        for (ip, mac) in responders {
+            println!("  {:<40} {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", ip.to_string(), mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]);
+        }
+    }
+
+    stats
+}
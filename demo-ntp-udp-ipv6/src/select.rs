@@ -0,0 +1,413 @@
+// Clock selection (clock filter, intersection and clustering, RFC 5905, Sections 10 and 11)
+use std::time::{Duration, Instant};
+
+use crate::ntp::{Peer, PRECISION};
+
+/// Frequency tolerance, how fast the dispersion of a sample grows: 15 PPM (RFC 5905, Section 7.2)
+pub const PHI: f64 = 15e-6;
+
+/// Samples the clock filter keeps per server
+const FILTER_STAGES: usize = 8;
+
+/// Dispersion of an empty filter stage, in seconds
+const MAX_DISPERSION: f64 = 16.0;
+
+/// Smallest root delay counted for the root distance, in seconds
+const MIN_DISPERSION: f64 = 0.005;
+
+/// Largest root distance of a server we select, in seconds
+pub const MAX_DISTANCE: f64 = 1.5;
+
+/// Fewest samples that bring the root distance of a server below 1.5 s,
+/// the empty stages of fewer add up to more
+pub const MIN_SAMPLES: usize = 4;
+
+/// Fewest survivors the clustering keeps
+const MIN_CLUSTER: usize = 3;
+
+/// What the clock filter makes of the samples of a server (RFC 5905, Section 10).
+pub struct Filtered {
+    /// Offset of the sample with the lowest delay, in seconds
+    pub offset: f64,
+    /// Delay of that sample
+    pub delay: f64,
+    /// RMS of the differences of the other offsets to the one taken
+    pub jitter: f64,
+    /// Root distance: how far the offset may be off the reference clock at most
+    pub distance: f64,
+    /// Stratum of the server
+    pub stratum: u8,
+}
+
+/// Run the clock filter over the last eight samples of a server
+/// (RFC 5905, Section 10).
+///
+/// The sample with the lowest delay suffered the least from queues on the
+/// way, so its offset is taken. The dispersion of a sample grows with its
+/// age, and stages without a sample count as 16 s, so a server needs
+/// several samples until its root distance allows it to be selected.
+///
+/// peer: The server and its samples
+/// now: The time to age the samples to
+///
+/// Returns None if no reply of the server passed the checks.
+///
+pub fn clock_filter(peer: &Peer, now: Instant) -> Option<Filtered> {
+    let reply = peer.reply.as_ref()?;
+
+    // offset, delay and aged dispersion of each stage
+    let start = peer.samples.len().saturating_sub(FILTER_STAGES);
+    let mut stages: Vec<(f64, f64, f64)> = peer.samples[start..].iter()
+        .map(|sample| (sample.offset, sample.delay, sample.dispersion + PHI * now.duration_since(sample.received).as_secs_f64()))
+        .collect();
+    stages.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let &(offset, delay, _) = stages.first()?;
+
+    // epsilon = sum of epsilon_i / 2^(i + 1), in the order of the delay
+    let dispersion: f64 = (0..FILTER_STAGES)
+        .map(|i| stages.get(i).map_or(MAX_DISPERSION, |stage| stage.2) / 2f64.powi(i as i32 + 1))
+        .sum();
+
+    // psi = sqrt(sum of (theta_i - theta_0)^2 / (n - 1)), at least our precision
+    let jitter = if stages.len() > 1 {
+        (stages[1..].iter().map(|stage| (stage.0 - offset).powi(2)).sum::<f64>() / (stages.len() - 1) as f64).sqrt()
+    }else{
+        0.0
+    };
+    let jitter = jitter.max(2f64.powi(PRECISION.into()));
+
+    // lambda = (delta_r + delta) / 2 + epsilon_r + epsilon + psi (Section 11.2)
+    let root_delay = Duration::from(reply.root_delay).as_secs_f64();
+    let root_dispersion = Duration::from(reply.root_dispersion).as_secs_f64();
+    let distance = (root_delay + delay).max(MIN_DISPERSION) / 2.0 + root_dispersion + dispersion + jitter;
+
+    Some(Filtered { offset, delay, jitter, distance, stratum: reply.stratum })
+}
+
+/// How the selection judged a server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The best survivor, the clock we follow
+    SystemPeer,
+    /// Survived the clustering, its offset is combined
+    Survivor,
+    /// A truechimer the clustering dropped
+    Outlier,
+    /// Its correctness interval misses the one the majority agrees on
+    Falseticker,
+    /// Root distance over 1.5 s, too far off to be selected
+    Unfit,
+    /// Selectable, but no majority of the servers agrees
+    Candidate,
+}
+
+impl Verdict {
+    /// The tally code ntpq shows in front of the server.
+    pub fn tally(self) -> char {
+        match self {
+            Verdict::SystemPeer => '*',
+            Verdict::Survivor => '+',
+            Verdict::Outlier => '-',
+            Verdict::Falseticker => 'x',
+            Verdict::Unfit | Verdict::Candidate => ' ',
+        }
+    }
+}
+
+/// The combined estimate of the survivors (RFC 5905, Section 11.2.3).
+pub struct Estimate {
+    /// Combined offset, in seconds
+    pub offset: f64,
+    /// System jitter: jitter of the system peer and spread of the survivors
+    pub jitter: f64,
+    /// Index of the system peer
+    pub peer: usize,
+    /// Number of survivors combined
+    pub survivors: usize,
+}
+
+/// Select the truechimers of the filtered servers, cluster them and
+/// combine their offsets (RFC 5905, Section 11.2).
+///
+/// The intersection algorithm (Marzullo's algorithm, as modified for NTP)
+/// takes the interval [offset - distance, offset + distance] of each
+/// server, in which its true offset must be, and looks for the smallest
+/// interval shared by a majority of them, allowing f falsetickers with
+/// 2f < m. A server whose interval misses it is a falseticker. The
+/// clustering then drops the survivors spreading the most, as long as
+/// more than three are left and that spread is larger than their jitter.
+///
+/// candidates: The clock filter result of each server, None if it has none
+///
+/// Returns the verdict of each server (None without filter result), and
+/// the estimate if a majority agrees.
+///
+pub fn select(candidates: &[Option<Filtered>]) -> (Vec<Option<Verdict>>, Option<Estimate>) {
+    let mut verdicts: Vec<Option<Verdict>> = candidates.iter()
+        .map(|candidate| candidate.as_ref().map(|c| if c.distance < MAX_DISTANCE { Verdict::Candidate } else { Verdict::Unfit }))
+        .collect();
+
+    let selectable: Vec<(usize, &Filtered)> = candidates.iter().enumerate()
+        .filter_map(|(i, candidate)| candidate.as_ref().map(|c| (i, c)))
+        .filter(|(i, _)| verdicts[*i] == Some(Verdict::Candidate))
+        .collect();
+
+    let Some((low, high)) = intersection(&selectable.iter().map(|(_, c)| (c.offset, c.distance)).collect::<Vec<_>>()) else {
+        return (verdicts, None);
+    };
+
+    // truechimers, best first: lower stratum, then lower distance
+    let mut survivors = Vec::new();
+    for &(i, c) in &selectable {
+        if c.offset + c.distance < low || c.offset - c.distance > high {
+            verdicts[i] = Some(Verdict::Falseticker);
+        }else{
+            survivors.push((i, c));
+        }
+    }
+    let merit = |c: &Filtered| f64::from(c.stratum) * MAX_DISTANCE + c.distance;
+    survivors.sort_by(|a, b| merit(a.1).total_cmp(&merit(b.1)));
+
+    // clustering (Section 11.2.2): drop the one with the most selection jitter
+    while survivors.len() > MIN_CLUSTER {
+        let selection_jitter = |c: &Filtered| (survivors.iter().map(|(_, o)| (o.offset - c.offset).powi(2)).sum::<f64>() / (survivors.len() - 1) as f64).sqrt();
+
+        let (worst, max_jitter) = survivors.iter().enumerate()
+            .map(|(n, (_, c))| (n, selection_jitter(c)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("No survivors");
+        let min_jitter = survivors.iter().map(|(_, c)| c.jitter).fold(f64::INFINITY, f64::min);
+
+        if max_jitter < min_jitter {
+            break;
+        }
+
+        let (i, _) = survivors.remove(worst);
+        verdicts[i] = Some(Verdict::Outlier);
+    }
+
+    for &(i, _) in &survivors {
+        verdicts[i] = Some(Verdict::Survivor);
+    }
+    let (peer, system) = survivors[0];
+    verdicts[peer] = Some(Verdict::SystemPeer);
+
+    // combine (Section 11.2.3): weighted by 1 / lambda
+    let weight: f64 = survivors.iter().map(|(_, c)| 1.0 / c.distance).sum();
+    let offset = survivors.iter().map(|(_, c)| c.offset / c.distance).sum::<f64>() / weight;
+    let selection_jitter = (survivors.iter().map(|(_, c)| (c.offset - system.offset).powi(2) / c.distance).sum::<f64>() / weight).sqrt();
+    let jitter = (system.jitter.powi(2) + selection_jitter.powi(2)).sqrt();
+
+    (verdicts, Some(Estimate { offset, jitter, peer, survivors: survivors.len() }))
+}
+
+/// The intersection algorithm (RFC 5905, Section 11.2.1).
+///
+/// Scans the interval endpoints from below for the lowest point inside
+/// m - f intervals, and from above for the highest, allowing more
+/// falsetickers f until they are found, while 2f < m.
+///
+/// intervals: Offset and root distance of each server
+///
+/// Returns the interval the majority agrees on, None if there is none.
+///
+fn intersection(intervals: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let m = intervals.len();
+
+    // endpoints: -1 lower end, 0 midpoint, +1 upper end
+    let mut endpoints: Vec<(f64, i32)> = intervals.iter()
+        .flat_map(|&(offset, distance)| [(offset - distance, -1), (offset, 0), (offset + distance, 1)])
+        .collect();
+    endpoints.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let mut f = 0;
+    while 2 * f < m {
+        let needed = (m - f) as i32;
+
+        // midpoints outside the interval are counted, there may be f at most
+        let mut found = 0;
+        let mut chime = 0;
+        let mut low = f64::INFINITY;
+        for &(value, kind) in &endpoints {
+            chime -= kind;
+            if chime >= needed {
+                low = value;
+                break;
+            }
+            if kind == 0 {
+                found += 1;
+            }
+        }
+
+        chime = 0;
+        let mut high = f64::NEG_INFINITY;
+        for &(value, kind) in endpoints.iter().rev() {
+            chime += kind;
+            if chime >= needed {
+                high = value;
+                break;
+            }
+            if kind == 0 {
+                found += 1;
+            }
+        }
+
+        if found <= f && low < high {
+            return Some((low, high));
+        }
+
+        f += 1;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ntp::{NtpPacket, Sample};
+
+    use super::*;
+
+    /// A clock filter result, the delay does not matter for the selection.
+    fn filtered(offset: f64, distance: f64, jitter: f64) -> Option<Filtered> {
+        Some(Filtered { offset, delay: 0.01, jitter, distance, stratum: 2 })
+    }
+
+    /// A server with the given number of samples, each 1 ms off with 10 ms delay.
+    fn peer(samples: usize, now: Instant) -> Peer {
+        let mut peer = Peer::new("[2001:db8::1]:123".parse().unwrap(), None);
+        peer.reply = Some(NtpPacket { stratum: 1, ..NtpPacket::client_request() });
+        peer.samples = (0..samples).map(|_| Sample { offset: 0.001, delay: 0.01, dispersion: 0.0001, received: now }).collect();
+        peer
+    }
+
+    #[test]
+    fn falseticker_found() {
+        let (verdicts, estimate) = select(&[
+            filtered(0.010, 0.04, 0.001),
+            filtered(0.012, 0.05, 0.001),
+            filtered(0.011, 0.06, 0.001),
+            filtered(1.000, 0.05, 0.001),
+            None,
+        ]);
+
+        assert_eq!(verdicts, [
+            Some(Verdict::SystemPeer),
+            Some(Verdict::Survivor),
+            Some(Verdict::Survivor),
+            Some(Verdict::Falseticker),
+            None,
+        ]);
+
+        // weighted by 1 / distance, the falseticker left out
+        let estimate = estimate.expect("No estimate");
+        let weight = 1.0 / 0.04 + 1.0 / 0.05 + 1.0 / 0.06;
+        let offset = (0.010 / 0.04 + 0.012 / 0.05 + 0.011 / 0.06) / weight;
+        assert!((estimate.offset - offset).abs() < 1e-12);
+        assert_eq!((estimate.peer, estimate.survivors), (0, 3));
+    }
+
+    #[test]
+    fn no_majority() {
+        let candidates = [
+            filtered(0.0, 0.01, 0.001),
+            filtered(1.0, 0.01, 0.001),
+            filtered(2.0, 0.01, 0.001),
+        ];
+
+        let (verdicts, estimate) = select(&candidates);
+        assert_eq!(verdicts, [Some(Verdict::Candidate); 3]);
+        assert!(estimate.is_none());
+
+        // two servers can not outvote each other either
+        let (verdicts, estimate) = select(&candidates[..2]);
+        assert_eq!(verdicts, [Some(Verdict::Candidate); 2]);
+        assert!(estimate.is_none());
+    }
+
+    #[test]
+    fn clustered_down_to_three() {
+        // all intervals overlap, the two spreading the most are dropped
+        let (verdicts, estimate) = select(&[
+            filtered(0.200, 0.5, 1e-6),
+            filtered(0.000, 0.5, 1e-6),
+            filtered(0.001, 0.5, 1e-6),
+            filtered(0.100, 0.5, 1e-6),
+            filtered(0.002, 0.5, 1e-6),
+        ]);
+
+        assert_eq!(verdicts, [
+            Some(Verdict::Outlier),
+            Some(Verdict::SystemPeer),
+            Some(Verdict::Survivor),
+            Some(Verdict::Outlier),
+            Some(Verdict::Survivor),
+        ]);
+        assert_eq!(estimate.expect("No estimate").survivors, MIN_CLUSTER);
+    }
+
+    #[test]
+    fn clustering_stops_within_jitter() {
+        // the spread is below the jitter of each, nothing to gain
+        let (verdicts, estimate) = select(&[
+            filtered(0.000, 0.5, 0.1),
+            filtered(0.001, 0.5, 0.1),
+            filtered(0.002, 0.5, 0.1),
+            filtered(0.003, 0.5, 0.1),
+        ]);
+
+        assert!(!verdicts.contains(&Some(Verdict::Outlier)));
+        assert_eq!(estimate.expect("No estimate").survivors, 4);
+    }
+
+    #[test]
+    fn unfit_above_max_distance() {
+        let (verdicts, estimate) = select(&[
+            filtered(0.010, 0.05, 0.001),
+            filtered(0.011, 0.05, 0.001),
+            filtered(0.012, 0.05, 0.001),
+            filtered(0.011, 1.6, 0.001),
+        ]);
+
+        assert_eq!(verdicts[3], Some(Verdict::Unfit));
+        assert_eq!(estimate.expect("No estimate").survivors, 3);
+    }
+
+    #[test]
+    fn empty_stages_count_as_max_dispersion() {
+        let now = Instant::now();
+        let jitter = 2f64.powi(PRECISION.into());
+
+        // one sample: 7 stages of 16 s, weighted 1/4 to 1/256
+        let filtered = clock_filter(&peer(1, now), now).expect("Not filtered");
+        let dispersion = 0.0001 / 2.0 + MAX_DISPERSION * (0.5 - 1.0 / 256.0);
+        assert!((filtered.distance - (0.01 / 2.0 + dispersion + jitter)).abs() < 1e-9);
+        assert!(filtered.distance > MAX_DISTANCE);
+
+        // all eight stages filled
+        let filtered = clock_filter(&peer(8, now), now).expect("Not filtered");
+        let dispersion = 0.0001 * (1.0 - 1.0 / 256.0);
+        assert!((filtered.distance - (0.01 / 2.0 + dispersion + jitter)).abs() < 1e-9);
+        assert_eq!(filtered.offset, 0.001);
+
+        // no reply, nothing to filter
+        let mut silent = peer(8, now);
+        silent.reply = None;
+        assert!(clock_filter(&silent, now).is_none());
+    }
+
+    #[test]
+    fn min_samples_selectable() {
+        let now = Instant::now();
+
+        let filtered = clock_filter(&peer(MIN_SAMPLES, now), now).expect("Not filtered");
+        assert!(filtered.distance < MAX_DISTANCE);
+
+        let filtered = clock_filter(&peer(MIN_SAMPLES - 1, now), now).expect("Not filtered");
+        assert!(filtered.distance > MAX_DISTANCE);
+
+        let (verdicts, estimate) = select(&[Some(filtered)]);
+        assert_eq!(verdicts, [Some(Verdict::Unfit)]);
+        assert!(estimate.is_none());
+    }
+}
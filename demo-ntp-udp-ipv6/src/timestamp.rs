@@ -0,0 +1,329 @@
+// NTP time formats (short format and timestamp format)
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970)
+const UNIX_EPOCH_OFFSET: u64 = 2208988800;
+
+/// Nanoseconds per second
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Seconds of a NTP era, about 136 years
+const ERA_SECONDS: i128 = 1 << 32;
+
+/// Convert nanoseconds to a binary fraction of `bits` bits, rounded.
+///
+/// A fraction step is smaller than a nanosecond for 32 bits, so converting
+/// back gives the same nanoseconds.
+///
+fn nanos_to_fraction(nanos: u32, bits: u32) -> u64 {
+    ((u64::from(nanos) << bits) + NANOS_PER_SEC / 2) / NANOS_PER_SEC
+}
+
+/// Convert a binary fraction of `bits` bits to nanoseconds, rounded.
+fn fraction_to_nanos(fraction: u64, bits: u32) -> u64 {
+    (fraction * NANOS_PER_SEC + (1 << (bits - 1))) >> bits
+}
+
+/// Whole seconds since the NTP epoch (1900) and nanoseconds of a time,
+/// without wrapping around at the end of an era.
+fn seconds_since_1900(time: SystemTime) -> (i128, u32) {
+    let (seconds, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (i128::from(since.as_secs()), since.subsec_nanos()),
+        // before 1970, the nanoseconds count forward from a whole second
+        Err(e) => {
+            let before = e.duration();
+            let carry = u64::from(before.subsec_nanos() > 0);
+            (-i128::from(before.as_secs() + carry), (NANOS_PER_SEC as u32 - before.subsec_nanos()) % NANOS_PER_SEC as u32)
+        },
+    };
+
+    (seconds + i128::from(UNIX_EPOCH_OFFSET), nanos)
+}
+
+/// NTP short format: 16 bits seconds, 16 bits fraction (RFC 5905, Section 6).
+///
+/// Used for the root delay and root dispersion, a step is about 15 µs.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NtpShort(pub u32);
+
+impl From<Duration> for NtpShort {
+    /// Durations of 65536 seconds or more saturate.
+    fn from(duration: Duration) -> NtpShort {
+        if duration.as_secs() > 0xffff {
+            return NtpShort(u32::MAX);
+        }
+
+        // the fraction may round up to the next second
+        let value = (duration.as_secs() << 16) + nanos_to_fraction(duration.subsec_nanos(), 16);
+        NtpShort(value.min(u64::from(u32::MAX)) as u32)
+    }
+}
+
+impl From<NtpShort> for Duration {
+    fn from(short: NtpShort) -> Duration {
+        let nanos = fraction_to_nanos(u64::from(short.0 & 0xffff), 16);
+        Duration::from_secs(u64::from(short.0 >> 16)) + Duration::from_nanos(nanos)
+    }
+}
+
+/// NTP timestamp format: 32 bits seconds since 1900, 32 bits fraction
+/// (RFC 5905, Section 6).
+///
+/// A step is about 233 ps, so times of nanosecond resolution convert to
+/// timestamps and back without loss.
+///
+/// The seconds wrap around every 2^32 seconds, the first time on 7 February
+/// 2036, when era 0 ends and era 1 begins. A timestamp does not carry its
+/// era, so converting it to a time takes the one closest to the local clock.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NtpTimestamp(pub u64);
+
+impl NtpTimestamp {
+    /// The local clock as timestamp.
+    pub fn now() -> NtpTimestamp {
+        NtpTimestamp::from(SystemTime::now())
+    }
+
+    /// Seconds since the start of the era.
+    pub fn seconds(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    /// Fraction of the second, in units of 2^-32 seconds.
+    pub fn fraction(self) -> u32 {
+        self.0 as u32
+    }
+
+    /// The difference to an earlier timestamp, self - earlier, in seconds.
+    ///
+    /// Computed in two's complement, like RFC 5905, Section 6 says, so it is
+    /// right across an era boundary as long as they are less than 68 years
+    /// apart.
+    ///
+    pub fn difference(self, earlier: NtpTimestamp) -> f64 {
+        self.0.wrapping_sub(earlier.0) as i64 as f64 / (1u64 << 32) as f64
+    }
+
+    /// The timestamp some seconds later (or earlier, if negative).
+    pub fn add_seconds(self, seconds: f64) -> NtpTimestamp {
+        NtpTimestamp(self.0.wrapping_add((seconds * (1u64 << 32) as f64) as i64 as u64))
+    }
+
+    /// A zero timestamp means unknown, e.g. the server's clock was never set.
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Nanoseconds since the Unix epoch, in the era closest to the pivot.
+    ///
+    /// The era is resolved like RFC 5905, Section 6 says: the difference of
+    /// the seconds to the pivot is taken in two's complement, so the time
+    /// is right if it is less than 68 years from the pivot, in either era.
+    ///
+    /// pivot: A time close to the timestamp, normally the local clock
+    ///
+    pub fn unix_nanos(self, pivot: SystemTime) -> i128 {
+        let (pivot, _) = seconds_since_1900(pivot);
+
+        // the seconds of the pivot in its era, and how far the timestamp is from them
+        let pivot_seconds = pivot.rem_euclid(ERA_SECONDS) as u32;
+        let difference = i128::from(self.seconds().wrapping_sub(pivot_seconds) as i32);
+
+        let seconds = pivot + difference - i128::from(UNIX_EPOCH_OFFSET);
+        seconds * i128::from(NANOS_PER_SEC) + fraction_to_nanos(u64::from(self.fraction()), 32) as i128
+    }
+}
+
+impl From<SystemTime> for NtpTimestamp {
+    fn from(time: SystemTime) -> NtpTimestamp {
+        let (seconds, nanos) = seconds_since_1900(time);
+
+        // the seconds wrap around at the end of the era
+        let seconds = seconds.rem_euclid(ERA_SECONDS) as u64;
+        let fraction = nanos_to_fraction(nanos, 32);
+
+        NtpTimestamp((seconds << 32).wrapping_add(fraction))
+    }
+}
+
+impl From<NtpTimestamp> for SystemTime {
+    /// The era is the one closest to the local clock.
+    fn from(timestamp: NtpTimestamp) -> SystemTime {
+        let nanos = timestamp.unix_nanos(SystemTime::now());
+        let since = Duration::new((nanos.unsigned_abs() / u128::from(NANOS_PER_SEC)) as u64, (nanos.unsigned_abs() % u128::from(NANOS_PER_SEC)) as u32);
+
+        if nanos >= 0 {
+            UNIX_EPOCH + since
+        }else{
+            UNIX_EPOCH - since
+        }
+    }
+}
+
+impl From<DateTime<Utc>> for NtpTimestamp {
+    fn from(time: DateTime<Utc>) -> NtpTimestamp {
+        NtpTimestamp::from(SystemTime::from(time))
+    }
+}
+
+impl From<NtpTimestamp> for DateTime<Utc> {
+    /// The era is the one closest to the local clock.
+    fn from(timestamp: NtpTimestamp) -> DateTime<Utc> {
+        let nanos = timestamp.unix_nanos(SystemTime::now());
+        DateTime::from_timestamp(nanos.div_euclid(i128::from(NANOS_PER_SEC)) as i64, nanos.rem_euclid(i128::from(NANOS_PER_SEC)) as u32).expect("Invalid timestamp")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A time given as RFC 3339.
+    fn time(rfc3339: &str) -> SystemTime {
+        DateTime::parse_from_rfc3339(rfc3339).expect("Invalid test time").into()
+    }
+
+    /// Nanoseconds since the Unix epoch of a time given as RFC 3339.
+    fn unix_nanos(rfc3339: &str) -> i128 {
+        let time: DateTime<Utc> = time(rfc3339).into();
+        i128::from(time.timestamp()) * i128::from(NANOS_PER_SEC) + i128::from(time.timestamp_subsec_nanos())
+    }
+
+    #[test]
+    fn unix_epoch() {
+        assert_eq!(NtpTimestamp::from(UNIX_EPOCH), NtpTimestamp(UNIX_EPOCH_OFFSET << 32));
+        assert_eq!(SystemTime::from(NtpTimestamp(UNIX_EPOCH_OFFSET << 32)), UNIX_EPOCH);
+    }
+
+    #[test]
+    fn fraction_of_a_second() {
+        let timestamp = NtpTimestamp::from(time("2026-10-18T00:00:00.5Z"));
+        assert_eq!(timestamp.fraction(), 0x8000_0000);
+
+        let timestamp = NtpTimestamp::from(time("2026-10-18T00:00:00.25Z"));
+        assert_eq!(timestamp.fraction(), 0x4000_0000);
+    }
+
+    #[test]
+    fn before_1970() {
+        // the fraction counts forward from the whole second before
+        let timestamp = NtpTimestamp::from(time("1969-12-31T23:59:59.75Z"));
+
+        assert_eq!(timestamp.seconds(), UNIX_EPOCH_OFFSET as u32 - 1);
+        assert_eq!(timestamp.fraction(), 0xc000_0000);
+        assert_eq!(SystemTime::from(timestamp), time("1969-12-31T23:59:59.75Z"));
+    }
+
+    #[test]
+    fn nanoseconds_round_trip() {
+        for rfc3339 in ["2026-10-18T12:34:56Z", "2026-10-18T12:34:56.000000001Z", "2026-10-18T12:34:56.123456789Z", "2026-10-18T12:34:56.499999999Z", "2026-10-18T12:34:56.999999999Z", "1969-07-20T20:17:40.000000001Z"] {
+            let timestamp = NtpTimestamp::from(time(rfc3339));
+            assert_eq!(SystemTime::from(timestamp), time(rfc3339), "{}", rfc3339);
+
+            let date: DateTime<Utc> = time(rfc3339).into();
+            assert_eq!(DateTime::<Utc>::from(NtpTimestamp::from(date)), date, "{}", rfc3339);
+        }
+    }
+
+    #[test]
+    fn short_from_duration_rounds() {
+        assert_eq!(NtpShort::from(Duration::from_millis(1500)), NtpShort(0x0001_8000));
+        assert_eq!(NtpShort::from(Duration::ZERO), NtpShort(0));
+
+        // half a step is about 7629.4 ns
+        assert_eq!(NtpShort::from(Duration::from_nanos(7629)), NtpShort(0));
+        assert_eq!(NtpShort::from(Duration::from_nanos(7630)), NtpShort(1));
+
+        // the fraction rounds up to the next second
+        assert_eq!(NtpShort::from(Duration::new(2, 999_999_999)), NtpShort(0x0003_0000));
+    }
+
+    #[test]
+    fn short_from_duration_saturates() {
+        assert_eq!(NtpShort::from(Duration::from_secs(0xffff)), NtpShort(0xffff_0000));
+        assert_eq!(NtpShort::from(Duration::new(0xffff, 999_999_999)), NtpShort(u32::MAX));
+        assert_eq!(NtpShort::from(Duration::from_secs(0x1_0000)), NtpShort(u32::MAX));
+    }
+
+    #[test]
+    fn short_to_duration_rounds() {
+        assert_eq!(Duration::from(NtpShort(0x0001_8000)), Duration::from_millis(1500));
+        // 2^-16 s is 15258.79 ns
+        assert_eq!(Duration::from(NtpShort(1)), Duration::from_nanos(15259));
+        assert_eq!(Duration::from(NtpShort(0xffff)), Duration::from_nanos(999_984_741));
+    }
+
+    #[test]
+    fn short_round_trip_within_half_a_step() {
+        for nanos in [1, 7_629, 15_259, 123_456_789, 500_000_000, 999_992_370] {
+            let duration = Duration::new(3, nanos);
+            let back = Duration::from(NtpShort::from(duration));
+            let error = back.abs_diff(duration);
+
+            assert!(error <= Duration::from_nanos(7_630), "{:?} came back as {:?}", duration, back);
+        }
+    }
+
+    #[test]
+    fn era_0_ends_in_2036() {
+        let last = NtpTimestamp::from(time("2036-02-07T06:28:15Z"));
+        let first = NtpTimestamp::from(time("2036-02-07T06:28:16Z"));
+
+        assert_eq!(last.seconds(), u32::MAX);
+        assert_eq!(first.seconds(), 0);
+        assert_eq!(NtpTimestamp::from(time("1900-01-01T00:00:00Z")).seconds(), 0);
+    }
+
+    #[test]
+    fn era_resolved_around_the_boundary() {
+        let last = NtpTimestamp(u64::from(u32::MAX) << 32);
+        let first = NtpTimestamp(0x0000_0001_8000_0000);
+
+        // pivot before, at and after the boundary
+        for pivot in ["2036-02-07T06:28:15Z", "2036-02-07T06:28:16Z", "2036-02-08T00:00:00Z", "2026-10-18T00:00:00Z"] {
+            assert_eq!(last.unix_nanos(time(pivot)), unix_nanos("2036-02-07T06:28:15Z"), "pivot {}", pivot);
+            assert_eq!(first.unix_nanos(time(pivot)), unix_nanos("2036-02-07T06:28:17.5Z"), "pivot {}", pivot);
+        }
+    }
+
+    #[test]
+    fn era_resolved_within_68_years() {
+        // 1968 in era 0 and 2104 in era 1 have nearly the same seconds as the boundary
+        let timestamp = NtpTimestamp::from(time("2104-02-26T09:42:23Z"));
+
+        assert_eq!(timestamp.unix_nanos(time("2050-01-01T00:00:00Z")), unix_nanos("2104-02-26T09:42:23Z"));
+        assert_eq!(timestamp.unix_nanos(time("1990-01-01T00:00:00Z")), unix_nanos("1968-01-20T03:14:07Z"));
+    }
+
+    #[test]
+    fn zero_timestamp() {
+        let zero = NtpTimestamp(0);
+
+        assert!(zero.is_zero());
+        // the start of era 1 for today's clock, not 1900
+        assert_eq!(zero.unix_nanos(time("2026-10-18T00:00:00Z")), unix_nanos("2036-02-07T06:28:16Z"));
+        assert_eq!(zero.unix_nanos(time("1950-01-01T00:00:00Z")), unix_nanos("1900-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn difference_across_the_boundary() {
+        let before = NtpTimestamp::from(time("2036-02-07T06:28:15.75Z"));
+        let after = NtpTimestamp::from(time("2036-02-07T06:28:16.25Z"));
+
+        assert_eq!(after.difference(before), 0.5);
+        assert_eq!(before.difference(after), -0.5);
+        assert_eq!(before.add_seconds(0.5), after);
+    }
+
+    #[test]
+    fn nanoseconds_survive_the_boundary() {
+        for rfc3339 in ["2036-02-07T06:28:15.999999999Z", "2036-02-07T06:28:16.000000001Z", "2036-02-07T06:28:16.123456789Z"] {
+            let timestamp = NtpTimestamp::from(time(rfc3339));
+            assert_eq!(timestamp.unix_nanos(time("2036-02-07T00:00:00Z")), unix_nanos(rfc3339));
+        }
+    }
+}
@@ -0,0 +1,274 @@
+// Traceroute (probes with increasing hop limit on top of the raw stack)
+use std::{collections::HashMap, io::ErrorKind, net::{Ipv6Addr, SocketAddrV6}, time::{Duration, Instant}};
+
+use pnet::datalink::{DataLinkReceiver, DataLinkSender};
+
+use crate::{ip::{self, Ipv6Packet, SendOptions}, link, udp};
+
+/// First destination port of UDP probes, like classic traceroute.
+const BASE_PORT: u16 = 33434;
+
+/// Number of probes sent per hop.
+const QUERIES: u16 = 3;
+
+/// Number of data bytes in each probe.
+const PAYLOAD_SIZE: usize = 32;
+
+/// What kind of probes to send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeMode {
+    /// UDP to an increasing destination port per probe (classic traceroute)
+    Udp,
+    /// ICMPv6 Echo Requests with increasing sequence numbers (traceroute -I)
+    Icmp,
+    /// UDP with constant ports, so load balancers hashing the flow send
+    /// every probe the same way (Paris traceroute). The probe is identified
+    /// by the UDP checksum instead.
+    Paris,
+}
+
+/// Options of a traceroute session.
+pub struct TracerouteOptions {
+    /// The host to trace the route to
+    pub target: Ipv6Addr,
+    /// Kind of probes
+    pub mode: ProbeMode,
+    /// Maximum hop limit to probe
+    pub max_hops: u8,
+    /// Time to wait for the responses of each hop
+    pub wait: Duration,
+    /// Source port of UDP probes
+    pub port: u16,
+}
+
+/// What the response to a probe tells.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    /// A router on the way (time exceeded)
+    Hop,
+    /// The target answered
+    Reached,
+    /// Destination unreachable with the given code
+    Unreachable(u8),
+}
+
+/// A response matched to one of our probes.
+struct Response {
+    /// Number of the probe
+    probe: u16,
+    /// Who answered
+    from: Ipv6Addr,
+    /// What the answer means
+    outcome: Outcome,
+}
+
+/// Add two 16 bit words in one's complement arithmetic.
+fn ones_complement_add(a: u16, b: u16) -> u16 {
+    let sum = u32::from(a) + u32::from(b);
+    ((sum & 0xFFFF) + (sum >> 16)) as u16
+}
+
+/// Create the IPv6 packet of a probe.
+fn create_probe(data: &mut Vec<u8>, options: &TracerouteOptions, this_ip: Ipv6Addr, identifier: u16, probe: u16, send_options: &SendOptions) {
+    let payload = [0u8; PAYLOAD_SIZE];
+    let src = SocketAddrV6::new(this_ip, options.port, 0, 0);
+
+    match options.mode {
+        ProbeMode::Udp => {
+            let dest = SocketAddrV6::new(options.target, BASE_PORT + probe - 1, 0, 0);
+
+            let mut datagram = Vec::new();
+            udp::create_datagram(&mut datagram, dest, src, &payload);
+            ip::create_packet(data, &this_ip, &options.target, ip::NEXT_HEADER_UDP, &datagram, send_options);
+        },
+        ProbeMode::Icmp => {
+            ip::icmpv6_create_echo_request(data, &this_ip, &options.target, identifier, probe, &payload, send_options);
+        },
+        ProbeMode::Paris => {
+            let dest = SocketAddrV6::new(options.target, BASE_PORT, 0, 0);
+
+            let mut datagram = Vec::new();
+            udp::create_datagram(&mut datagram, dest, src, &payload);
+
+            // Choose the first two payload bytes so the checksum becomes the probe
+            // number: checksum = !(sum + word), so word = !probe + !sum = !probe + checksum
+            let checksum = u16::from_be_bytes([datagram[6], datagram[7]]);
+            let word = ones_complement_add(!probe, checksum).to_be_bytes();
+            datagram[8] = word[0];
+            datagram[9] = word[1];
+
+            let checksum = probe.to_be_bytes();
+            datagram[6] = checksum[0];
+            datagram[7] = checksum[1];
+
+            ip::create_packet(data, &this_ip, &options.target, ip::NEXT_HEADER_UDP, &datagram, send_options);
+        },
+    }
+}
+
+/// Match a received packet to one of our probes.
+///
+/// Routers answer with ICMPv6 errors quoting the start of our probe, the
+/// target with Port Unreachable (UDP) or an Echo Reply (ICMPv6).
+///
+fn match_response(options: &TracerouteOptions, this_ip: Ipv6Addr, identifier: u16, packet: &Ipv6Packet) -> Option<Response> {
+    if packet.dest != this_ip || packet.next_header != ip::NEXT_HEADER_ICMPV6 {
+        return None;
+    }
+
+    // the target answers ICMPv6 probes directly
+    if options.mode == ProbeMode::Icmp && packet.src == options.target && let Some(reply) = ip::icmpv6_unpack_echo_reply(packet) {
+        if reply.identifier != identifier {
+            return None;
+        }
+
+        return Some(Response { probe: reply.sequence, from: packet.src, outcome: Outcome::Reached });
+    }
+
+    let error = ip::icmpv6_unpack_error(packet)?;
+    let quoted = &error.quoted;
+
+    // check this is about one of our probes
+    if quoted.src != this_ip || quoted.dest != options.target {
+        return None;
+    }
+
+    let transport = quoted.transport;
+    let probe = match options.mode {
+        ProbeMode::Udp | ProbeMode::Paris => {
+            if quoted.next_header != ip::NEXT_HEADER_UDP || transport[0..2] != options.port.to_be_bytes() {
+                return None;
+            }
+
+            let dest_port = u16::from_be_bytes([transport[2], transport[3]]);
+            if options.mode == ProbeMode::Udp {
+                dest_port.checked_sub(BASE_PORT)? + 1
+            }else{
+                // the checksum tells the probe
+                u16::from_be_bytes([transport[6], transport[7]])
+            }
+        },
+        ProbeMode::Icmp => {
+            if quoted.next_header != ip::NEXT_HEADER_ICMPV6 || transport[0] != ip::ICMPV6_ECHO_REQUEST || transport[4..6] != identifier.to_be_bytes() {
+                return None;
+            }
+
+            u16::from_be_bytes([transport[6], transport[7]])
+        },
+    };
+
+    let outcome = match (error.icmp_type, error.code) {
+        (ip::ICMPV6_TIME_EXCEEDED, _) => Outcome::Hop,
+        // port unreachable from the target: the UDP probe arrived
+        (ip::ICMPV6_DESTINATION_UNREACHABLE, 4) if packet.src == options.target => Outcome::Reached,
+        (_, code) => Outcome::Unreachable(code),
+    };
+
+    Some(Response { probe, from: packet.src, outcome })
+}
+
+/// Annotation for a destination unreachable code, like traceroute6 prints it.
+fn unreachable_annotation(code: u8) -> String {
+    match code {
+        0 => String::from("!N"),
+        1 | 5 | 6 => String::from("!X"),
+        2 => String::from("!S"),
+        3 => String::from("!H"),
+        4 => String::from("!P"),
+        code => format!("!<{}>", code),
+    }
+}
+
+/// Run a traceroute session.
+///
+/// For every hop limit from 1 up to `max_hops`, sends three probes and prints
+/// who answered and the round trip times. Stops once the target is reached or
+/// reported unreachable.
+///
+pub fn run(tx: &mut dyn DataLinkSender, rx: &mut dyn DataLinkReceiver, this_mac: [u8; 6], this_ip: Ipv6Addr, gateway_mac: [u8; 6], send_options: &SendOptions, options: &TracerouteOptions) {
+    // identify our ICMPv6 probes by the process ID
+    let identifier = std::process::id() as u16;
+
+    let mut send_options = send_options.clone();
+
+    println!("traceroute to {}, {} hops max, {} byte packets ({:?} probes)",
+        options.target, options.max_hops, 40 + 8 + PAYLOAD_SIZE, options.mode);
+
+    for hop_limit in 1..=options.max_hops {
+        send_options.hop_limit = hop_limit;
+
+        // send all probes of this hop
+        let mut sent: HashMap<u16, Instant> = HashMap::new();
+        let first = u16::from(hop_limit - 1) * QUERIES + 1;
+
+        for probe in first..(first + QUERIES) {
+            let mut frame = Vec::new();
+            link::push_ethernet_header(&mut frame, &this_mac, &gateway_mac);
+            create_probe(&mut frame, options, this_ip, identifier, probe, &send_options);
+
+            if let Some(Err(e)) = tx.send_to(&frame, None) {
+                println!("Could not send probe: {}", e);
+            }
+
+            sent.insert(probe, Instant::now());
+        }
+
+        // collect the responses
+        let mut responses: HashMap<u16, (Response, Duration)> = HashMap::new();
+        let start = Instant::now();
+
+        while responses.len() < usize::from(QUERIES) && start.elapsed() < options.wait {
+            let frame = match rx.next() {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => panic!("Error occured: {}", e),
+            };
+
+            let Some(packet) = link::unwrap_ip_packet(frame, &this_mac).and_then(ip::parse) else {
+                continue;
+            };
+
+            let Some(response) = match_response(options, this_ip, identifier, &packet) else {
+                continue;
+            };
+
+            if let Some(t) = sent.get(&response.probe) {
+                responses.entry(response.probe).or_insert((response, t.elapsed()));
+            }
+        }
+
+        // print the hop
+        let mut line = format!("{:2} ", hop_limit);
+        let mut last_from = None;
+        let mut done = false;
+
+        for probe in first..(first + QUERIES) {
+            match responses.get(&probe) {
+                Some((response, rtt)) => {
+                    if last_from != Some(response.from) {
+                        line += &format!(" {}", response.from);
+                        last_from = Some(response.from);
+                    }
+
+                    line += &format!("  {:.3} ms", rtt.as_secs_f64() * 1000.0);
+
+                    match response.outcome {
+                        Outcome::Hop => {},
+                        Outcome::Reached => done = true,
+                        Outcome::Unreachable(code) => {
+                            line += &format!(" {}", unreachable_annotation(code));
+                            done = true;
+                        },
+                    }
+                },
+                None => line += " *",
+            }
+        }
+
+        println!("{}", line);
+
+        if done {
+            return;
+        }
+    }
+}
@@ -1,10 +1,10 @@
-use std::net::SocketAddrV6;
+use std::{error::Error, fmt, io::{self, ErrorKind}, net::{Ipv6Addr, SocketAddrV6, UdpSocket}, time::{Duration, Instant}};
 
 // UDP (transport layer)
-use crate::ntp::*;
+use crate::ip::{compute_checksum, icmpv6_to_io_error, icmpv6_unpack_udp_error, Ipv6Packet, Stack, NEXT_HEADER_ICMPV6, NEXT_HEADER_UDP};
 
-/// Create the UDP packet
-pub fn create_packet(data: &mut Vec<u8>, dest: SocketAddrV6, src: SocketAddrV6) {
+/// Create a UDP datagram with arbitrary payload
+pub fn create_datagram(data: &mut Vec<u8>, dest: SocketAddrV6, src: SocketAddrV6, payload: &[u8]) {
     // Used for checksum
     let curr_idx = data.len();
     // source port
@@ -16,8 +16,8 @@ pub fn create_packet(data: &mut Vec<u8>, dest: SocketAddrV6, src: SocketAddrV6)
     // length and checksum currently ignored
     data.extend_from_slice(&[0x00u8; 4]);
     
-    // setup ntp payload
-    add_request_payload(data);
+    // the payload
+    data.extend_from_slice(payload);
 
     // set length
     let length = u16::try_from(data[curr_idx..].len()).expect("Payload to big");
@@ -51,13 +51,12 @@ pub fn create_packet(data: &mut Vec<u8>, dest: SocketAddrV6, src: SocketAddrV6)
     data[curr_idx + 7] = checksum[1];
 }
 
-/// Unwraps the UDP packet and return the NTP timestamp
-pub fn unpack(data: &[u8], src: SocketAddrV6, dest: SocketAddrV6) -> Option<String> {
-    // First: Verify checksum
+/// Verify the checksum of a received datagram.
+pub fn verify_checksum(src: &Ipv6Addr, dest: &Ipv6Addr, data: &[u8]) -> bool {
     let mut check = Vec::new();
 
-    check.extend_from_slice(&src.ip().octets());// 16
-    check.extend_from_slice(&dest.ip().octets());// 32
+    check.extend_from_slice(&src.octets());// 16
+    check.extend_from_slice(&dest.octets());// 32
     check.extend_from_slice(&(data.len() as u32).to_be_bytes());// 36
     check.extend_from_slice(&[0; 3]);// 39
     check.push(17);// 40
@@ -80,43 +79,173 @@ pub fn unpack(data: &[u8], src: SocketAddrV6, dest: SocketAddrV6) -> Option<Stri
     // calculate checksum
     let checksum = compute_checksum(check.as_slice()).to_be_bytes();
 
+    checksum == data[6..=7]
+}
+
+/// A received UDP datagram.
+pub struct Datagram<'a> {
+    /// Source port
+    pub src_port: u16,
+    /// Destination port
+    pub dest_port: u16,
+    /// The payload
+    pub payload: &'a [u8],
+}
+
+/// Parse the UDP datagram carried in an IPv6 packet and verify its checksum.
+///
+/// Returns None if the packet carries no valid UDP datagram.
+///
+pub fn parse<'a>(packet: &Ipv6Packet<'a>) -> Option<Datagram<'a>> {
+    let data = packet.payload;
+    if packet.next_header != NEXT_HEADER_UDP || data.len() < 8 {
+        return None;
+    }
+
+    // check length
+    let length = usize::from(u16::from_be_bytes([data[4], data[5]]));
+    if length < 8 || length > data.len() {
+        return None;
+    }
+    let data = &data[..length];
+
     // Check checksum
-    if &checksum != &data[6..=7] {
+    if !verify_checksum(&packet.src, &packet.dest, data) {
         println!("Received corrupted IPv6/UDP packet: Dropping it.");
         return None;
     }
 
-    // check port
-    let src = src.port().to_be_bytes();
-    let dest = dest.port().to_be_bytes();
+    Some(Datagram {
+        src_port: u16::from_be_bytes([data[0], data[1]]),
+        dest_port: u16::from_be_bytes([data[2], data[3]]),
+        payload: &data[8..],
+    })
+}
+
+/// Reserve a port by binding a kernel socket on it.
+///
+/// As long as the returned socket lives, the kernel will not hand out the
+/// port to anyone else, and does not answer datagrams to it with ICMP port
+/// unreachable. The socket is never read, we receive on the raw stack.
+///
+pub fn reserve_port(ip: Ipv6Addr, port: u16) -> io::Result<UdpSocket> {
+    UdpSocket::bind(SocketAddrV6::new(ip, port, 0, 0))
+}
+
+/// A ICMPv6 error quoting one of our datagrams, as `recv_from` returns it.
+#[derive(Debug)]
+struct IcmpReport {
+    /// Destination address and port of the datagram
+    dest: SocketAddrV6,
+    /// What went wrong, and who reported it
+    message: String,
+}
+
+impl fmt::Display for IcmpReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for IcmpReport {}
 
-    if src == data[0..2] && dest == data[2..4] {
-        // ignore checksum, length, ..., just delegate payload to NTP
-        Some(get_timestamp(&data[8..]))
-    }else{
-        None
-    }    
+/// The destination of the datagram an error of `RawUdpSocket::recv_from`
+/// is about.
+///
+/// An unconnected socket gets the ICMPv6 errors for all its peers, this
+/// tells them apart. None if the error is no ICMPv6 error.
+///
+pub fn error_destination(error: &io::Error) -> Option<SocketAddrV6> {
+    error.get_ref()?.downcast_ref::<IcmpReport>().map(|report| report.dest)
 }
 
-/// Checksum compute function.
-pub fn compute_checksum(data: &[u8]) -> u16 {
-    // Ensure the data length is even (for 16-bit processing)
-    if data.len() % 2 != 0 {
-        panic!("Data length must be even");
+/// A UDP socket on top of the raw stack.
+///
+/// Works like `std::net::UdpSocket`, but the kernel does not know about
+/// it, so the port is only *stolen* (see the security note of `main`).
+///
+pub struct RawUdpSocket {
+    /// The stack below
+    stack: Stack,
+    /// The port we are bound on
+    port: u16,
+    /// How long `recv_from` waits, None to wait forever
+    read_timeout: Option<Duration>,
+}
+
+impl RawUdpSocket {
+    /// Bind a socket on the given port of the stack.
+    pub fn bind(stack: Stack, port: u16) -> RawUdpSocket {
+        RawUdpSocket {
+            stack,
+            port,
+            read_timeout: None,
+        }
+    }
+
+    /// The address and port the socket is bound on.
+    pub fn local_addr(&self) -> SocketAddrV6 {
+        SocketAddrV6::new(self.stack.this_ip, self.port, 0, 0)
     }
 
-    // Sum all 16-bit words
-    let mut sum: u32 = 0;
-    for chunk in data.chunks_exact(2) {
-        let word = u16::from_be_bytes([chunk[0], chunk[1]]);
-        sum += u32::from(word);
+    /// Set how long `recv_from` waits for a datagram, None to wait forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
     }
 
-    // Add the carry bits
-    while sum > 0xFFFF {
-        sum = (sum & 0xFFFF) + (sum >> 16);
+    /// Send a datagram to the given address.
+    ///
+    /// Returns the number of payload bytes sent.
+    ///
+    pub fn send_to(&mut self, payload: &[u8], addr: SocketAddrV6) -> io::Result<usize> {
+        let mut datagram = Vec::new();
+        create_datagram(&mut datagram, addr, self.local_addr(), payload);
+        self.stack.send(addr.ip(), NEXT_HEADER_UDP, &datagram)?;
+
+        Ok(payload.len())
     }
 
-    // One's complement of the sum
-    !(sum as u16)
-}
\ No newline at end of file
+    /// Receive the next datagram sent to our port.
+    ///
+    /// Returns the payload and who sent it. Fails with an error of kind TimedOut
+    /// if the read timeout elapsed, or with the reported error if a ICMPv6 error
+    /// quoting one of our datagrams arrives (see `error_destination`).
+    ///
+    pub fn recv_from(&mut self) -> io::Result<(Vec<u8>, SocketAddrV6)> {
+        let local = self.local_addr();
+        let start = Instant::now();
+
+        loop {
+            if let Some(timeout) = self.read_timeout && start.elapsed() >= timeout {
+                return Err(io::Error::new(ErrorKind::TimedOut, "UDP receive timed out"));
+            }
+
+            let packet = match self.stack.recv() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                // interrupted by a signal, its handler decides what happens
+                Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            match packet.next_header {
+                NEXT_HEADER_UDP => {
+                    if let Some(datagram) = parse(&packet) && datagram.dest_port == self.port {
+                        return Ok((datagram.payload.to_vec(), SocketAddrV6::new(packet.src, datagram.src_port, 0, 0)));
+                    }
+                },
+                // routers or the peer may answer with a ICMPv6 error instead
+                NEXT_HEADER_ICMPV6 => {
+                    if let Some(error) = icmpv6_unpack_udp_error(&packet, local) {
+                        let transport = error.quoted.transport;
+                        let dest = SocketAddrV6::new(error.quoted.dest, u16::from_be_bytes([transport[2], transport[3]]), 0, 0);
+
+                        let e = icmpv6_to_io_error(&error, &packet.src);
+                        return Err(io::Error::new(e.kind(), IcmpReport { dest, message: e.to_string() }));
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+}
@@ -0,0 +1,447 @@
+// HTTP/1.1 (application layer), over any connection
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+
+/// Longest status line, header line or chunk size line we accept.
+const MAX_LINE: usize = 8192;
+
+/// Most header lines (and trailer lines) we accept.
+const MAX_HEADERS: usize = 100;
+
+/// A URL of the http scheme, split into its parts (RFC 9110, Section 4.2.1).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Url {
+    /// Host name or address, without brackets
+    pub host: String,
+    /// Port, 80 if not given
+    pub port: u16,
+    /// Path and query, at least "/"
+    pub path: String,
+}
+
+impl Url {
+    /// Parse `[http://]host[:port][/path]`.
+    ///
+    /// Returns None for other schemes (https needs TLS) or an invalid port.
+    ///
+    pub fn parse(url: &str) -> Option<Url> {
+        let rest = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+            Some(_) => return None,
+            None => url,
+        };
+
+        // the authority ends with the path (or the query, or the fragment)
+        let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(end);
+
+        // the fragment is never sent
+        let path = path.split('#').next().unwrap_or_default();
+        let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+
+        // no user info, IPv6 literals in brackets
+        let authority = authority.rsplit('@').next().unwrap_or_default();
+        let (host, port) = if let Some(literal) = authority.strip_prefix('[') {
+            let (host, port) = literal.split_once(']')?;
+            (host, port.strip_prefix(':'))
+        }else{
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+
+        let port = match port {
+            Some(port) if !port.is_empty() => port.parse().ok()?,
+            _ => 80,
+        };
+
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(Url { host: host.to_string(), port, path })
+    }
+
+    /// The value of the Host header: the host, and the port if not 80.
+    pub fn authority(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+
+        if self.port == 80 {
+            host
+        }else{
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+/// Create a request without body (RFC 9112, Section 3).
+///
+/// We ask the server to close the connection after the response, so it
+/// ends the body it sends without length as well.
+///
+/// method: The request method, e.g. GET or HEAD
+/// url: What to request
+///
+pub fn create_request(method: &str, url: &Url) -> Vec<u8> {
+    let mut request = String::new();
+
+    // request line, then the headers, then an empty line
+    request.push_str(&format!("{} {} HTTP/1.1\r\n", method, url.path));
+    request.push_str(&format!("Host: {}\r\n", url.authority()));
+    request.push_str(&format!("User-Agent: raw-networking-demo/{}\r\n", env!("CARGO_PKG_VERSION")));
+    request.push_str("Accept: */*\r\n");
+    request.push_str("Connection: close\r\n");
+    request.push_str("\r\n");
+
+    request.into_bytes()
+}
+
+/// A response, its body decoded from the transfer coding.
+#[derive(Debug)]
+pub struct Response {
+    /// HTTP version of the server, e.g. HTTP/1.1
+    pub version: String,
+    /// Status code
+    pub status: u16,
+    /// Reason phrase
+    pub reason: String,
+    /// Header fields, in the order received, trailer fields after them
+    pub headers: Vec<(String, String)>,
+    /// The body
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// The value of a header field, names are case-insensitive.
+    ///
+    /// Multiple fields of the same name are combined with commas
+    /// (RFC 9110, Section 5.3).
+    ///
+    pub fn header(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self.headers.iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect();
+
+        if values.is_empty() {
+            None
+        }else{
+            Some(values.join(", "))
+        }
+    }
+}
+
+/// An error about a malformed response.
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// Read a line ending with CRLF (or a bare LF), without the line end.
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+
+    if line.last() != Some(&b'\n') {
+        return Err(if line.len() >= MAX_LINE { invalid("Line too long") } else { ErrorKind::UnexpectedEof.into() });
+    }
+
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    // field values should be ASCII, keep anything else readable
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Read header (or trailer) fields up to the empty line (RFC 9112, Section 5).
+fn read_fields(reader: &mut impl BufRead, fields: &mut Vec<(String, String)>) -> io::Result<()> {
+    for _ in 0..MAX_HEADERS {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        // obsolete line folding: continues the previous value (Section 5.2)
+        if line.starts_with([' ', '\t']) {
+            let Some((_, value)) = fields.last_mut() else {
+                return Err(invalid("Header starts with a continuation line"));
+            };
+
+            value.push(' ');
+            value.push_str(line.trim());
+            continue;
+        }
+
+        // name, colon, value with optional white space around
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid(format!("Invalid header line '{}'", line)));
+        };
+
+        if name.is_empty() || name.ends_with([' ', '\t']) {
+            return Err(invalid(format!("Invalid header name '{}'", name)));
+        }
+
+        fields.push((name.to_string(), value.trim().to_string()));
+    }
+
+    Err(invalid("Too many header lines"))
+}
+
+/// Parse the status line, e.g. `HTTP/1.1 200 OK` (RFC 9112, Section 4).
+fn parse_status_line(line: &str) -> io::Result<(String, u16, String)> {
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    let status = parts.next().unwrap_or_default();
+    let reason = parts.next().unwrap_or_default();
+
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid(format!("Not an HTTP/1.x response: '{}'", line)));
+    }
+
+    // always three digits
+    if status.len() != 3 || !status.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid(format!("Invalid status code '{}'", status)));
+    }
+    let status = status.parse().unwrap_or_default();
+
+    Ok((version.to_string(), status, reason.to_string()))
+}
+
+/// Read a body in the chunked transfer coding (RFC 9112, Section 7.1).
+///
+/// Each chunk is its size in hex, optional extensions, CRLF, the data and
+/// CRLF. A chunk of size 0 ends the body, followed by optional trailer
+/// fields, which are added to the header fields.
+///
+fn read_chunked(reader: &mut impl BufRead, headers: &mut Vec<(String, String)>) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+
+    loop {
+        // the size, extensions are ignored
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| invalid(format!("Invalid chunk size '{}'", size)))?;
+
+        if size == 0 {
+            break;
+        }
+
+        // the size is not trusted, the buffer grows only with what arrives
+        let start = body.len();
+        reader.by_ref().take(size).read_to_end(&mut body)?;
+        let read = (body.len() - start) as u64;
+        if read < size {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, format!("Chunk ended after {} of {} bytes", read, size)));
+        }
+
+        // the data ends with CRLF
+        if !read_line(reader)?.is_empty() {
+            return Err(invalid("Chunk longer than its size"));
+        }
+    }
+
+    read_fields(reader, headers)?;
+    Ok(body)
+}
+
+/// Read a response (RFC 9112, Section 6.3 for how long its body is).
+///
+/// The body is chunked if the last transfer coding is chunked, else as
+/// long as Content-Length says, else it lasts until the server closes the
+/// connection. Responses to HEAD, 1xx, 204 and 304 have no body. Interim
+/// 1xx responses are skipped.
+///
+/// reader: The connection to read from
+/// head: The request was HEAD
+///
+pub fn read_response(reader: &mut impl BufRead, head: bool) -> io::Result<Response> {
+    loop {
+        let line = read_line(reader)?;
+        let (version, status, reason) = parse_status_line(&line)?;
+
+        let mut headers = Vec::new();
+        read_fields(reader, &mut headers)?;
+
+        let mut response = Response { version, status, reason, headers, body: Vec::new() };
+
+        // interim response, the final one follows
+        if (100..200).contains(&status) {
+            continue;
+        }
+
+        if head || status == 204 || status == 304 {
+            return Ok(response);
+        }
+
+        let transfer_encoding = response.header("Transfer-Encoding");
+        let content_length = response.header("Content-Length");
+
+        if let Some(encoding) = transfer_encoding {
+            // only chunked is understood, anything else lasts until the connection closes
+            let last = encoding.rsplit(',').next().unwrap_or_default().trim();
+            if last.eq_ignore_ascii_case("chunked") {
+                response.body = read_chunked(reader, &mut response.headers)?;
+            }else{
+                reader.read_to_end(&mut response.body)?;
+            }
+        }else if let Some(length) = content_length {
+            // repeated fields must all be the same
+            let mut lengths = length.split(',').map(|l| l.trim().parse::<u64>().ok());
+            let length = match lengths.next() {
+                Some(Some(first)) if lengths.all(|l| l == Some(first)) => first,
+                _ => return Err(invalid(format!("Invalid Content-Length '{}'", length))),
+            };
+
+            reader.by_ref().take(length).read_to_end(&mut response.body)?;
+            if (response.body.len() as u64) < length {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, format!("Body ended after {} of {} bytes", response.body.len(), length)));
+            }
+        }else{
+            reader.read_to_end(&mut response.body)?;
+        }
+
+        return Ok(response);
+    }
+}
+
+/// Send a request and read the response.
+///
+/// stream: The connection to the server, e.g. a `RawTcpStream`
+/// method: The request method, e.g. GET or HEAD
+/// url: What to request
+///
+pub fn request(stream: &mut (impl Read + Write), method: &str, url: &Url) -> io::Result<Response> {
+    stream.write_all(&create_request(method, url))?;
+
+    read_response(&mut BufReader::new(stream), method == "HEAD")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read a response from the bytes the server sent.
+    fn read(data: &str, head: bool) -> io::Result<Response> {
+        read_response(&mut data.as_bytes(), head)
+    }
+
+    #[test]
+    fn url_parse() {
+        let url = |host: &str, port, path: &str| Some(Url { host: host.to_string(), port, path: path.to_string() });
+
+        assert_eq!(Url::parse("http://example.com/"), url("example.com", 80, "/"));
+        assert_eq!(Url::parse("example.com"), url("example.com", 80, "/"));
+        assert_eq!(Url::parse("HTTP://example.com:8080/a/b?c=d#e"), url("example.com", 8080, "/a/b?c=d"));
+        assert_eq!(Url::parse("http://user@example.com?q"), url("example.com", 80, "/?q"));
+        assert_eq!(Url::parse("http://[2001:db8::1]:8080/"), url("2001:db8::1", 8080, "/"));
+        assert_eq!(Url::parse("http://[2001:db8::1]"), url("2001:db8::1", 80, "/"));
+
+        assert_eq!(Url::parse("https://example.com/"), None);
+        assert_eq!(Url::parse("http://example.com:http/"), None);
+        assert_eq!(Url::parse("http:///path"), None);
+    }
+
+    #[test]
+    fn authority() {
+        assert_eq!(Url::parse("example.com").unwrap().authority(), "example.com");
+        assert_eq!(Url::parse("example.com:8080").unwrap().authority(), "example.com:8080");
+        assert_eq!(Url::parse("[2001:db8::1]:8080").unwrap().authority(), "[2001:db8::1]:8080");
+    }
+
+    #[test]
+    fn status_line() {
+        assert_eq!(parse_status_line("HTTP/1.1 200 OK").unwrap(), ("HTTP/1.1".to_string(), 200, "OK".to_string()));
+        assert_eq!(parse_status_line("HTTP/1.0 404 Not Found").unwrap(), ("HTTP/1.0".to_string(), 404, "Not Found".to_string()));
+        assert_eq!(parse_status_line("HTTP/1.1 204").unwrap(), ("HTTP/1.1".to_string(), 204, String::new()));
+
+        for line in ["HTTP/2 200 OK", "ICY 200 OK", "HTTP/1.1 20 OK", "HTTP/1.1 2000 OK", "HTTP/1.1 2x0 OK", ""] {
+            assert_eq!(parse_status_line(line).unwrap_err().kind(), ErrorKind::InvalidData, "{}", line);
+        }
+    }
+
+    #[test]
+    fn header_fields() {
+        let response = read("HTTP/1.1 200 OK\r\nServer: demo\r\nX-Folded: a\r\n  b\r\nx-list: 1\nX-List:2\r\nContent-Length: 0\r\n\r\n", false).unwrap();
+
+        assert_eq!(response.header("server").as_deref(), Some("demo"));
+        assert_eq!(response.header("X-Folded").as_deref(), Some("a b"));
+        assert_eq!(response.header("X-List").as_deref(), Some("1, 2"));
+        assert_eq!(response.header("Missing"), None);
+
+        assert_eq!(read("HTTP/1.1 200 OK\r\nNo colon\r\n\r\n", false).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read("HTTP/1.1 200 OK\r\nName : value\r\n\r\n", false).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn interim_response_skipped() {
+        let response = read("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </a>\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi", false).unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Link"), None);
+        assert_eq!(response.body, b"hi");
+    }
+
+    #[test]
+    fn content_length() {
+        // repeated, but the same
+        let body = |data: &str| read(data, false).map(|response| response.body);
+        assert_eq!(body("HTTP/1.1 200 OK\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabcdef").unwrap(), b"abc");
+        assert_eq!(body("HTTP/1.1 200 OK\r\nContent-Length: 3, 3\r\n\r\nabc").unwrap(), b"abc");
+
+        // conflicting or no number
+        for length in ["Content-Length: 3\r\nContent-Length: 4", "Content-Length: 3, 4", "Content-Length: -1", "Content-Length: "] {
+            let data = format!("HTTP/1.1 200 OK\r\n{}\r\n\r\nabcd", length);
+            assert_eq!(body(&data).unwrap_err().kind(), ErrorKind::InvalidData, "{}", length);
+        }
+
+        // without length until the connection closes, without body for HEAD and 204
+        assert_eq!(body("HTTP/1.0 200 OK\r\n\r\nuntil the end").unwrap(), b"until the end");
+        assert!(read("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n", true).unwrap().body.is_empty());
+        assert!(body("HTTP/1.1 204 No Content\r\n\r\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn chunked() {
+        let response = read(concat!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+            "5;name=value\r\nhello\r\n",
+            "7\r\n, world\r\n",
+            "0;last\r\nX-Sum: 12\r\n\r\n",
+        ), false).unwrap();
+
+        assert_eq!(response.body, b"hello, world");
+        assert_eq!(response.header("X-Sum").as_deref(), Some("12"));
+    }
+
+    #[test]
+    fn truncated_body() {
+        assert_eq!(read("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabcd", false).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(read("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\na\r\nabcd", false).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        // the last chunk or the trailer missing
+        assert_eq!(read("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\n", false).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(read("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n", false).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        // more data than the chunk size says
+        assert_eq!(read("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabcd\r\n0\r\n\r\n", false).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_chunk() {
+        // sizes no server could send, without allocating them or overflowing
+        for size in ["ffffffffffffffff", "7fffffffffff"] {
+            let data = format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{}\r\nabcd", size);
+            assert_eq!(read(&data, false).unwrap_err().kind(), ErrorKind::UnexpectedEof, "{}", size);
+        }
+
+        for size in ["10000000000000000", "x", ""] {
+            let data = format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{}\r\nabcd", size);
+            assert_eq!(read(&data, false).unwrap_err().kind(), ErrorKind::InvalidData, "{}", size);
+        }
+
+        // a line without end
+        let data = format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{}", "f".repeat(MAX_LINE + 1));
+        assert_eq!(read(&data, false).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,349 @@
+// IP layer
+use std::{collections::HashMap, io::{self, ErrorKind}, net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs}};
+
+use pnet::datalink::{DataLinkReceiver, DataLinkSender};
+
+/// IP protocol number of ICMP
+pub const PROTOCOL_ICMP: u8 = 1;
+
+/// IP protocol number of TCP
+pub const PROTOCOL_TCP: u8 = 6;
+
+/// IP protocol number of UDP
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// How the identification field of the IPv4 header is chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdMode {
+    /// Always use the same ID
+    Fixed(u16),
+    /// Count up by one for every packet sent
+    Sequential,
+    /// Pick a new random ID for every packet sent
+    Random,
+}
+
+/// Per-packet options for the IPv4 header.
+///
+/// The defaults match what this demo always sent: ID 0xcc80, TTL 60,
+/// DSCP/ECN 0 and the DF flag cleared.
+///
+#[derive(Clone, Debug)]
+pub struct SendOptions {
+    /// Time to live
+    pub ttl: u8,
+    /// Differentiated services code point (6 bit, RFC 2474)
+    pub dscp: u8,
+    /// Explicit congestion notification (2 bit, RFC 3168)
+    pub ecn: u8,
+    /// Set the don't fragment flag
+    pub dont_fragment: bool,
+    /// How to choose the identification field
+    pub id_mode: IdMode,
+    // last ID used in sequential mode
+    next_id: u16,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        SendOptions {
+            ttl: 60,
+            dscp: 0,
+            ecn: 0,
+            dont_fragment: false,
+            id_mode: IdMode::Fixed(0xcc80),
+            next_id: rand::random(),
+        }
+    }
+}
+
+impl SendOptions {
+    /// Returns the identification for the next packet.
+    pub fn next_id(&mut self) -> u16 {
+        match self.id_mode {
+            IdMode::Fixed(id) => id,
+            IdMode::Sequential => {
+                self.next_id = self.next_id.wrapping_add(1);
+                self.next_id
+            },
+            IdMode::Random => rand::random(),
+        }
+    }
+
+    /// The type of service byte (DSCP + ECN).
+    pub fn tos(&self) -> u8 {
+        (self.dscp << 2) | (self.ecn & 0x03)
+    }
+}
+
+/// Resolve `host[:port]` to the first IPv4 address of the host.
+///
+/// default_port: The port if left out
+///
+pub fn resolve(server: &str, default_port: u16) -> io::Result<SocketAddrV4> {
+    if let Ok(addr) = server.parse::<SocketAddrV4>() {
+        return Ok(addr);
+    }
+
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("Invalid port '{}'", port)))?),
+        None => (server, default_port),
+    };
+
+    (host, port).to_socket_addrs()?
+        .find_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("No IPv4 address for {}", host)))
+}
+
+/// Parse a DSCP given either as a number (0-63) or as a name
+/// like EF, CS6, AF41 or BE (RFC 2474, RFC 2597, RFC 3246).
+pub fn parse_dscp(s: &str) -> Option<u8> {
+    let s = s.to_ascii_uppercase();
+
+    let dscp = if let Ok(n) = s.parse::<u8>() {
+        n
+    }else if s == "EF" {
+        46
+    }else if s == "BE" || s == "DF" {
+        0
+    }else if let Some(class) = s.strip_prefix("CS") {
+        let class: u8 = class.parse().ok()?;
+        if class > 7 {
+            return None;
+        }
+        class << 3
+    }else if let Some(af) = s.strip_prefix("AF") {
+        // AFxy: class x (1-4), drop precedence y (1-3)
+        let digits: Vec<u8> = af.bytes().map(|b| b.wrapping_sub(b'0')).collect();
+        if digits.len() != 2 || !(1..=4).contains(&digits[0]) || !(1..=3).contains(&digits[1]) {
+            return None;
+        }
+        (digits[0] << 3) | (digits[1] << 1)
+    }else{
+        return None;
+    };
+
+    if dscp < 64 {
+        Some(dscp)
+    }else{
+        None
+    }
+}
+
+/// Creates the IPv4 packet
+///
+/// src: Our IPv4 address
+/// dest: The destination IPv4 address
+/// protocol: The protocol number of the payload (e.g. PROTOCOL_UDP)
+/// payload: The transport layer packet
+/// options: Options for the header fields
+///
+pub fn create_ip_packet(src: Ipv4Addr, dest: Ipv4Addr, protocol: u8, payload: &[u8], options: &mut SendOptions) -> Vec<u8> {
+    // first byte (0x45), then DSCP/ECN
+    let mut data = vec![(4u8 << 4) | 5, options.tos()];
+
+    // length on index 2 and 3 will be set later
+    data.push(0);
+    data.push(0);
+
+    // ID, flags (only DF), fragment offset (always 0)
+    data.extend_from_slice(&options.next_id().to_be_bytes());
+    data.push(if options.dont_fragment { 0x40 } else { 0 });
+    data.push(0);
+
+    // TTL
+    data.push(options.ttl);
+
+    // protocol
+    data.push(protocol);
+
+    // checksum will be done later
+    data.push(0);// index 10
+    data.push(0);// index 11
+
+    // source address
+    data.extend_from_slice( &src.octets() );
+
+    // target address
+    data.extend_from_slice( &dest.octets() );
+
+    // the payload
+    data.extend_from_slice(payload);
+
+    // calculate total length
+    let len = u16::try_from(data.len()).expect("Payload too long").to_be_bytes();
+    data[2] = len[0];
+    data[3] = len[1];
+
+    // Now calculate checksum over IP header
+    let checksum = compute_checksum(&data[0..20]).to_be_bytes();
+    data[10] = checksum[0];
+    data[11] = checksum[1];
+
+    // ready, return data
+    data
+
+}
+
+pub fn compute_checksum(header: &[u8]) -> u16 {
+    // Ensure the header length is even (for 16-bit processing)
+    if !header.len().is_multiple_of(2) {
+        panic!("Header length must be even");
+    }
+
+    // Sum all 16-bit words
+    let mut sum: u32 = 0;
+    for chunk in header.chunks_exact(2) {
+        let word = u16::from_be_bytes([chunk[0], chunk[1]]);
+        sum += u32::from(word);
+    }
+
+    // Add the carry bits
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    // One's complement of the sum
+    !(sum as u16)
+}
+
+/// A received IPv4 packet.
+pub struct Ipv4Packet<'a> {
+    /// Source address
+    pub src: Ipv4Addr,
+    /// Destination address
+    pub dest: Ipv4Addr,
+    /// Protocol number of the payload
+    pub protocol: u8,
+    /// Time to live left on arrival
+    pub ttl: u8,
+    /// The payload, without any ethernet padding
+    pub payload: &'a [u8],
+}
+
+/// Parse an IPv4 packet and verify the header checksum.
+///
+/// Returns None if this is not a valid IPv4 packet.
+///
+pub fn parse(frame: &[u8]) -> Option<Ipv4Packet<'_>> {
+    // check version and length
+    if frame.len() < 20 || frame[0] >> 4 != 4 {
+        return None;
+    }
+
+    let header_len = ((frame[0] & 0x0F) * 4) as usize;
+    let total_len = u16::from_be_bytes([frame[2], frame[3]]) as usize;
+    if header_len < 20 || total_len < header_len || total_len > frame.len() {
+        return None;
+    }
+
+    // calculate checksum
+    let mut header = frame[0..header_len].to_vec();
+
+    header[10] = 0;
+    header[11] = 0;
+
+    let checksum = compute_checksum(header.as_slice()).to_be_bytes();
+    if checksum != frame[10..=11] {
+        println!("Received corrupted IP packet, {:?} != {:?}: Dropping it.", checksum, &frame[10..=11]);
+        return None;
+    }
+
+    Some(Ipv4Packet {
+        src: Ipv4Addr::from(<[u8; 4]>::try_from(&frame[12..16]).unwrap()),
+        dest: Ipv4Addr::from(<[u8; 4]>::try_from(&frame[16..20]).unwrap()),
+        protocol: frame[9],
+        ttl: frame[8],
+        payload: &frame[header_len..total_len],
+    })
+}
+
+/// The raw stack up to the IP layer: the ethernet channel, our addresses
+/// and the gateway every packet goes through, but those to the neighbors we answer.
+///
+/// Transport layer sockets are built on top of it.
+///
+pub struct Stack {
+    /// Sending half of the ethernet channel
+    pub tx: Box<dyn DataLinkSender>,
+    /// Receiving half of the ethernet channel, with a short read timeout
+    pub rx: Box<dyn DataLinkReceiver>,
+    /// Our MAC address
+    pub this_mac: [u8; 6],
+    /// Our IPv4 address
+    pub this_ip: Ipv4Addr,
+    /// The MAC address of the gateway
+    pub gateway_mac: [u8; 6],
+    /// MTU of the interface
+    pub mtu: usize,
+    /// Options for the IP header of each packet we send
+    pub send_options: SendOptions,
+    /// The MAC addresses of hosts on our link that sent to us directly, if we
+    /// accept them (server mode), else None and only the gateway is heard
+    pub neighbors: Option<HashMap<Ipv4Addr, [u8; 6]>>,
+}
+
+impl Stack {
+    /// Send an IPv4 packet through the gateway, or straight to a neighbor.
+    ///
+    /// dest: The destination IPv4 address
+    /// protocol: The protocol number of the payload
+    /// payload: The transport layer packet
+    ///
+    pub fn send(&mut self, dest: Ipv4Addr, protocol: u8, payload: &[u8]) -> io::Result<()> {
+        // build bottom-up: payload in IPv4 in ethernet
+        let packet = create_ip_packet(self.this_ip, dest, protocol, payload, &mut self.send_options);
+        let frame = crate::link::create_ethernet_packet(&self.this_mac, self.next_hop(&dest), &packet);
+
+        match self.tx.send_to(&frame, None) {
+            Some(result) => result,
+            None => Err(io::Error::other("Could not send ethernet frame")),
+        }
+    }
+
+    /// Receive the next IPv4 packet the gateway, or a neighbor, sends to us.
+    ///
+    /// Returns None for any other frame, and an error of kind TimedOut if
+    /// nothing arrived within the read timeout of the channel.
+    ///
+    pub fn recv(&mut self) -> io::Result<Option<Ipv4Packet<'_>>> {
+        let frame = self.rx.next()?;
+
+        // check source: the gateway, or a neighbor if we accept them
+        let Some(source) = frame.get(6..12).map(|mac| <[u8; 6]>::try_from(mac).unwrap()) else {
+            return Ok(None);
+        };
+        if source != self.gateway_mac && self.neighbors.is_none() {
+            return Ok(None);
+        }
+
+        let Some(packet) = crate::link::unwrap_ip_packet(frame, &self.this_mac, &source).and_then(parse) else {
+            return Ok(None);
+        };
+
+        if packet.dest != self.this_ip {
+            return Ok(None);
+        }
+
+        // remember the neighbors, through the gateway means not on our link
+        if let Some(neighbors) = &mut self.neighbors {
+            if source == self.gateway_mac {
+                neighbors.remove(&packet.src);
+            }else{
+                neighbors.insert(packet.src, source);
+            }
+        }
+
+        Ok(Some(packet))
+    }
+
+    /// The MAC address to send to dest: the neighbor itself if it sent to us
+    /// directly, else the gateway.
+    ///
+    fn next_hop(&self, dest: &Ipv4Addr) -> &[u8; 6] {
+        self.neighbors.as_ref().and_then(|neighbors| neighbors.get(dest)).unwrap_or(&self.gateway_mac)
+    }
+}
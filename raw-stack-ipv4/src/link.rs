@@ -1,7 +1,5 @@
 // Link layer (ethernet)
 
-use std::net::SocketAddr;
-
 /// Create the ARP packet to find gateway MAC address.
 /// 
 /// mac: Our MAC address
@@ -109,7 +107,10 @@ pub fn unwrap_arp(frame: &[u8], mac: [u8; 6], curr_ip: [u8; 4], lookup_ip: [u8;
 }
 
 /// Create Ethernet packet over IP
-pub fn create_ethernet_packet(src_mac: &[u8; 6], dest_mac: &[u8; 6], src: SocketAddr, dest: SocketAddr) -> Vec<u8> {
+///
+/// packet: The IPv4 packet to carry
+///
+pub fn create_ethernet_packet(src_mac: &[u8; 6], dest_mac: &[u8; 6], packet: &[u8]) -> Vec<u8> {
     // Create ethernet frame
     let mut frame = Vec::new();
     
@@ -121,19 +122,17 @@ pub fn create_ethernet_packet(src_mac: &[u8; 6], dest_mac: &[u8; 6], src: Socket
     frame.push(0x08);
     frame.push(0x00);
 
-    // Create payload of IPv4 packet
-    frame.extend(crate::ip::create_ip_packet(src, dest));
+    // Append payload IPv4 packet
+    frame.extend_from_slice(packet);
 
     // return ethernet frame
     frame
 }
 
-/// Unwrap Ethernet packet to return Timestamp.
-/// 
-/// Traverses up all layers to the application layer, returning the timestamp.
+/// Unwrap Ethernet packet to return the IPv4 packet it carries.
 /// 
-/// Returns None if this packet was not addressed to us, else return the timestamp.
-pub fn unwrap_ethernet_packet(frame: &[u8], our_mac: &[u8; 6], gateway_mac: &[u8; 6], src: SocketAddr, dest: SocketAddr) -> Option<String> {
+/// Returns None if this frame was not sent by the gateway to us, or is not IPv4.
+pub fn unwrap_ip_packet<'a>(frame: &'a [u8], our_mac: &[u8; 6], gateway_mac: &[u8; 6]) -> Option<&'a [u8]> {
     // check we are destination
     if &frame[0..6] != our_mac {
         return None;
@@ -149,6 +148,16 @@ pub fn unwrap_ethernet_packet(frame: &[u8], our_mac: &[u8; 6], gateway_mac: &[u8
         return None;
     }
 
-    // Okay, delegate to IP layer
-    crate::ip::unpack(&frame[14..], src, dest)
-}
\ No newline at end of file
+    Some(&frame[14..])
+}
+
+/// Get the MTU of an interface.
+///
+/// Read from sysfs, 1500 (the ethernet default) if that fails.
+///
+pub fn interface_mtu(name: &str) -> usize {
+    std::fs::read_to_string(format!("/sys/class/net/{}/mtu", name))
+        .ok()
+        .and_then(|mtu| mtu.trim().parse().ok())
+        .unwrap_or(1500)
+}
@@ -0,0 +1,1979 @@
+// TCP (transport layer)
+use std::{cell::RefCell, collections::{HashMap, VecDeque}, hash::{BuildHasher, RandomState}, io::{self, ErrorKind, Read, Write}, net::{Ipv4Addr, SocketAddrV4}, rc::Rc, time::{Duration, Instant}};
+
+use crate::ip::{Ipv4Packet, Stack, PROTOCOL_TCP};
+
+/// TCP flag FIN: no more data from the sender
+pub const FIN: u8 = 0x01;
+
+/// TCP flag SYN: synchronize sequence numbers
+pub const SYN: u8 = 0x02;
+
+/// TCP flag RST: reset the connection
+pub const RST: u8 = 0x04;
+
+/// TCP flag PSH: push the data to the application
+pub const PSH: u8 = 0x08;
+
+/// TCP flag ACK: the acknowledgment number is valid
+pub const ACK: u8 = 0x10;
+
+/// TCP option kind: end of option list
+const OPTION_END: u8 = 0;
+
+/// TCP option kind: no operation, for alignment
+const OPTION_NOP: u8 = 1;
+
+/// TCP option kind: maximum segment size (RFC 9293, Section 3.7.1)
+const OPTION_MSS: u8 = 2;
+
+/// TCP option kind: window scale (RFC 7323, Section 2)
+const OPTION_WINDOW_SCALE: u8 = 3;
+
+/// TCP option kind: SACK permitted (RFC 2018, Section 2)
+const OPTION_SACK_PERMITTED: u8 = 4;
+
+/// TCP option kind: SACK blocks (RFC 2018, Section 3)
+const OPTION_SACK: u8 = 5;
+
+/// TCP option kind: timestamps (RFC 7323, Section 3)
+const OPTION_TIMESTAMPS: u8 = 8;
+
+/// Bytes the timestamps take in every segment, with the two NOPs in front.
+const TIMESTAMPS_LEN: usize = 12;
+
+/// Maximum segment size of the peer if it sends no MSS option (RFC 9293, Section 3.7.1).
+const DEFAULT_MSS: usize = 536;
+
+/// Smallest MSS of the peer we go along with, like Linux.
+const MIN_MSS: usize = 48;
+
+/// Size of the IPv4 and TCP headers without options: our MSS is the MTU less these.
+const HEADERS_LEN: usize = 20 + 20;
+
+/// Size of the receive buffer, the largest window we announce.
+const RECEIVE_BUFFER: usize = 1 << 20;
+
+/// Our window scale shift, the one Linux announces (RFC 7323, Section 2.2).
+const WINDOW_SCALE: u8 = 7;
+
+/// Largest window scale shift of the peer (RFC 7323, Section 2.3).
+const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Size of the send buffer, `write` blocks while it is full.
+const SEND_BUFFER: usize = 1 << 20;
+
+/// Retransmission timeout before the first round trip time measurement (RFC 6298, Section 2.1).
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// Lower bound of the retransmission timeout (RFC 6298, Section 2.4).
+const MIN_RTO: Duration = Duration::from_secs(1);
+
+/// Upper bound of the retransmission timeout (RFC 6298, Section 2.5).
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Our clock granularity: the read timeout of the ethernet channel.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(10);
+
+/// How often the retransmission timer may expire in a row before giving up.
+const MAX_RETRANSMITS: u32 = 6;
+
+/// Number of duplicate acknowledgments triggering a fast retransmit (RFC 5681, Section 3.2).
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+/// How many connections of a listener may be in the handshake (SYN queue).
+const SYN_BACKLOG: usize = 128;
+
+/// How many established connections may wait for `accept` (accept queue).
+const ACCEPT_BACKLOG: usize = 128;
+
+/// How long a dropped stream may take to close, like the FIN timeout of Linux.
+const FIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Check if sequence number a is before b, modulo 2^32.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Check if sequence number a is before or equal b, modulo 2^32.
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// Initial congestion window for a sender MSS (RFC 5681, Section 3.1).
+fn initial_window(smss: usize) -> usize {
+    if smss > 2190 {
+        2 * smss
+    }else if smss > 1095 {
+        3 * smss
+    }else{
+        4 * smss
+    }
+}
+
+/// Sort ranges of sequence numbers by their distance from base, and merge
+/// the ones that overlap or touch.
+fn merge_ranges(ranges: &mut Vec<(u32, u32)>, base: u32) {
+    ranges.sort_by_key(|(left, _)| left.wrapping_sub(base));
+
+    let mut merged: Vec<(u32, u32)> = Vec::new();
+    for &(left, right) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if seq_le(left, last.1) => {
+                if seq_lt(last.1, right) {
+                    last.1 = right;
+                }
+            },
+            _ => merged.push((left, right)),
+        }
+    }
+
+    *ranges = merged;
+}
+
+/// Compute the TCP checksum over the IPv4 pseudo header and the segment (RFC 9293, Section 3.1).
+///
+/// The checksum field of the segment is expected to be zero.
+///
+pub fn compute_checksum(src: Ipv4Addr, dest: Ipv4Addr, segment: &[u8]) -> u16 {
+    let mut check = Vec::new();
+
+    // IPv4 pseudo header: source, destination, zero, protocol, TCP length
+    check.extend_from_slice(&src.octets());
+    check.extend_from_slice(&dest.octets());
+    check.push(0);
+    check.push(PROTOCOL_TCP);
+    check.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+
+    // push TCP header and payload
+    check.extend_from_slice(segment);
+
+    if check.len() % 2 == 1 {
+        // align 2
+        check.push(0x00);
+    }
+
+    crate::ip::compute_checksum(&check)
+}
+
+/// The TCP options we know (RFC 9293 Section 3.2, RFC 7323, RFC 2018).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Maximum segment size, only in SYN segments
+    pub mss: Option<u16>,
+    /// Window scale shift, only in SYN segments
+    pub window_scale: Option<u8>,
+    /// SACK permitted, only in SYN segments
+    pub sack_permitted: bool,
+    /// Timestamp value and timestamp echo reply
+    pub timestamps: Option<(u32, u32)>,
+    /// SACK blocks: first sequence number of the block, and the one after it
+    pub sack: Vec<(u32, u32)>,
+}
+
+impl Options {
+    /// Encode the options, padded to a multiple of 4 bytes.
+    ///
+    /// The order is the one of Linux, so a SYN looks like any other:
+    /// MSS, SACK permitted, timestamps, NOP, window scale.
+    ///
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        if let Some(mss) = self.mss {
+            data.extend_from_slice(&[OPTION_MSS, 4]);
+            data.extend_from_slice(&mss.to_be_bytes());
+        }
+
+        // SACK permitted takes the place of the NOPs in front of the timestamps
+        match (self.sack_permitted, self.timestamps.is_some()) {
+            (true, true) => data.extend_from_slice(&[OPTION_SACK_PERMITTED, 2]),
+            (true, false) => data.extend_from_slice(&[OPTION_NOP, OPTION_NOP, OPTION_SACK_PERMITTED, 2]),
+            (false, true) => data.extend_from_slice(&[OPTION_NOP, OPTION_NOP]),
+            (false, false) => {},
+        }
+
+        if let Some((value, echo_reply)) = self.timestamps {
+            data.extend_from_slice(&[OPTION_TIMESTAMPS, 10]);
+            data.extend_from_slice(&value.to_be_bytes());
+            data.extend_from_slice(&echo_reply.to_be_bytes());
+        }
+
+        if let Some(shift) = self.window_scale {
+            data.extend_from_slice(&[OPTION_NOP, OPTION_WINDOW_SCALE, 3, shift]);
+        }
+
+        if !self.sack.is_empty() {
+            data.extend_from_slice(&[OPTION_NOP, OPTION_NOP, OPTION_SACK, 2 + 8 * self.sack.len() as u8]);
+            for (left, right) in &self.sack {
+                data.extend_from_slice(&left.to_be_bytes());
+                data.extend_from_slice(&right.to_be_bytes());
+            }
+        }
+
+        // align 4
+        while data.len() % 4 != 0 {
+            data.push(OPTION_END);
+        }
+
+        data
+    }
+
+    /// Decode the options of a segment.
+    ///
+    /// Unknown options are skipped, a malformed one ends the list.
+    ///
+    pub fn decode(data: &[u8]) -> Options {
+        let mut options = Options::default();
+        let mut i = 0;
+
+        while i < data.len() {
+            let kind = data[i];
+            if kind == OPTION_END {
+                break;
+            }
+
+            if kind == OPTION_NOP {
+                i += 1;
+                continue;
+            }
+
+            // kind, length (of all three), value
+            let Some(&len) = data.get(i + 1) else {
+                break;
+            };
+            let len = usize::from(len);
+            if len < 2 || i + len > data.len() {
+                break;
+            }
+            let value = &data[i + 2..i + len];
+
+            match (kind, value.len()) {
+                (OPTION_MSS, 2) => options.mss = Some(u16::from_be_bytes([value[0], value[1]])),
+                (OPTION_WINDOW_SCALE, 1) => options.window_scale = Some(value[0]),
+                (OPTION_SACK_PERMITTED, 0) => options.sack_permitted = true,
+                (OPTION_TIMESTAMPS, 8) => {
+                    options.timestamps = Some((
+                        u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                        u32::from_be_bytes([value[4], value[5], value[6], value[7]]),
+                    ));
+                },
+                (OPTION_SACK, len) if len.is_multiple_of(8) => {
+                    options.sack = value.chunks(8).map(|block| (
+                        u32::from_be_bytes([block[0], block[1], block[2], block[3]]),
+                        u32::from_be_bytes([block[4], block[5], block[6], block[7]]),
+                    )).collect();
+                },
+                _ => {},
+            }
+
+            i += len;
+        }
+
+        options
+    }
+}
+
+/// The fields of a TCP header besides the ports.
+pub struct Header {
+    /// Sequence number of the first byte (or SYN/FIN)
+    pub seq: u32,
+    /// Acknowledgment number, only valid with the ACK flag
+    pub ack: u32,
+    /// The TCP flags
+    pub flags: u8,
+    /// The receive window, as in the header (scaled down)
+    pub window: u16,
+    /// The TCP options
+    pub options: Options,
+}
+
+/// Create a TCP segment.
+///
+/// src: Our address and port
+/// dest: The address and port of the peer
+/// header: Sequence numbers, flags, window and options
+/// payload: The data
+///
+pub fn create_segment(src: SocketAddrV4, dest: SocketAddrV4, header: &Header, payload: &[u8]) -> Vec<u8> {
+    let options = header.options.encode();
+    let mut data = Vec::new();
+
+    // ports
+    data.extend_from_slice(&src.port().to_be_bytes());
+    data.extend_from_slice(&dest.port().to_be_bytes());
+
+    // sequence and acknowledgment number
+    data.extend_from_slice(&header.seq.to_be_bytes());
+    data.extend_from_slice(&header.ack.to_be_bytes());
+
+    // data offset in 32 bit words, flags
+    data.push((((20 + options.len()) / 4) << 4) as u8);
+    data.push(header.flags);
+
+    // window
+    data.extend_from_slice(&header.window.to_be_bytes());
+
+    // checksum set below, urgent pointer unused
+    data.extend_from_slice(&[0x00u8; 4]);
+
+    // the options and the payload
+    data.extend_from_slice(&options);
+    data.extend_from_slice(payload);
+
+    let checksum = compute_checksum(*src.ip(), *dest.ip(), &data).to_be_bytes();
+    data[16] = checksum[0];
+    data[17] = checksum[1];
+
+    data
+}
+
+/// Create the reset answering a segment without connection (RFC 9293, Section 3.10.7.1).
+///
+/// src: Our address and port
+/// dest: The address and port of the peer
+/// header: The header of the segment
+/// len: The length of its payload
+///
+pub fn create_reset(src: SocketAddrV4, dest: SocketAddrV4, header: &Header, len: usize) -> Vec<u8> {
+    let reset = if header.flags & ACK != 0 {
+        Header { seq: header.ack, ack: 0, flags: RST, window: 0, options: Options::default() }
+    }else{
+        // SYN and FIN count as one byte each
+        let len = len + usize::from(header.flags & SYN != 0) + usize::from(header.flags & FIN != 0);
+        Header { seq: 0, ack: header.seq.wrapping_add(len as u32), flags: RST | ACK, window: 0, options: Options::default() }
+    };
+
+    create_segment(src, dest, &reset, &[])
+}
+
+/// A received TCP segment.
+pub struct Segment<'a> {
+    /// Source port
+    pub src_port: u16,
+    /// Destination port
+    pub dest_port: u16,
+    /// The header fields
+    pub header: Header,
+    /// The data
+    pub payload: &'a [u8],
+}
+
+/// Parse the TCP segment carried in an IPv4 packet and verify its checksum.
+///
+/// Returns None if the packet carries no valid TCP segment.
+///
+pub fn parse<'a>(packet: &Ipv4Packet<'a>) -> Option<Segment<'a>> {
+    let data = packet.payload;
+    if packet.protocol != PROTOCOL_TCP || data.len() < 20 {
+        return None;
+    }
+
+    let header_len = usize::from(data[12] >> 4) * 4;
+    if header_len < 20 || header_len > data.len() {
+        return None;
+    }
+
+    // verify checksum
+    let mut check = data.to_vec();
+    check[16] = 0;
+    check[17] = 0;
+
+    if compute_checksum(packet.src, packet.dest, &check).to_be_bytes() != data[16..18] {
+        println!("Received corrupted IPv4/TCP packet: Dropping it.");
+        return None;
+    }
+
+    Some(Segment {
+        src_port: u16::from_be_bytes([data[0], data[1]]),
+        dest_port: u16::from_be_bytes([data[2], data[3]]),
+        header: Header {
+            seq: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            ack: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            flags: data[13],
+            window: u16::from_be_bytes([data[14], data[15]]),
+            options: Options::decode(&data[20..header_len]),
+        },
+        payload: &data[header_len..],
+    })
+}
+
+/// State of a TCP connection (RFC 9293, Section 3.3.2).
+///
+/// LISTEN is the state of a `RawTcpListener`, a connection starts in
+/// SYN-SENT or SYN-RECEIVED. TIME-WAIT is left immediately, since nobody
+/// else will use our port.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// SYN sent, waiting for SYN-ACK
+    SynSent,
+    /// SYN received and SYN-ACK sent, waiting for its ACK
+    SynReceived,
+    /// Connected, data flows both ways
+    Established,
+    /// We sent FIN, waiting for its ACK
+    FinWait1,
+    /// Our FIN was acknowledged, waiting for the FIN of the peer
+    FinWait2,
+    /// Both sent FIN at the same time, waiting for the ACK of ours
+    Closing,
+    /// The peer sent FIN, we may still send
+    CloseWait,
+    /// We sent FIN after the peer did, waiting for its ACK
+    LastAck,
+    /// Both directions are closed
+    Closed,
+}
+
+
+/// Retransmission timeout estimation (RFC 6298).
+struct RtoEstimator {
+    /// Smoothed round trip time, None before the first measurement
+    srtt: Option<Duration>,
+    /// Round trip time variation
+    rttvar: Duration,
+    /// The current retransmission timeout
+    rto: Duration,
+}
+
+impl RtoEstimator {
+    /// Start with the initial timeout, no measurement yet.
+    fn new() -> RtoEstimator {
+        RtoEstimator {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    /// Update with a round trip time measurement (RFC 6298, Section 2.2 and 2.3).
+    fn sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            },
+            Some(srtt) => {
+                // RTTVAR first, it uses the old SRTT
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                (srtt * 7 + rtt) / 8
+            },
+        };
+
+        self.srtt = Some(srtt);
+        self.rto = (srtt + CLOCK_GRANULARITY.max(self.rttvar * 4)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Double the timeout after the timer expired (RFC 6298, Section 5.5).
+    fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
+
+/// A segment received before the ones in front of it.
+struct OutOfOrder {
+    /// Sequence number of the first byte
+    seq: u32,
+    /// The data
+    data: Vec<u8>,
+    /// The segment carried a FIN
+    fin: bool,
+}
+
+/// A connection is known by our port and the address and port of the
+/// peer, our address is the one of the stack.
+type Key = (u16, SocketAddrV4);
+
+/// The state of a TCP connection (transmission control block, RFC 9293, Section 3.3.1).
+///
+/// It only queues the segments to send, the host sends them.
+///
+struct Connection {
+    /// Our address and port
+    local: SocketAddrV4,
+    /// Address and port of the peer
+    remote: SocketAddrV4,
+    /// State of the connection
+    state: State,
+    /// Oldest unacknowledged sequence number
+    snd_una: u32,
+    /// Next sequence number to send
+    snd_nxt: u32,
+    /// Highest sequence number sent so far, snd_nxt goes back on timeouts
+    snd_max: u32,
+    /// The window of the peer in bytes
+    snd_wnd: usize,
+    /// Window scale shift of the peer, 0 if not negotiated
+    snd_wscale: u8,
+    /// Largest payload of a segment: the MSS of the peer less the options in every segment
+    smss: usize,
+    /// Sequence number of our FIN, once closed
+    fin_seq: Option<u32>,
+    /// Data not acknowledged yet, sent or not, starting at snd_una
+    unacked: VecDeque<u8>,
+    /// Next sequence number we expect
+    rcv_nxt: u32,
+    /// Our window scale shift, 0 if not negotiated
+    rcv_wscale: u8,
+    /// The MSS we announce
+    rcv_mss: usize,
+    /// Data received but not read yet
+    received: VecDeque<u8>,
+    /// Segments received out of order (reorder buffer)
+    out_of_order: Vec<OutOfOrder>,
+    /// Sequence number of the segment last kept out of order, its SACK block goes first
+    last_out_of_order: u32,
+    /// The window we announced last, in bytes
+    advertised: usize,
+    /// The acknowledgment number we sent last
+    last_ack_sent: u32,
+    /// Timestamps negotiated
+    timestamps: bool,
+    /// Timestamp value of the peer to echo (TS.Recent)
+    ts_recent: u32,
+    /// Random offset of our timestamp clock
+    ts_offset: u32,
+    /// Start of our timestamp clock
+    ts_start: Instant,
+    /// SACK negotiated
+    sack_permitted: bool,
+    /// Ranges above snd_una the peer reported received (SACK scoreboard)
+    sacked: Vec<(u32, u32)>,
+    /// End of the last hole retransmitted in this recovery
+    high_rxt: u32,
+    /// Retransmission timeout
+    rto: RtoEstimator,
+    /// When the retransmission timer expires, None if stopped
+    timer: Option<Instant>,
+    /// End of the segment timed for a round trip time measurement, and when it was sent
+    timed: Option<(u32, Instant)>,
+    /// How often the timer expired in a row
+    retransmits: u32,
+    /// Congestion window in bytes
+    cwnd: usize,
+    /// Slow start threshold in bytes
+    ssthresh: usize,
+    /// Number of duplicate acknowledgments in a row
+    dup_acks: u32,
+    /// snd_max when the last loss was detected, NewReno leaves fast recovery once it is acknowledged
+    recover: u32,
+    /// In fast recovery
+    in_recovery: bool,
+    /// Segments to send
+    outbox: Vec<Vec<u8>>,
+    /// Why the connection failed, every call of its stream reports it
+    error: Option<(ErrorKind, String)>,
+    /// Since when no stream handles the connection (not accepted yet, or dropped)
+    orphaned: Option<Instant>,
+}
+
+impl Connection {
+    /// A new connection, its SYN not sent yet.
+    ///
+    /// local: Our address and port
+    /// remote: Address and port of the peer
+    /// iss: Our initial sequence number
+    /// mss: The MSS we announce
+    /// state: SynSent to connect, SynReceived to answer a SYN
+    ///
+    fn new(local: SocketAddrV4, remote: SocketAddrV4, iss: u32, mss: usize, state: State) -> Connection {
+        Connection {
+            local,
+            remote,
+            state,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: 0,
+            snd_wscale: 0,
+            smss: DEFAULT_MSS,
+            fin_seq: None,
+            unacked: VecDeque::new(),
+            rcv_nxt: 0,
+            rcv_wscale: 0,
+            rcv_mss: mss,
+            received: VecDeque::new(),
+            out_of_order: Vec::new(),
+            last_out_of_order: 0,
+            advertised: 0,
+            last_ack_sent: 0,
+            timestamps: false,
+            ts_recent: 0,
+            ts_offset: rand::random(),
+            ts_start: Instant::now(),
+            sack_permitted: false,
+            sacked: Vec::new(),
+            high_rxt: iss,
+            rto: RtoEstimator::new(),
+            timer: None,
+            timed: None,
+            retransmits: 0,
+            cwnd: 0,
+            ssthresh: usize::MAX,
+            dup_acks: 0,
+            recover: iss,
+            in_recovery: false,
+            outbox: Vec::new(),
+            error: None,
+            orphaned: None,
+        }
+    }
+
+    /// The error the connection failed with, if any.
+    fn error(&self) -> Option<io::Error> {
+        self.error.as_ref().map(|(kind, message)| io::Error::new(*kind, message.clone()))
+    }
+
+    /// Fail the connection: it is closed, and its stream reports the error.
+    fn fail(&mut self, kind: ErrorKind, message: String) {
+        self.error = Some((kind, message));
+        self.state = State::Closed;
+        self.timer = None;
+    }
+
+    /// Our timestamp clock: milliseconds from a random start (RFC 7323, Section 5.4).
+    fn ts_now(&self) -> u32 {
+        (self.ts_start.elapsed().as_millis() as u32).wrapping_add(self.ts_offset)
+    }
+
+    /// What is left of the receive buffer, in bytes.
+    fn receive_space(&self) -> usize {
+        RECEIVE_BUFFER - self.received.len().min(RECEIVE_BUFFER)
+    }
+
+    /// Check if the connection can be forgotten: no stream handles it, and
+    /// it is closed, or did not manage to close within FIN_TIMEOUT. Dropped
+    /// while connecting, there is nothing to close (RFC 9293, Section 3.10.4).
+    fn gone(&self) -> bool {
+        let Some(since) = self.orphaned else {
+            return false;
+        };
+
+        match self.state {
+            State::Closed | State::SynSent => true,
+            State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck => since.elapsed() > FIN_TIMEOUT,
+            _ => false,
+        }
+    }
+
+    /// Check if anything we sent (or want to send) is not acknowledged yet.
+    fn outstanding(&self) -> bool {
+        !self.unacked.is_empty() || matches!(self.state, State::FinWait1 | State::Closing | State::LastAck)
+    }
+
+    /// Send our SYN, or the SYN-ACK answering the SYN of the peer.
+    ///
+    /// The SYN offers all options, the SYN-ACK those the SYN offered. The
+    /// window of both is never scaled.
+    ///
+    fn send_syn(&mut self) {
+        let passive = self.state == State::SynReceived;
+        let iss = self.snd_una;
+
+        let header = Header {
+            seq: iss,
+            ack: if passive { self.rcv_nxt } else { 0 },
+            flags: if passive { SYN | ACK } else { SYN },
+            window: RECEIVE_BUFFER.min(usize::from(u16::MAX)) as u16,
+            options: Options {
+                mss: Some(self.rcv_mss as u16),
+                window_scale: (!passive || self.rcv_wscale != 0).then_some(WINDOW_SCALE),
+                sack_permitted: !passive || self.sack_permitted,
+                timestamps: (!passive || self.timestamps).then(|| (self.ts_now(), self.ts_recent)),
+                sack: Vec::new(),
+            },
+        };
+
+        self.outbox.push(create_segment(self.local, self.remote, &header, &[]));
+        self.sent(iss, iss.wrapping_add(1));
+    }
+
+    /// Take the options of the SYN of the peer: each one is used if both offer it.
+    fn negotiate(&mut self, options: &Options) {
+        // both scale, or none does (RFC 7323, Section 2.2)
+        if let Some(shift) = options.window_scale {
+            self.snd_wscale = shift.min(MAX_WINDOW_SCALE);
+            self.rcv_wscale = WINDOW_SCALE;
+        }
+
+        if let Some((value, _)) = options.timestamps {
+            self.timestamps = true;
+            self.ts_recent = value;
+        }
+
+        self.sack_permitted = options.sack_permitted;
+
+        // no larger than our own MTU allows, and room for the timestamps (RFC 7323, Section 3.2)
+        self.smss = options.mss.map_or(DEFAULT_MSS, usize::from).max(MIN_MSS).min(self.rcv_mss);
+        if self.timestamps {
+            self.smss -= TIMESTAMPS_LEN;
+        }
+    }
+
+    /// The handshake is complete: our SYN is acknowledged.
+    ///
+    /// ack: The acknowledgment number
+    /// window: The window of the peer, scaled
+    /// echo_reply: The timestamp echo reply, if timestamps are negotiated
+    ///
+    fn synchronized(&mut self, ack: u32, window: usize, echo_reply: Option<u32>) {
+        self.snd_una = ack;
+        self.snd_wnd = window;
+        self.cwnd = initial_window(self.smss);
+        self.state = State::Established;
+
+        self.sample_rtt(ack, echo_reply);
+
+        if self.retransmits > 0 {
+            // SYN lost, start the data transfer carefully (RFC 6298, Section 5.7)
+            self.rto.rto = self.rto.rto.max(Duration::from_secs(3));
+        }
+
+        self.retransmits = 0;
+        self.timer = None;
+    }
+
+    /// Measure the round trip time with an acknowledgment.
+    ///
+    /// From the echoed timestamp, retransmissions included (RFC 7323,
+    /// Section 4), else from the timed segment. An echo reply of 0 is no
+    /// timestamp of ours, the peer has none to echo yet, so it is ignored
+    /// like Linux does.
+    ///
+    fn sample_rtt(&mut self, ack: u32, echo_reply: Option<u32>) {
+        if let Some(echo_reply) = echo_reply.filter(|&echo_reply| echo_reply != 0) {
+            let rtt = self.ts_now().wrapping_sub(echo_reply);
+            self.rto.sample(Duration::from_millis(u64::from(rtt)));
+        }else if let Some((end, sent)) = self.timed && seq_le(end, ack) {
+            self.rto.sample(sent.elapsed());
+            self.timed = None;
+        }
+    }
+
+    /// SACK blocks for the segments in the reorder buffer (RFC 2018, Section 4).
+    ///
+    /// The block with the segment received last goes first, then the others
+    /// in order, as many as fit next to the timestamps.
+    ///
+    fn sack_blocks(&self) -> Vec<(u32, u32)> {
+        let mut blocks: Vec<(u32, u32)> = self.out_of_order.iter()
+            .filter(|s| !s.data.is_empty())
+            .map(|s| (s.seq, s.seq.wrapping_add(s.data.len() as u32)))
+            .collect();
+        merge_ranges(&mut blocks, self.rcv_nxt);
+
+        let last = self.last_out_of_order;
+        if let Some(i) = blocks.iter().position(|&(left, right)| seq_le(left, last) && seq_lt(last, right)) {
+            let block = blocks.remove(i);
+            blocks.insert(0, block);
+        }
+
+        blocks.truncate(if self.timestamps { 3 } else { 4 });
+        blocks
+    }
+
+    /// Send a segment acknowledging what we received so far.
+    ///
+    /// It carries the timestamps if negotiated, and SACK blocks if it has no
+    /// data and what we received has holes.
+    ///
+    fn send_segment(&mut self, seq: u32, flags: u8, payload: &[u8]) {
+        let window = (self.receive_space() >> self.rcv_wscale).min(usize::from(u16::MAX));
+        self.advertised = window << self.rcv_wscale;
+        self.last_ack_sent = self.rcv_nxt;
+
+        let options = Options {
+            timestamps: self.timestamps.then(|| (self.ts_now(), self.ts_recent)),
+            sack: if self.sack_permitted && payload.is_empty() { self.sack_blocks() } else { Vec::new() },
+            ..Options::default()
+        };
+
+        let header = Header { seq, ack: self.rcv_nxt, flags: flags | ACK, window: window as u16, options };
+        self.outbox.push(create_segment(self.local, self.remote, &header, payload));
+    }
+
+    /// Note that the sequence numbers from seq up to end were sent.
+    ///
+    /// Starts the retransmission timer if not running, and times the segment
+    /// if nothing is timed yet. Retransmissions are never timed (Karn's algorithm).
+    ///
+    fn sent(&mut self, seq: u32, end: u32) {
+        if seq_lt(seq, self.snd_max) {
+            self.timed = None;
+        }else if self.timed.is_none() {
+            self.timed = Some((end, Instant::now()));
+        }
+
+        if seq_lt(self.snd_nxt, end) {
+            self.snd_nxt = end;
+        }
+
+        if seq_lt(self.snd_max, end) {
+            self.snd_max = end;
+        }
+
+        if self.timer.is_none() {
+            self.timer = Some(Instant::now() + self.rto.rto);
+        }
+    }
+
+    /// Send len bytes of the send buffer, starting at sequence number seq.
+    fn send_data(&mut self, seq: u32, len: usize) {
+        let offset = seq.wrapping_sub(self.snd_una) as usize;
+        let payload: Vec<u8> = self.unacked.range(offset..offset + len).copied().collect();
+
+        self.send_segment(seq, PSH, &payload);
+        self.sent(seq, seq.wrapping_add(len as u32));
+    }
+
+    /// Send our FIN.
+    fn send_fin(&mut self, fin_seq: u32) {
+        self.send_segment(fin_seq, FIN, &[]);
+        self.sent(fin_seq, fin_seq.wrapping_add(1));
+    }
+
+    /// Send what is not sent yet, as far as the window of the peer and the
+    /// congestion window allow, and our FIN after the data once closed.
+    fn transmit(&mut self) {
+        if !self.outstanding() {
+            return;
+        }
+
+        // the first two duplicate acknowledgments may send a segment each (limited transmit, RFC 3042)
+        let limited = if self.in_recovery { 0 } else { self.dup_acks.min(DUP_ACK_THRESHOLD - 1) as usize * self.smss };
+        let window = self.snd_wnd.min(self.cwnd + limited);
+
+        loop {
+            let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+
+            if offset < self.unacked.len() {
+                let len = self.smss.min(self.unacked.len() - offset).min(window.saturating_sub(offset));
+
+                // no small segments while more data waits, unless nothing is in flight (RFC 9293, Section 3.8.6.2.1)
+                if len == 0 || (len < self.smss && offset + len < self.unacked.len() && offset > 0) {
+                    break;
+                }
+
+                self.send_data(self.snd_nxt, len);
+            }else if let Some(fin_seq) = self.fin_seq && fin_seq == self.snd_nxt {
+                self.send_fin(fin_seq);
+            }else{
+                break;
+            }
+        }
+
+        // also if the window is closed, so it gets probed
+        if self.timer.is_none() {
+            self.timer = Some(Instant::now() + self.rto.rto);
+        }
+    }
+
+    /// Retransmit the oldest unacknowledged segment, or our FIN.
+    fn retransmit(&mut self) {
+        if !self.unacked.is_empty() {
+            self.send_data(self.snd_una, self.smss.min(self.unacked.len()));
+        }else if let Some(fin_seq) = self.fin_seq && self.outstanding() {
+            self.send_fin(fin_seq);
+        }
+    }
+
+    /// Retransmit the next hole the SACK blocks of the peer show, after the
+    /// ones retransmitted in this recovery already.
+    ///
+    /// Returns false if there is no such hole.
+    ///
+    fn retransmit_hole(&mut self) -> bool {
+        let Some(&(_, highest)) = self.sacked.last() else {
+            return false;
+        };
+
+        // skip what was retransmitted or SACKed already
+        let mut start = if seq_lt(self.snd_una, self.high_rxt) { self.high_rxt } else { self.snd_una };
+        for &(left, right) in &self.sacked {
+            if seq_le(left, start) && seq_lt(start, right) {
+                start = right;
+            }
+        }
+
+        if !seq_lt(start, highest) {
+            return false;
+        }
+
+        // up to the next SACK block
+        let next = self.sacked.iter()
+            .map(|&(left, _)| left)
+            .find(|&left| seq_lt(start, left))
+            .unwrap_or(highest);
+
+        let offset = start.wrapping_sub(self.snd_una) as usize;
+        let len = self.smss
+            .min(next.wrapping_sub(start) as usize)
+            .min(self.unacked.len().saturating_sub(offset));
+        if len == 0 {
+            return false;
+        }
+
+        self.send_data(start, len);
+        self.high_rxt = start.wrapping_add(len as u32);
+
+        true
+    }
+
+    /// Bytes the peer reported in SACK blocks.
+    fn sacked_bytes(&self) -> usize {
+        self.sacked.iter().map(|&(left, right)| right.wrapping_sub(left) as usize).sum()
+    }
+
+    /// Add the SACK blocks of the peer to the scoreboard (RFC 2018, Section 5).
+    fn update_scoreboard(&mut self, blocks: &[(u32, u32)]) {
+        for &(left, right) in blocks {
+            // only blocks about what we sent and is not acknowledged yet
+            if !seq_lt(left, right) || !seq_lt(self.snd_una, right) || seq_lt(self.snd_max, right) {
+                continue;
+            }
+
+            let left = if seq_lt(left, self.snd_una) { self.snd_una } else { left };
+            self.sacked.push((left, right));
+        }
+
+        merge_ranges(&mut self.sacked, self.snd_una);
+    }
+
+    /// The retransmission timer expired (RFC 6298, Section 5.4 to 5.6).
+    fn on_timeout(&mut self) {
+        self.retransmits += 1;
+        self.timer = None;
+
+        if self.retransmits > MAX_RETRANSMITS {
+            let message = match self.state {
+                State::SynSent => format!("Connection to {} timed out", self.remote),
+                _ => "Data not acknowledged".to_string(),
+            };
+            self.fail(ErrorKind::TimedOut, message);
+            return;
+        }
+
+        self.rto.backoff();
+
+        // SYN or SYN-ACK lost
+        if matches!(self.state, State::SynSent | State::SynReceived) {
+            self.send_syn();
+            return;
+        }
+
+        // closed window: probe it with a single byte
+        if self.snd_wnd == 0 {
+            if !self.unacked.is_empty() {
+                self.send_data(self.snd_una, 1);
+            }else{
+                self.retransmit();
+            }
+            return;
+        }
+
+        // loss: back to slow start (RFC 5681, Section 3.1)
+        let flight = self.snd_max.wrapping_sub(self.snd_una) as usize;
+        self.ssthresh = (flight / 2).max(2 * self.smss);
+        self.cwnd = self.smss;
+        self.dup_acks = 0;
+        self.in_recovery = false;
+        self.recover = self.snd_max;
+
+        // the peer may drop what it reported in SACK blocks (RFC 2018, Section 8)
+        self.sacked.clear();
+        self.high_rxt = self.snd_una;
+
+        // and send everything again, starting with the oldest segment
+        self.snd_nxt = self.snd_una;
+        self.retransmit();
+    }
+
+    /// An acknowledgment of new data arrived.
+    ///
+    /// ack: The acknowledgment number
+    /// echo_reply: The timestamp echo reply, if timestamps are negotiated
+    ///
+    fn on_new_ack(&mut self, ack: u32, echo_reply: Option<u32>) {
+        let acked = ack.wrapping_sub(self.snd_una) as usize;
+
+        // the FIN is not in the buffer
+        self.unacked.drain(..acked.min(self.unacked.len()));
+        self.snd_una = ack;
+        if seq_lt(self.snd_nxt, ack) {
+            self.snd_nxt = ack;
+        }
+
+        // forget what is acknowledged now
+        self.sacked.retain(|&(_, right)| seq_lt(ack, right));
+        if let Some(first) = self.sacked.first_mut() && seq_lt(first.0, ack) {
+            first.0 = ack;
+        }
+
+        self.retransmits = 0;
+        self.sample_rtt(ack, echo_reply);
+
+        if self.in_recovery {
+            if seq_le(self.recover, ack) {
+                // full acknowledgment: leave fast recovery (RFC 6582, Section 3.2, step 3)
+                let flight = self.snd_max.wrapping_sub(ack) as usize;
+                self.cwnd = self.ssthresh.min(flight.max(self.smss) + self.smss);
+                self.in_recovery = false;
+            }else{
+                // partial acknowledgment: the next segment was lost too (step 3, partial)
+                if !self.retransmit_hole() {
+                    self.retransmit();
+                }
+                self.cwnd = self.cwnd.saturating_sub(acked) + self.smss;
+            }
+        }else if self.cwnd < self.ssthresh {
+            // slow start
+            self.cwnd += acked.min(self.smss);
+        }else{
+            // congestion avoidance, about one MSS per round trip
+            self.cwnd += (self.smss * self.smss / self.cwnd).max(1);
+        }
+
+        self.dup_acks = 0;
+
+        // restart the timer for what is still in flight (RFC 6298, Section 5.2 and 5.3)
+        self.timer = if self.snd_una == self.snd_max { None } else { Some(Instant::now() + self.rto.rto) };
+    }
+
+    /// A duplicate acknowledgment arrived (RFC 6582, Section 3.2).
+    fn on_duplicate_ack(&mut self) {
+        self.dup_acks += 1;
+
+        if self.in_recovery {
+            // every duplicate means a segment left the network (step 4),
+            // and SACK may show the next hole to fill
+            self.cwnd += self.smss;
+            self.retransmit_hole();
+        }else if (self.dup_acks >= DUP_ACK_THRESHOLD || self.sacked_bytes() >= DUP_ACK_THRESHOLD as usize * self.smss) && seq_lt(self.recover, self.snd_una) {
+            // fast retransmit once three segments arrived after the oldest one (RFC 6675, Section 5),
+            // and fast recovery until everything sent so far is acknowledged (step 2)
+            let flight = self.snd_max.wrapping_sub(self.snd_una) as usize;
+            self.ssthresh = (flight / 2).max(2 * self.smss);
+            self.recover = self.snd_max;
+            self.in_recovery = true;
+
+            self.high_rxt = self.snd_una;
+            if !self.retransmit_hole() {
+                self.retransmit();
+            }
+            self.cwnd = self.ssthresh + DUP_ACK_THRESHOLD as usize * self.smss;
+        }
+    }
+
+    /// Take the SYN-ACK answering our SYN, with the options the peer agreed to.
+    fn process_syn_ack(&mut self, header: Header) {
+        self.rcv_nxt = header.seq.wrapping_add(1);
+        self.negotiate(&header.options);
+
+        // the window in a SYN is never scaled
+        let echo_reply = header.options.timestamps.filter(|_| self.timestamps).map(|(_, echo_reply)| echo_reply);
+        self.synchronized(header.ack, usize::from(header.window), echo_reply);
+
+        // complete the handshake
+        self.send_segment(self.snd_nxt, 0, &[]);
+    }
+
+    /// Process a segment of this connection (RFC 9293, Section 3.10.7).
+    fn process(&mut self, header: Header, payload: &[u8]) {
+        let (seq, ack, flags) = (header.seq, header.ack, header.flags);
+
+        if self.state == State::Closed {
+            return;
+        }
+
+        // waiting for SYN-ACK
+        if self.state == State::SynSent {
+            if flags & ACK != 0 && ack != self.snd_nxt {
+                return;
+            }
+
+            if flags & RST != 0 {
+                if flags & ACK != 0 {
+                    self.fail(ErrorKind::ConnectionRefused, format!("Connection to {} refused", self.remote));
+                }
+                return;
+            }
+
+            if flags & SYN != 0 && flags & ACK != 0 {
+                self.process_syn_ack(header);
+            }
+
+            return;
+        }
+
+        // reset, if in our window
+        if flags & RST != 0 {
+            if seq_le(self.rcv_nxt, seq) && seq_lt(seq, self.rcv_nxt.wrapping_add(self.advertised.max(1) as u32)) {
+                self.fail(ErrorKind::ConnectionReset, "Connection reset by peer".to_string());
+            }
+            return;
+        }
+
+        // waiting for the ACK of our SYN-ACK
+        if self.state == State::SynReceived {
+            // the SYN again: our SYN-ACK got lost
+            if flags & SYN != 0 {
+                self.send_syn();
+                return;
+            }
+
+            if flags & ACK == 0 {
+                return;
+            }
+
+            // acknowledges something else, the peer may be an old incarnation
+            if !seq_lt(self.snd_una, ack) || seq_lt(self.snd_max, ack) {
+                self.outbox.push(create_reset(self.local, self.remote, &header, payload.len()));
+                return;
+            }
+
+            let echo_reply = header.options.timestamps.filter(|_| self.timestamps).map(|(_, echo_reply)| echo_reply);
+            self.synchronized(ack, usize::from(header.window) << self.snd_wscale, echo_reply);
+        }
+
+        // timestamps: drop old duplicates (PAWS, RFC 7323, Section 5.3),
+        // and remember the value to echo (Section 4.3)
+        let mut echo_reply = None;
+        if self.timestamps && let Some((value, reply)) = header.options.timestamps {
+            if seq_lt(value, self.ts_recent) {
+                self.send_segment(self.snd_nxt, 0, &[]);
+                return;
+            }
+
+            if seq_le(seq, self.last_ack_sent) {
+                self.ts_recent = value;
+            }
+
+            echo_reply = Some(reply);
+        }
+
+        if flags & ACK != 0 {
+            if seq_lt(self.snd_max, ack) {
+                // acknowledges what we never sent, tell the peer where we are
+                self.send_segment(self.snd_nxt, 0, &[]);
+                return;
+            }
+
+            let window = usize::from(header.window) << self.snd_wscale;
+
+            if self.sack_permitted {
+                self.update_scoreboard(&header.options.sack);
+            }
+
+            if seq_lt(self.snd_una, ack) {
+                self.on_new_ack(ack, echo_reply);
+                self.snd_wnd = window;
+            }else if ack == self.snd_una {
+                // duplicate: no data, same window or SACK blocks, and we wait for an acknowledgment (RFC 5681 Section 2, RFC 6675 Section 2)
+                let same_window = window == self.snd_wnd || !header.options.sack.is_empty();
+                if payload.is_empty() && flags & (SYN | FIN) == 0 && same_window && self.snd_una != self.snd_max {
+                    self.on_duplicate_ack();
+                }
+
+                self.snd_wnd = window;
+            }
+
+            // our FIN acknowledged?
+            if let Some(fin_seq) = self.fin_seq && ack == fin_seq.wrapping_add(1) {
+                self.state = match self.state {
+                    State::FinWait1 => State::FinWait2,
+                    // TIME-WAIT is left immediately
+                    State::Closing | State::LastAck => State::Closed,
+                    state => state,
+                };
+            }
+        }
+
+        // data and FIN
+        if !payload.is_empty() || flags & FIN != 0 {
+            self.accept(seq, payload, flags & FIN != 0);
+
+            // acknowledge every segment, duplicates tell the peer about holes (RFC 5681, Section 4.2)
+            self.send_segment(self.snd_nxt, 0, &[]);
+        }
+    }
+
+    /// Take the data of a segment, or keep it in the reorder buffer if it
+    /// arrived before the ones in front of it.
+    fn accept(&mut self, seq: u32, payload: &[u8], fin: bool) {
+        if seq_lt(self.rcv_nxt, seq) {
+            // out of order: keep it, if in our window and not kept already
+            let window_end = self.rcv_nxt.wrapping_add(self.receive_space() as u32);
+            if seq_lt(seq, window_end) && !self.out_of_order.iter().any(|s| s.seq == seq) {
+                self.out_of_order.push(OutOfOrder { seq, data: payload.to_vec(), fin });
+            }
+
+            self.last_out_of_order = seq;
+            return;
+        }
+
+        self.take(seq, payload, fin);
+
+        // the hole may be filled now
+        while let Some(i) = self.out_of_order.iter().position(|s| seq_le(s.seq, self.rcv_nxt)) {
+            let segment = self.out_of_order.swap_remove(i);
+            self.take(segment.seq, &segment.data, segment.fin);
+        }
+    }
+
+    /// Take the new part of a segment starting at or before rcv_nxt.
+    fn take(&mut self, seq: u32, payload: &[u8], fin: bool) {
+        let end = seq.wrapping_add(payload.len() as u32);
+
+        // data of the peer ends with its FIN
+        if !matches!(self.state, State::Established | State::FinWait1 | State::FinWait2) {
+            return;
+        }
+
+        if seq_lt(self.rcv_nxt, end) {
+            // only as much as fits in the receive buffer
+            let new = &payload[self.rcv_nxt.wrapping_sub(seq) as usize..];
+            let len = new.len().min(self.receive_space());
+
+            self.received.extend(&new[..len]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+        }
+
+        if fin && self.rcv_nxt == end {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+
+            self.state = match self.state {
+                State::Established => State::CloseWait,
+                State::FinWait1 => State::Closing,
+                // TIME-WAIT is left immediately
+                State::FinWait2 => State::Closed,
+                state => state,
+            };
+        }
+    }
+
+    /// Close our direction: the FIN follows the data.
+    ///
+    /// Returns false if it is closed already.
+    ///
+    fn close(&mut self) -> bool {
+        match self.state {
+            State::Established => self.state = State::FinWait1,
+            State::CloseWait => self.state = State::LastAck,
+            _ => return false,
+        }
+
+        self.fin_seq = Some(self.snd_una.wrapping_add(self.unacked.len() as u32));
+        self.transmit();
+
+        true
+    }
+}
+
+/// Connections of a listening port not accepted yet.
+struct Listener {
+    /// Connections in the handshake (SYN queue)
+    syn_queue: Vec<Key>,
+    /// Established connections waiting for `accept` (accept queue)
+    accept_queue: VecDeque<Key>,
+}
+
+/// The TCP layer on top of the stack: the connections and listeners sharing it.
+///
+/// Whoever waits receives the segments of all connections and runs all
+/// their timers, so every connection keeps going while one is used.
+///
+struct Host {
+    /// The stack below
+    stack: Stack,
+    /// The connections, demultiplexed by our port and the address and port of the peer
+    connections: HashMap<Key, Connection>,
+    /// The listeners, by port
+    listeners: HashMap<u16, Listener>,
+    /// Secret key of the initial sequence numbers
+    isn_key: RandomState,
+    /// Start of the clock of the initial sequence numbers
+    isn_start: Instant,
+}
+
+impl Host {
+    /// The TCP layer on top of a stack, without connections yet.
+    fn new(stack: Stack) -> Rc<RefCell<Host>> {
+        Rc::new(RefCell::new(Host {
+            stack,
+            connections: HashMap::new(),
+            listeners: HashMap::new(),
+            isn_key: RandomState::new(),
+            isn_start: Instant::now(),
+        }))
+    }
+
+    /// The MSS we announce: the MTU of the interface less the headers.
+    fn mss(&self) -> usize {
+        self.stack.mtu.saturating_sub(HEADERS_LEN).clamp(DEFAULT_MSS, usize::from(u16::MAX))
+    }
+
+    /// Initial sequence number of a connection (RFC 6528, Section 3).
+    ///
+    /// A clock ticking every 4 microseconds plus a keyed hash of the
+    /// connection: hard to guess for others, and a new connection with the
+    /// same peer and ports starts after the sequence numbers of the old one.
+    ///
+    fn isn(&self, key: Key) -> u32 {
+        let clock = (self.isn_start.elapsed().as_micros() / 4) as u32;
+        clock.wrapping_add(self.isn_key.hash_one((self.stack.this_ip, key)) as u32)
+    }
+
+    /// The connection of a stream.
+    fn connection(&mut self, key: &Key) -> &mut Connection {
+        self.connections.get_mut(key).expect("Connection of a stream removed")
+    }
+
+    /// Open a connection: send our SYN (active open).
+    fn connect(&mut self, port: u16, remote: SocketAddrV4) {
+        let key = (port, remote);
+        let local = SocketAddrV4::new(self.stack.this_ip, port);
+
+        let mut connection = Connection::new(local, remote, self.isn(key), self.mss(), State::SynSent);
+        connection.send_syn();
+        self.connections.insert(key, connection);
+    }
+
+    /// Send the segments the connections queued.
+    fn flush(&mut self) -> io::Result<()> {
+        for connection in self.connections.values_mut() {
+            for segment in connection.outbox.drain(..) {
+                self.stack.send(*connection.remote.ip(), PROTOCOL_TCP, &segment)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the expired timers, then receive and process the next segment,
+    /// waiting no longer than the deadline or the next timer.
+    fn poll(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        let now = Instant::now();
+        for connection in self.connections.values_mut() {
+            if let Some(timer) = connection.timer && now >= timer {
+                connection.on_timeout();
+            }
+        }
+
+        self.clean_up();
+        self.flush()?;
+
+        // wait for a segment until the next timer or the deadline
+        let timer = self.connections.values().filter_map(|connection| connection.timer).min();
+        let until = match (deadline, timer) {
+            (Some(deadline), Some(timer)) => Some(deadline.min(timer)),
+            (deadline, timer) => deadline.or(timer),
+        };
+
+        if let Some((key, header, payload)) = self.receive(until)? {
+            self.dispatch(key, header, &payload)?;
+            self.clean_up();
+        }
+
+        self.flush()
+    }
+
+    /// Receive the next TCP segment sent to us.
+    ///
+    /// Returns None if the deadline passed before a segment arrived.
+    ///
+    fn receive(&mut self, deadline: Option<Instant>) -> io::Result<Option<(Key, Header, Vec<u8>)>> {
+        loop {
+            if let Some(deadline) = deadline && Instant::now() >= deadline {
+                return Ok(None);
+            }
+
+            let packet = match self.stack.recv() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                // interrupted by a signal, its handler decides what happens
+                Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            let Some(segment) = parse(&packet) else {
+                continue;
+            };
+
+            // copy out, the packet borrows the stack
+            let key = (segment.dest_port, SocketAddrV4::new(packet.src, segment.src_port));
+            return Ok(Some((key, segment.header, segment.payload.to_vec())));
+        }
+    }
+
+    /// Hand a segment to its connection, or to the listener of its port.
+    fn dispatch(&mut self, key: Key, header: Header, payload: &[u8]) -> io::Result<()> {
+        let Some(connection) = self.connections.get_mut(&key) else {
+            if self.listeners.contains_key(&key.0) {
+                return self.listen(key, header, payload.len());
+            }
+
+            // not ours
+            return Ok(());
+        };
+
+        let mut listener = self.listeners.get_mut(&key.0);
+
+        // accept queue full: drop the ACK completing the handshake, the peer sends it again
+        if connection.state == State::SynReceived && listener.as_ref().is_some_and(|l| l.accept_queue.len() >= ACCEPT_BACKLOG) {
+            return Ok(());
+        }
+
+        let before = connection.state;
+        connection.process(header, payload);
+        connection.transmit();
+
+        // handshake done: from the SYN queue into the accept queue
+        if before == State::SynReceived && connection.state != State::SynReceived && let Some(listener) = listener.as_mut() {
+            listener.syn_queue.retain(|&k| k != key);
+            if connection.state != State::Closed {
+                listener.accept_queue.push_back(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A segment to a listening port without connection (RFC 9293, Section 3.10.7.2).
+    ///
+    /// A SYN opens a connection, an ACK is answered with a reset.
+    ///
+    fn listen(&mut self, key: Key, header: Header, len: usize) -> io::Result<()> {
+        let (port, remote) = key;
+        let local = SocketAddrV4::new(self.stack.this_ip, port);
+
+        if header.flags & RST != 0 {
+            return Ok(());
+        }
+
+        if header.flags & ACK != 0 {
+            let reset = create_reset(local, remote, &header, len);
+            return self.stack.send(*remote.ip(), PROTOCOL_TCP, &reset);
+        }
+
+        if header.flags & SYN == 0 {
+            return Ok(());
+        }
+
+        // queues full: drop the SYN, the peer sends it again
+        let listener = &self.listeners[&port];
+        if listener.syn_queue.len() >= SYN_BACKLOG || listener.accept_queue.len() >= ACCEPT_BACKLOG {
+            return Ok(());
+        }
+
+        let mut connection = Connection::new(local, remote, self.isn(key), self.mss(), State::SynReceived);
+        connection.rcv_nxt = header.seq.wrapping_add(1);
+        connection.negotiate(&header.options);
+        connection.orphaned = Some(Instant::now());
+
+        // the window in a SYN is never scaled
+        connection.snd_wnd = usize::from(header.window);
+        connection.send_syn();
+
+        self.connections.insert(key, connection);
+        if let Some(listener) = self.listeners.get_mut(&port) {
+            listener.syn_queue.push(key);
+        }
+
+        Ok(())
+    }
+
+    /// Remove the connections no stream handles once they are gone.
+    fn clean_up(&mut self) {
+        let gone: Vec<Key> = self.connections.iter()
+            .filter(|(_, connection)| connection.gone())
+            .map(|(&key, _)| key)
+            .collect();
+
+        for key in gone {
+            self.connections.remove(&key);
+
+            if let Some(listener) = self.listeners.get_mut(&key.0) {
+                listener.syn_queue.retain(|&k| k != key);
+                listener.accept_queue.retain(|&k| k != key);
+            }
+        }
+    }
+}
+
+/// A TCP connection on top of the raw stack.
+///
+/// Works like `std::net::TcpStream`: connect (or accept with a
+/// `RawTcpListener`), then read and write. Data is sent in segments as
+/// large as the MSS of the peer and our MTU allow, as far as the window of
+/// the peer and the congestion window allow. `write` only blocks while the
+/// send buffer is full, `flush` waits until everything is acknowledged.
+///
+/// The SYN offers window scaling and timestamps (RFC 7323) and SACK
+/// (RFC 2018), the connection uses what the peer agrees to. Lost segments
+/// are retransmitted when the retransmission timer expires (RFC 6298) or
+/// after three duplicate acknowledgments, with NewReno congestion control
+/// (RFC 5681, RFC 6582) filling the holes the SACK blocks of the peer show.
+/// Segments arriving out of order are kept until the hole in front of them
+/// is filled, and reported to the peer in SACK blocks.
+///
+pub struct RawTcpStream {
+    /// The TCP layer, shared with the listener and its other connections
+    host: Rc<RefCell<Host>>,
+    /// Our port and the address and port of the peer
+    key: Key,
+    /// How long `read` waits, None to wait forever
+    read_timeout: Option<Duration>,
+}
+
+impl RawTcpStream {
+    /// Connect to a TCP server (three-way handshake).
+    ///
+    /// stack: The stack to send through
+    /// port: Our port
+    /// remote: Address and port of the server
+    ///
+    pub fn connect(stack: Stack, port: u16, remote: SocketAddrV4) -> io::Result<RawTcpStream> {
+        let host = Host::new(stack);
+        host.borrow_mut().connect(port, remote);
+
+        let stream = RawTcpStream { host, key: (port, remote), read_timeout: None };
+        stream.wait(None, |c| c.state != State::SynSent)?;
+
+        Ok(stream)
+    }
+
+    /// Our address and port.
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.host.borrow().stack.this_ip, self.key.0)
+    }
+
+    /// Address and port of the peer.
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.key.1
+    }
+
+    /// Set how long `read` waits for data, None to wait forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Receive and process segments and timeouts until `done` holds for our connection.
+    ///
+    /// Returns false if the deadline passed before.
+    ///
+    fn wait(&self, deadline: Option<Instant>, done: fn(&Connection) -> bool) -> io::Result<bool> {
+        let mut host = self.host.borrow_mut();
+
+        loop {
+            let connection = host.connection(&self.key);
+            if let Some(e) = connection.error() {
+                return Err(e);
+            }
+
+            if done(connection) {
+                return Ok(true);
+            }
+
+            if let Some(deadline) = deadline && Instant::now() >= deadline {
+                return Ok(false);
+            }
+
+            host.poll(deadline)?;
+        }
+    }
+
+    /// Close our direction of the connection (send FIN).
+    ///
+    /// Waits until all data and the FIN are acknowledged. Data of the peer
+    /// can still be read.
+    ///
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        {
+            let mut host = self.host.borrow_mut();
+            if !host.connection(&self.key).close() {
+                return Ok(());
+            }
+            host.flush()?;
+        }
+
+        self.wait(None, |c| !c.outstanding())?;
+        Ok(())
+    }
+}
+
+impl Read for RawTcpStream {
+    /// Read received data.
+    ///
+    /// Returns 0 once the peer closed its direction (sent FIN).
+    ///
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.host.borrow_mut().connection(&self.key).received.is_empty() {
+            let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+
+            // data, or FIN received
+            let done = self.wait(deadline, |c| !c.received.is_empty() || matches!(c.state, State::CloseWait | State::LastAck | State::Closing | State::Closed))?;
+            if !done {
+                return Err(io::Error::new(ErrorKind::TimedOut, "TCP read timed out"));
+            }
+        }
+
+        let mut host = self.host.borrow_mut();
+        let connection = host.connection(&self.key);
+
+        let len = buf.len().min(connection.received.len());
+        for (b, r) in buf.iter_mut().zip(connection.received.drain(..len)) {
+            *b = r;
+        }
+
+        // tell the peer about the room we made, once it is worth a segment (RFC 9293, Section 3.8.6.2.2)
+        if connection.receive_space() >= connection.advertised + connection.smss {
+            connection.send_segment(connection.snd_nxt, 0, &[]);
+            host.flush()?;
+        }
+
+        Ok(len)
+    }
+}
+
+impl Write for RawTcpStream {
+    /// Queue data for sending, waiting while the send buffer is full.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        {
+            let mut host = self.host.borrow_mut();
+            let connection = host.connection(&self.key);
+
+            if let Some(e) = connection.error() {
+                return Err(e);
+            }
+
+            if !matches!(connection.state, State::Established | State::CloseWait) {
+                return Err(io::Error::new(ErrorKind::NotConnected, "Connection closed for writing"));
+            }
+        }
+
+        self.wait(None, |c| c.unacked.len() < SEND_BUFFER)?;
+
+        let mut host = self.host.borrow_mut();
+        let connection = host.connection(&self.key);
+
+        let len = buf.len().min(SEND_BUFFER - connection.unacked.len());
+        connection.unacked.extend(&buf[..len]);
+        connection.transmit();
+        host.flush()?;
+
+        Ok(len)
+    }
+
+    /// Wait until everything written is acknowledged.
+    fn flush(&mut self) -> io::Result<()> {
+        self.wait(None, |c| c.unacked.is_empty())?;
+        Ok(())
+    }
+}
+
+impl Drop for RawTcpStream {
+    /// Send FIN if not done yet, without waiting for the acknowledgment.
+    ///
+    /// The connection closes while the listener or its other streams
+    /// wait. Data not acknowledged yet would be lost silently, so the
+    /// connection is reset instead then.
+    ///
+    fn drop(&mut self) {
+        let mut host = self.host.borrow_mut();
+        let Some(connection) = host.connections.get_mut(&self.key) else {
+            return;
+        };
+
+        if matches!(connection.state, State::Established | State::CloseWait) && !connection.unacked.is_empty() {
+            connection.send_segment(connection.snd_nxt, RST, &[]);
+            connection.state = State::Closed;
+        }else{
+            connection.close();
+        }
+
+        connection.orphaned = Some(Instant::now());
+        let _ = host.flush();
+        host.clean_up();
+    }
+}
+
+/// A TCP listener on top of the raw stack.
+///
+/// Works like `std::net::TcpListener`: bind to a port, then accept
+/// connections. SYNs are answered with SYN-ACK while `accept` or any stream
+/// of the listener waits, so several clients may connect at the same time,
+/// each demultiplexed by its address and port. Up to SYN_BACKLOG
+/// connections may be in the handshake and ACCEPT_BACKLOG established ones
+/// wait for `accept`, further SYNs are dropped so the peer tries again.
+///
+pub struct RawTcpListener {
+    /// The TCP layer, shared with the accepted streams
+    host: Rc<RefCell<Host>>,
+    /// Our port
+    port: u16,
+}
+
+impl RawTcpListener {
+    /// Listen on a port (passive open).
+    ///
+    /// stack: The stack to send through
+    /// port: Our port
+    ///
+    pub fn bind(stack: Stack, port: u16) -> RawTcpListener {
+        let host = Host::new(stack);
+        host.borrow_mut().listeners.insert(port, Listener { syn_queue: Vec::new(), accept_queue: VecDeque::new() });
+
+        RawTcpListener { host, port }
+    }
+
+    /// Our address and port.
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.host.borrow().stack.this_ip, self.port)
+    }
+
+    /// Wait for the next established connection.
+    ///
+    /// Returns the stream and the address and port of the peer.
+    ///
+    pub fn accept(&mut self) -> io::Result<(RawTcpStream, SocketAddrV4)> {
+        let mut host = self.host.borrow_mut();
+
+        loop {
+            let listener = host.listeners.get_mut(&self.port).expect("Listener removed");
+            if let Some(key) = listener.accept_queue.pop_front() {
+                host.connection(&key).orphaned = None;
+
+                let stream = RawTcpStream { host: self.host.clone(), key, read_timeout: None };
+                return Ok((stream, key.1));
+            }
+
+            host.poll(None)?;
+        }
+    }
+}
+
+impl Drop for RawTcpListener {
+    /// Stop listening, and reset the connections not accepted yet.
+    fn drop(&mut self) {
+        let mut host = self.host.borrow_mut();
+        let Some(listener) = host.listeners.remove(&self.port) else {
+            return;
+        };
+
+        for key in listener.syn_queue.iter().chain(&listener.accept_queue) {
+            if let Some(connection) = host.connections.get_mut(key) {
+                connection.send_segment(connection.snd_nxt, RST, &[]);
+                connection.state = State::Closed;
+            }
+        }
+
+        let _ = host.flush();
+        host.clean_up();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connection after the handshake: we start at 1001, the peer at 5001,
+    /// MSS 1000 without timestamps, SACK permitted, a window of 65535.
+    fn established() -> Connection {
+        let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 40000);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80);
+
+        let mut connection = Connection::new(local, remote, 1000, 1460, State::SynSent);
+        connection.send_syn();
+        connection.process(Header {
+            seq: 5000,
+            ack: 1001,
+            flags: SYN | ACK,
+            window: u16::MAX,
+            options: Options { mss: Some(1000), sack_permitted: true, ..Options::default() },
+        }, &[]);
+
+        // no measurement of the handshake
+        connection.rto = RtoEstimator::new();
+        connection.outbox.clear();
+        connection
+    }
+
+    /// An acknowledgment from the peer, with SACK blocks.
+    fn ack(ack: u32, sack: &[(u32, u32)]) -> Header {
+        Header { seq: 5001, ack, flags: ACK, window: u16::MAX, options: Options { sack: sack.to_vec(), ..Options::default() } }
+    }
+
+    /// Sequence numbers of the segments sent since the last call.
+    fn sent(connection: &mut Connection) -> Vec<u32> {
+        connection.outbox.drain(..).map(|segment| u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]])).collect()
+    }
+
+    #[test]
+    fn dropped_connections_gone() {
+        let mut connection = established();
+        connection.orphaned = Some(Instant::now());
+        assert!(!connection.gone());
+
+        connection.state = State::Closed;
+        assert!(connection.gone());
+
+        // dropped while connecting, but not a handshake of a listener
+        connection.state = State::SynSent;
+        assert!(connection.gone());
+        connection.state = State::SynReceived;
+        assert!(!connection.gone());
+    }
+
+    #[test]
+    fn rto_first_and_later_samples() {
+        let mut rto = RtoEstimator::new();
+        assert_eq!(rto.rto, INITIAL_RTO);
+
+        // SRTT = R, RTTVAR = R/2, RTO = SRTT + 4 RTTVAR
+        rto.sample(Duration::from_secs(2));
+        assert_eq!(rto.srtt, Some(Duration::from_secs(2)));
+        assert_eq!(rto.rttvar, Duration::from_secs(1));
+        assert_eq!(rto.rto, Duration::from_secs(6));
+
+        // RTTVAR = 3/4 RTTVAR + 1/4 |SRTT - R|, SRTT = 7/8 SRTT + 1/8 R
+        rto.sample(Duration::from_secs(1));
+        assert_eq!(rto.rttvar, Duration::from_secs(1));
+        assert_eq!(rto.srtt, Some(Duration::from_millis(1875)));
+        assert_eq!(rto.rto, Duration::from_millis(5875));
+    }
+
+    #[test]
+    fn rto_minimum_and_backoff() {
+        let mut rto = RtoEstimator::new();
+        rto.sample(Duration::from_millis(10));
+        assert_eq!(rto.rto, MIN_RTO);
+
+        rto.backoff();
+        assert_eq!(rto.rto, Duration::from_secs(2));
+        rto.backoff();
+        assert_eq!(rto.rto, Duration::from_secs(4));
+
+        for _ in 0..10 {
+            rto.backoff();
+        }
+        assert_eq!(rto.rto, MAX_RTO);
+    }
+
+    #[test]
+    fn round_trip_timed() {
+        let mut connection = established();
+        connection.unacked.extend([0; 1000]);
+        connection.transmit();
+        assert_eq!(sent(&mut connection), [1001]);
+
+        connection.process(ack(2001, &[]), &[]);
+        assert!(connection.rto.srtt.is_some());
+    }
+
+    #[test]
+    fn karn_ignores_retransmissions() {
+        let mut connection = established();
+        connection.unacked.extend([0; 1000]);
+        connection.transmit();
+
+        connection.on_timeout();
+        assert_eq!(sent(&mut connection), [1001, 1001]);
+        assert!(connection.timed.is_none());
+
+        // the acknowledgment may be for either, so it is no measurement
+        connection.process(ack(2001, &[]), &[]);
+        assert!(connection.rto.srtt.is_none());
+        assert_eq!(connection.rto.rto, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn zero_echo_reply_ignored() {
+        let mut connection = established();
+        connection.sample_rtt(1001, Some(0));
+        assert!(connection.rto.srtt.is_none());
+    }
+
+    #[test]
+    fn fast_retransmit_and_newreno_partial_ack() {
+        let mut connection = established();
+        connection.unacked.extend([0; 4000]);
+        connection.transmit();
+        assert_eq!(sent(&mut connection), [1001, 2001, 3001, 4001]);
+
+        // the first segment is lost, the three after it arrive
+        connection.process(ack(1001, &[]), &[]);
+        connection.process(ack(1001, &[]), &[]);
+        assert!(!connection.in_recovery);
+        assert!(sent(&mut connection).is_empty());
+
+        connection.process(ack(1001, &[]), &[]);
+        assert!(connection.in_recovery);
+        assert_eq!(sent(&mut connection), [1001]);
+        assert_eq!(connection.ssthresh, 2000);
+        assert_eq!(connection.cwnd, 2000 + 3 * 1000);
+        assert_eq!(connection.recover, 5001);
+
+        // partial acknowledgment: the second segment was lost too
+        connection.process(ack(2001, &[]), &[]);
+        assert!(connection.in_recovery);
+        assert_eq!(sent(&mut connection), [2001]);
+
+        // full acknowledgment: recovery is over
+        connection.process(ack(5001, &[]), &[]);
+        assert!(!connection.in_recovery);
+        assert_eq!(connection.cwnd, 2000);
+    }
+
+    #[test]
+    fn sack_holes_retransmitted() {
+        let mut connection = established();
+        connection.cwnd = 10000;
+        connection.unacked.extend([0; 5000]);
+        connection.transmit();
+        assert_eq!(sent(&mut connection), [1001, 2001, 3001, 4001, 5001]);
+
+        // the first and the third segment are lost
+        connection.process(ack(1001, &[(2001, 3001)]), &[]);
+        connection.process(ack(1001, &[(4001, 5001), (2001, 3001)]), &[]);
+        connection.process(ack(1001, &[(4001, 6001), (2001, 3001)]), &[]);
+        assert_eq!(connection.sacked, [(2001, 3001), (4001, 6001)]);
+        assert_eq!(sent(&mut connection), [1001]);
+
+        // the next duplicate fills the next hole, then there is none
+        connection.process(ack(1001, &[(4001, 6001), (2001, 3001)]), &[]);
+        assert_eq!(sent(&mut connection), [3001]);
+        connection.process(ack(1001, &[(4001, 6001), (2001, 3001)]), &[]);
+        assert!(sent(&mut connection).is_empty());
+    }
+
+    #[test]
+    fn ranges_merged() {
+        let mut ranges = vec![(300, 400), (100, 200), (200, 250), (150, 180), (500, 600)];
+        merge_ranges(&mut ranges, 0);
+        assert_eq!(ranges, [(100, 250), (300, 400), (500, 600)]);
+
+        // ordered from the base, across the wrap around
+        let mut ranges = vec![(10, 20), (5, 10), (u32::MAX - 50, 5)];
+        merge_ranges(&mut ranges, u32::MAX - 100);
+        assert_eq!(ranges, [(u32::MAX - 50, 20)]);
+    }
+
+    #[test]
+    fn reorder_buffer_delivers_in_order() {
+        let mut connection = established();
+        let data = |seq: u32| Header { seq, ack: 1001, flags: ACK | PSH, window: u16::MAX, options: Options::default() };
+
+        // the third and the second segment arrive before the first
+        connection.process(data(5011), b"third");
+        connection.process(data(5006), b"world");
+        assert!(connection.received.is_empty());
+        assert_eq!(connection.rcv_nxt, 5001);
+        assert_eq!(connection.sack_blocks(), [(5006, 5016)]);
+
+        connection.process(data(5001), b"hello");
+        assert_eq!(connection.received.iter().copied().collect::<Vec<u8>>(), b"helloworldthird");
+        assert_eq!(connection.rcv_nxt, 5016);
+        assert!(connection.out_of_order.is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+// Firewall guard (keeps the kernel from answering our raw traffic)
+use std::{io, net::Ipv6Addr, panic, process::{self, Command}, sync::{Mutex, Once}};
+
+use crate::port::Protocol;
+
+/// Firewall commands to guard a port, and to remove the guard again.
+pub struct Rules {
+    /// Commands installing the rules
+    apply: Vec<Vec<String>>,
+    /// Commands removing the rules
+    remove: Vec<Vec<String>>,
+}
+
+/// The commands removing the installed rules, so they can be run from
+/// the signal handler and panics as well.
+static INSTALLED: Mutex<Option<Vec<Vec<String>>>> = Mutex::new(None);
+
+/// Sets up the removal on signals and panics with the first rules.
+static HANDLERS: Once = Once::new();
+
+/// Check if a command is available.
+fn available(command: &str) -> bool {
+    Command::new(command).arg("--version").output().is_ok_and(|output| output.status.success())
+}
+
+/// Build a command line from its parts.
+fn command(parts: &[&str]) -> Vec<String> {
+    parts.iter().map(|p| p.to_string()).collect()
+}
+
+/// Run a command, failing if it does not exit successfully.
+fn run(command: &[String]) -> io::Result<()> {
+    let output = Command::new(&command[0]).args(&command[1..]).output()?;
+
+    if output.status.success() {
+        Ok(())
+    }else{
+        Err(io::Error::other(format!("'{}' failed: {}", command.join(" "), String::from_utf8_lossy(&output.stderr).trim())))
+    }
+}
+
+/// The firewall the rules are made for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// nftables, with an own table
+    Nft,
+    /// ip6tables, as fallback
+    Iptables,
+}
+
+/// Find the firewall to use: nftables, or else ip6tables.
+pub fn detect() -> Option<Backend> {
+    if available("nft") {
+        Some(Backend::Nft)
+    }else if available("ip6tables") {
+        Some(Backend::Iptables)
+    }else{
+        None
+    }
+}
+
+/// Get the rules dropping the packets to our port before the kernel sees them.
+///
+/// Our raw socket receives the frames before netfilter runs, so we still
+/// get them, but the kernel does not answer with port unreachable.
+///
+/// backend: The firewall to use
+/// ip: Our IPv6 address
+/// protocol: The transport protocol
+/// port: Our port
+///
+pub fn rules(backend: Backend, ip: Ipv6Addr, protocol: Protocol, port: u16) -> Rules {
+    let ip = ip.to_string();
+    let port = port.to_string();
+    let protocol = protocol.name();
+
+    match backend {
+        Backend::Nft => {
+            // an own table per process, removing it removes everything
+            let table = format!("raw_networking_demo_{}", process::id());
+
+            Rules {
+                apply: vec![
+                    command(&["nft", "add", "table", "ip6", &table]),
+                    command(&["nft", "add", "chain", "ip6", &table, "input", "{ type filter hook input priority 0; policy accept; }"]),
+                    command(&["nft", "add", "rule", "ip6", &table, "input", "ip6", "daddr", &ip, protocol, "dport", &port, "drop"]),
+                ],
+                remove: vec![
+                    command(&["nft", "delete", "table", "ip6", &table]),
+                ],
+            }
+        },
+        Backend::Iptables => {
+            // insert on top, and delete the very same rule later
+            Rules {
+                apply: vec![
+                    command(&["ip6tables", "-I", "INPUT", "-d", &ip, "-p", protocol, "--dport", &port, "-j", "DROP"]),
+                ],
+                remove: vec![
+                    command(&["ip6tables", "-D", "INPUT", "-d", &ip, "-p", protocol, "--dport", &port, "-j", "DROP"]),
+                ],
+            }
+        },
+    }
+}
+
+impl Rules {
+    /// Print the commands instead of running them.
+    pub fn print(&self) {
+        println!("Would install the firewall rules with:");
+        for command in &self.apply {
+            println!("  {}", command.join(" "));
+        }
+
+        println!("and remove them again with:");
+        for command in &self.remove {
+            println!("  {}", command.join(" "));
+        }
+    }
+}
+
+/// Install the rules.
+///
+/// They are removed again by `remove`, which also runs on SIGINT, SIGTERM
+/// and panics. Call it before exiting. Rules installed later, e.g. for a
+/// second port, are removed with the first ones.
+///
+pub fn install(rules: Rules) -> io::Result<()> {
+    for command in &rules.apply {
+        if let Err(e) = run(command) {
+            // undo what is already installed
+            for command in &rules.remove {
+                let _ = run(command);
+            }
+
+            return Err(e);
+        }
+    }
+
+    // the nft table is the same for all ports, delete it once
+    {
+        let mut installed = INSTALLED.lock().unwrap_or_else(|e| e.into_inner());
+        let installed = installed.get_or_insert_with(Vec::new);
+        for command in rules.remove {
+            if !installed.contains(&command) {
+                installed.push(command);
+            }
+        }
+    }
+
+    // there is only one signal handler, install it once
+    let mut result = Ok(());
+    HANDLERS.call_once(|| {
+        // remove on SIGINT and SIGTERM
+        result = ctrlc::set_handler(|| {
+            remove();
+            println!("Interrupted.");
+            process::exit(130);
+        }).map_err(io::Error::other);
+
+        // and on panics
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            remove();
+            hook(info);
+        }));
+    });
+
+    result
+}
+
+/// Remove the installed rules, if any.
+pub fn remove() {
+    let Some(commands) = INSTALLED.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+
+    for command in &commands {
+        if let Err(e) = run(command) {
+            println!("Could not remove firewall rule: {}", e);
+        }
+    }
+}
@@ -0,0 +1,635 @@
+// IP layer
+use std::{collections::HashMap, hash::{BuildHasher, RandomState}, io::{self, ErrorKind}, net::{Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs}};
+
+use pnet::datalink::{DataLinkReceiver, DataLinkSender};
+
+/// How the flow label of the IPv6 header is chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowLabel {
+    /// Always use the given label (0 means unlabeled)
+    Fixed(u32),
+    /// Derive the label from a hash over the flow (RFC 6437, Section 3)
+    Auto,
+}
+
+/// Per-packet options for the IPv6 header.
+///
+/// The defaults match what this demo always sent: hop limit 60,
+/// traffic class 0 and flow label 0.
+///
+#[derive(Clone, Debug)]
+pub struct SendOptions {
+    /// Hop limit
+    pub hop_limit: u8,
+    /// Differentiated services code point (6 bit, RFC 2474)
+    pub dscp: u8,
+    /// Explicit congestion notification (2 bit, RFC 3168)
+    pub ecn: u8,
+    /// How to choose the flow label
+    pub flow_label: FlowLabel,
+    // secret key for the flow label hash
+    flow_key: RandomState,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        SendOptions {
+            hop_limit: 60,
+            dscp: 0,
+            ecn: 0,
+            flow_label: FlowLabel::Fixed(0),
+            flow_key: RandomState::new(),
+        }
+    }
+}
+
+impl SendOptions {
+    /// The traffic class byte (DSCP + ECN).
+    pub fn traffic_class(&self) -> u8 {
+        (self.dscp << 2) | (self.ecn & 0x03)
+    }
+
+    /// Returns the 20 bit flow label for a flow.
+    ///
+    /// In auto mode this is a keyed hash over source and destination address,
+    /// next header and ports, so it is constant for a flow but not predictable
+    /// from the outside (RFC 6437, Section 3 and Appendix A).
+    ///
+    pub fn flow_label(&self, src: &Ipv6Addr, dest: &Ipv6Addr, next_header: u8, src_port: u16, dest_port: u16) -> u32 {
+        match self.flow_label {
+            FlowLabel::Fixed(label) => label & 0xfffff,
+            FlowLabel::Auto => {
+                let hash = self.flow_key.hash_one((src, dest, next_header, src_port, dest_port));
+                let label = (hash as u32 ^ (hash >> 32) as u32) & 0xfffff;
+
+                // 0 means unlabeled, so never use it
+                if label == 0 { 1 } else { label }
+            },
+        }
+    }
+}
+
+/// Resolve `host[:port]` to the first IPv6 address of the host. Addresses
+/// with port are written in brackets, `[2001:db8::1]:123`.
+///
+/// default_port: The port if left out
+///
+pub fn resolve(server: &str, default_port: u16) -> io::Result<SocketAddrV6> {
+    if let Ok(addr) = server.parse::<SocketAddrV6>() {
+        return Ok(addr);
+    }
+
+    // an address without port, with or without brackets
+    if let Ok(ip) = server.trim_start_matches('[').trim_end_matches(']').parse::<Ipv6Addr>() {
+        return Ok(SocketAddrV6::new(ip, default_port, 0, 0));
+    }
+
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("Invalid port '{}'", port)))?),
+        None => (server, default_port),
+    };
+
+    (host, port).to_socket_addrs()?
+        .find_map(|addr| match addr {
+            SocketAddr::V6(addr) => Some(addr),
+            SocketAddr::V4(_) => None,
+        })
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("No IPv6 address for {}", host)))
+}
+
+/// Parse a DSCP given either as a number (0-63) or as a name
+/// like EF, CS6, AF41 or BE (RFC 2474, RFC 2597, RFC 3246).
+pub fn parse_dscp(s: &str) -> Option<u8> {
+    let s = s.to_ascii_uppercase();
+
+    let dscp = if let Ok(n) = s.parse::<u8>() {
+        n
+    }else if s == "EF" {
+        46
+    }else if s == "BE" || s == "DF" {
+        0
+    }else if let Some(class) = s.strip_prefix("CS") {
+        let class: u8 = class.parse().ok()?;
+        if class > 7 {
+            return None;
+        }
+        class << 3
+    }else if let Some(af) = s.strip_prefix("AF") {
+        // AFxy: class x (1-4), drop precedence y (1-3)
+        let digits: Vec<u8> = af.bytes().map(|b| b.wrapping_sub(b'0')).collect();
+        if digits.len() != 2 || !(1..=4).contains(&digits[0]) || !(1..=3).contains(&digits[1]) {
+            return None;
+        }
+        (digits[0] << 3) | (digits[1] << 1)
+    }else{
+        return None;
+    };
+
+    if dscp < 64 {
+        Some(dscp)
+    }else{
+        None
+    }
+}
+
+/// IPv6 next header number of TCP
+pub const NEXT_HEADER_TCP: u8 = 6;
+
+/// IPv6 next header number of UDP
+pub const NEXT_HEADER_UDP: u8 = 17;
+
+/// IPv6 next header number of ICMPv6
+pub const NEXT_HEADER_ICMPV6: u8 = 58;
+
+/// ICMPv6 type Destination Unreachable
+pub const ICMPV6_DESTINATION_UNREACHABLE: u8 = 1;
+
+/// ICMPv6 type Time Exceeded
+pub const ICMPV6_TIME_EXCEEDED: u8 = 3;
+
+/// ICMPv6 type Echo Request
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+
+/// ICMPv6 type Echo Reply
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// ICMPv6 type Router Solicitation
+pub const ICMPV6_ROUTER_SOLICITATION: u8 = 133;
+
+/// Push the IPv6 header with payload length 0.
+///
+/// Returns the index of the payload length field, to be set with
+/// `set_payload_length` once the payload is pushed.
+///
+fn push_header(data: &mut Vec<u8>, src: &Ipv6Addr, dest: &Ipv6Addr, next_header: u8, hop_limit: u8, traffic_class: u8, flow_label: u32) -> usize {
+    // push version 6, traffic class, flow label
+    data.extend_from_slice(&((6u32 << 28) | (u32::from(traffic_class) << 20) | (flow_label & 0xfffff)).to_be_bytes());
+
+    // store payload length index for later use
+    let plen_idx = data.len();
+
+    // push for now payload length 0, next header, hop limit
+    data.extend_from_slice(&[0, 0, next_header, hop_limit]);
+
+    // source address octets
+    data.extend_from_slice(&src.octets());
+
+    // dest address octets
+    data.extend_from_slice(&dest.octets());
+
+    plen_idx
+}
+
+/// Set the payload length of an IPv6 header pushed by `push_header`.
+fn set_payload_length(data: &mut [u8], plen_idx: usize) {
+    // compute length
+    let len = u16::try_from(data[{plen_idx+36}..].len()).expect("IPv6 payload too long.").to_be_bytes();
+
+    data[plen_idx] = len[0];
+    data[plen_idx+1] = len[1];
+}
+
+/// Creates an IPv6 packet with arbitrary payload.
+///
+/// next_header: The protocol of the payload (e.g. NEXT_HEADER_UDP)
+/// payload: The transport layer packet, including its checksum
+///
+pub fn create_packet(data: &mut Vec<u8>, src: &Ipv6Addr, dest: &Ipv6Addr, next_header: u8, payload: &[u8], options: &SendOptions) {
+    // the ports are part of the flow
+    let (src_port, dest_port) = if (next_header == NEXT_HEADER_UDP || next_header == NEXT_HEADER_TCP) && payload.len() >= 4 {
+        (u16::from_be_bytes([payload[0], payload[1]]), u16::from_be_bytes([payload[2], payload[3]]))
+    }else{
+        (0, 0)
+    };
+
+    let flow = options.flow_label(src, dest, next_header, src_port, dest_port);
+    let plen_idx = push_header(data, src, dest, next_header, options.hop_limit, options.traffic_class(), flow);
+
+    data.extend_from_slice(payload);
+
+    set_payload_length(data, plen_idx);
+}
+
+/// A received IPv6 packet.
+pub struct Ipv6Packet<'a> {
+    /// Source address
+    pub src: Ipv6Addr,
+    /// Destination address
+    pub dest: Ipv6Addr,
+    /// Next header (protocol of the payload)
+    pub next_header: u8,
+    /// Hop limit left on arrival
+    pub hop_limit: u8,
+    /// The payload, without any ethernet padding
+    pub payload: &'a [u8],
+}
+
+/// Parse an IPv6 packet.
+///
+/// Extension headers are not supported, the payload is whatever the
+/// fixed header announces as next header.
+///
+/// Returns None if this is not a valid IPv6 packet.
+///
+pub fn parse(frame: &[u8]) -> Option<Ipv6Packet<'_>> {
+    // check version and length
+    if frame.len() < 40 || frame[0] >> 4 != 6 {
+        return None;
+    }
+
+    let payload_len = u16::from_be_bytes([frame[4], frame[5]]) as usize;
+    if 40 + payload_len > frame.len() {
+        return None;
+    }
+
+    Some(Ipv6Packet {
+        src: Ipv6Addr::from(<[u8; 16]>::try_from(&frame[8..24]).unwrap()),
+        dest: Ipv6Addr::from(<[u8; 16]>::try_from(&frame[24..40]).unwrap()),
+        next_header: frame[6],
+        hop_limit: frame[7],
+        payload: &frame[40..{40+payload_len}],
+    })
+}
+
+/// The raw stack up to the IP layer: the ethernet channel, our addresses
+/// and the gateway every packet goes through, but those to the neighbors we answer.
+///
+/// Transport layer sockets are built on top of it.
+///
+pub struct Stack {
+    /// Sending half of the ethernet channel
+    pub tx: Box<dyn DataLinkSender>,
+    /// Receiving half of the ethernet channel, with a short read timeout
+    pub rx: Box<dyn DataLinkReceiver>,
+    /// Our MAC address
+    pub this_mac: [u8; 6],
+    /// Our global IPv6 address
+    pub this_ip: Ipv6Addr,
+    /// The MAC address of the router
+    pub gateway_mac: [u8; 6],
+    /// MTU of the interface
+    pub mtu: usize,
+    /// Options for the IPv6 header of each packet we send
+    pub send_options: SendOptions,
+    /// The MAC addresses of hosts on our link that sent to us directly, if we
+    /// accept them (server mode), else None and only the router is heard
+    pub neighbors: Option<HashMap<Ipv6Addr, [u8; 6]>>,
+}
+
+impl Stack {
+    /// Send an IPv6 packet through the router, or straight to a neighbor.
+    ///
+    /// dest: The destination IPv6 address
+    /// next_header: The protocol of the payload
+    /// payload: The transport layer packet, including its checksum
+    ///
+    pub fn send(&mut self, dest: &Ipv6Addr, next_header: u8, payload: &[u8]) -> io::Result<()> {
+        // build bottom-up: payload in IPv6 in ethernet
+        let mut frame = Vec::new();
+        crate::link::push_ethernet_header(&mut frame, &self.this_mac, self.next_hop(dest));
+        create_packet(&mut frame, &self.this_ip, dest, next_header, payload, &self.send_options);
+
+        match self.tx.send_to(&frame, None) {
+            Some(result) => result,
+            None => Err(io::Error::other("Could not send ethernet frame")),
+        }
+    }
+
+    /// Receive the next IPv6 packet the router, or a neighbor, sends to us.
+    ///
+    /// Returns None for any other frame, and an error of kind TimedOut if
+    /// nothing arrived within the read timeout of the channel.
+    ///
+    pub fn recv(&mut self) -> io::Result<Option<Ipv6Packet<'_>>> {
+        let frame = self.rx.next()?;
+
+        // check source: the router, or a neighbor if we accept them
+        if frame.len() < 14 {
+            return Ok(None);
+        }
+        let source = <[u8; 6]>::try_from(&frame[6..12]).unwrap();
+        if source != self.gateway_mac && self.neighbors.is_none() {
+            return Ok(None);
+        }
+
+        let Some(packet) = crate::link::unwrap_ip_packet(frame, &self.this_mac).and_then(parse) else {
+            return Ok(None);
+        };
+
+        if packet.dest != self.this_ip {
+            return Ok(None);
+        }
+
+        // remember the neighbors, through the router means not on our link
+        if let Some(neighbors) = &mut self.neighbors {
+            if source == self.gateway_mac {
+                neighbors.remove(&packet.src);
+            }else{
+                neighbors.insert(packet.src, source);
+            }
+        }
+
+        Ok(Some(packet))
+    }
+
+    /// The MAC address to send to dest: the neighbor itself if it sent to us
+    /// directly, else the router.
+    ///
+    fn next_hop(&self, dest: &Ipv6Addr) -> &[u8; 6] {
+        self.neighbors.as_ref().and_then(|neighbors| neighbors.get(dest)).unwrap_or(&self.gateway_mac)
+    }
+}
+
+/// Check if is ICMPv6 neighbor advertisement from the router
+/// 
+/// Returns true, if this is a router, and the link layer should use the MAC for futher communication.
+/// 
+pub fn icmpv6_check_neighbor(frame: &[u8]) -> bool {
+    // Check if ICMPv6
+    if frame[6] != NEXT_HEADER_ICMPV6 {
+        return false;
+    }
+
+    // Check if router advertisement
+    if frame[40] == 134 {
+        return true;
+
+    // if is not neighbor advertisement, return false
+    }else if frame[40] != 136 {
+        return false;
+    }
+
+    // if is neighbor advertisement, check if router bit set
+    frame[44] & 0x80 != 0
+}
+
+/// Compute the ICMPv6 checksum of a message.
+///
+/// The checksum field is expected to be zero.
+///
+pub fn icmpv6_checksum(src: &Ipv6Addr, dest: &Ipv6Addr, message: &[u8]) -> u16 {
+    // Calculate checksum (RFC 2460, Section 8.1)
+    let mut check = Vec::new();
+
+    // IPv6 pseodo header (RFC 2460, Section 8.1)
+    // Source IPv6 address
+    check.extend_from_slice(&src.octets());
+
+    // Destination IPv6 address
+    check.extend_from_slice(&dest.octets());
+
+    // u32 ICMPv6 message length
+    check.extend_from_slice(&(message.len() as u32).to_be_bytes());
+
+    // 3x zero + ICMPv6 type (58)
+    check.extend_from_slice(&[0,0,0,NEXT_HEADER_ICMPV6]);
+
+    // push payload
+    check.extend_from_slice(message);
+
+    // padd
+    if check.len() % 2 == 1 {
+        check.push(0x00);
+    }
+
+    // calculate checksum
+    compute_checksum(&check)
+}
+
+/// Create a ICMPv6 packet.
+///
+/// Pushes the IPv6 header and the ICMPv6 message, and computes the checksum.
+///
+/// icmp_type: The ICMPv6 message type
+/// code: The ICMPv6 message code
+/// body: The message body following the checksum
+///
+pub fn icmpv6_create_message(data: &mut Vec<u8>, src: &Ipv6Addr, dest: &Ipv6Addr, icmp_type: u8, code: u8, body: &[u8], options: &SendOptions) {
+    let flow = options.flow_label(src, dest, NEXT_HEADER_ICMPV6, 0, 0);
+    let plen_idx = push_header(data, src, dest, NEXT_HEADER_ICMPV6, options.hop_limit, options.traffic_class(), flow);
+
+    // Now the ICMPv6 payload
+    // keep track of current index for later checksum calculation
+    let idx_icmpv6_begin = data.len();
+
+    // type, code, checksum 0 for now
+    data.extend_from_slice(&[icmp_type, code, 0, 0]);
+
+    // message body
+    data.extend_from_slice(body);
+
+    // Set ICMPv6 checksum
+    let check = icmpv6_checksum(src, dest, &data[idx_icmpv6_begin..]).to_be_bytes();
+    data[idx_icmpv6_begin+2] = check[0];
+    data[idx_icmpv6_begin+3] = check[1];
+
+    // Set IPv6 length
+    set_payload_length(data, plen_idx);
+}
+
+/// Create a ICMPv6 router solicitation packet.
+/// 
+/// This packet is required for discover the gateway.
+/// 
+pub fn icmpv6_create_router_solicitation(data: &mut Vec<u8>, our_ip: &Ipv6Addr) {
+    // Hop limit must be 255 for neighbor discovery (RFC 4861, Section 6.1.1)
+    let options = SendOptions {
+        hop_limit: 255,
+        ..Default::default()
+    };
+
+    // to all router link local multicast, 4 bytes reserved=0
+    icmpv6_create_message(data, our_ip, &"ff02::2".parse::<Ipv6Addr>().unwrap(), ICMPV6_ROUTER_SOLICITATION, 0, &[0; 4], &options);
+}
+
+/// Create a ICMPv6 Echo Request packet (RFC 4443, Section 4.1).
+///
+/// identifier: Identifies our ping session
+/// sequence: The sequence number of this request
+/// payload: Arbitrary data, echoed back by the target
+///
+pub fn icmpv6_create_echo_request(data: &mut Vec<u8>, src: &Ipv6Addr, dest: &Ipv6Addr, identifier: u16, sequence: u16, payload: &[u8], options: &SendOptions) {
+    let mut body = Vec::new();
+    body.extend_from_slice(&identifier.to_be_bytes());
+    body.extend_from_slice(&sequence.to_be_bytes());
+    body.extend_from_slice(payload);
+
+    icmpv6_create_message(data, src, dest, ICMPV6_ECHO_REQUEST, 0, &body, options);
+}
+
+/// A received ICMPv6 Echo Reply.
+pub struct EchoReply<'a> {
+    /// Identifier copied from the request
+    pub identifier: u16,
+    /// Sequence number copied from the request
+    pub sequence: u16,
+    /// Data copied from the request
+    pub payload: &'a [u8],
+}
+
+/// Unpack a ICMPv6 Echo Reply (RFC 4443, Section 4.2).
+///
+/// Returns None if the packet is not a valid echo reply.
+///
+pub fn icmpv6_unpack_echo_reply<'a>(packet: &Ipv6Packet<'a>) -> Option<EchoReply<'a>> {
+    let message = packet.payload;
+
+    if packet.next_header != NEXT_HEADER_ICMPV6 || message.len() < 8 || message[0] != ICMPV6_ECHO_REPLY || message[1] != 0 {
+        return None;
+    }
+
+    // verify checksum
+    let mut check = message.to_vec();
+    check[2] = 0;
+    check[3] = 0;
+
+    if icmpv6_checksum(&packet.src, &packet.dest, &check).to_be_bytes() != message[2..4] {
+        println!("Received corrupted ICMPv6 message: Dropping it.");
+        return None;
+    }
+
+    Some(EchoReply {
+        identifier: u16::from_be_bytes([message[4], message[5]]),
+        sequence: u16::from_be_bytes([message[6], message[7]]),
+        payload: &message[8..],
+    })
+}
+
+/// The start of the packet quoted in an ICMPv6 error message.
+pub struct Quoted<'a> {
+    /// Source address of the original packet
+    pub src: Ipv6Addr,
+    /// Destination address of the original packet
+    pub dest: Ipv6Addr,
+    /// Next header of the original packet
+    pub next_header: u8,
+    /// The first bytes (at least 8) of the original transport layer packet
+    pub transport: &'a [u8],
+}
+
+/// A received ICMPv6 error message.
+pub struct Icmpv6Error<'a> {
+    /// Destination Unreachable or Time Exceeded
+    pub icmp_type: u8,
+    /// Reason of the error
+    pub code: u8,
+    /// The packet that caused the error
+    pub quoted: Quoted<'a>,
+}
+
+/// Unpack a ICMPv6 Destination Unreachable or Time Exceeded message (RFC 4443, Section 3).
+///
+/// These carry as much of the packet that caused the error as fits,
+/// so it can be matched to what we sent.
+///
+/// Returns None if the packet is not a valid ICMPv6 error message.
+///
+pub fn icmpv6_unpack_error<'a>(packet: &Ipv6Packet<'a>) -> Option<Icmpv6Error<'a>> {
+    let message = packet.payload;
+
+    if packet.next_header != NEXT_HEADER_ICMPV6 || message.len() < 8 || (message[0] != ICMPV6_DESTINATION_UNREACHABLE && message[0] != ICMPV6_TIME_EXCEEDED) {
+        return None;
+    }
+
+    // verify checksum
+    let mut check = message.to_vec();
+    check[2] = 0;
+    check[3] = 0;
+
+    if icmpv6_checksum(&packet.src, &packet.dest, &check).to_be_bytes() != message[2..4] {
+        println!("Received corrupted ICMPv6 message: Dropping it.");
+        return None;
+    }
+
+    // original IPv6 header, followed by at least 8 bytes
+    let quoted = &message[8..];
+    if quoted.len() < 48 || quoted[0] >> 4 != 6 {
+        return None;
+    }
+
+    Some(Icmpv6Error {
+        icmp_type: message[0],
+        code: message[1],
+        quoted: Quoted {
+            src: Ipv6Addr::from(<[u8; 16]>::try_from(&quoted[8..24]).unwrap()),
+            dest: Ipv6Addr::from(<[u8; 16]>::try_from(&quoted[24..40]).unwrap()),
+            next_header: quoted[6],
+            transport: &quoted[40..],
+        },
+    })
+}
+
+/// Unpack a ICMPv6 error about a UDP datagram we sent.
+///
+/// src: Source address and port of our datagrams
+///
+/// Returns None if this is no ICMPv6 error, or it quotes some other datagram.
+///
+pub fn icmpv6_unpack_udp_error<'a>(packet: &Ipv6Packet<'a>, src: SocketAddrV6) -> Option<Icmpv6Error<'a>> {
+    let error = icmpv6_unpack_error(packet)?;
+    let quoted = &error.quoted;
+
+    // check the quoted datagram is ours
+    if quoted.next_header != NEXT_HEADER_UDP || quoted.src != *src.ip() || quoted.transport[0..2] != src.port().to_be_bytes() {
+        return None;
+    }
+
+    Some(error)
+}
+
+/// Turn a ICMPv6 error into an I/O error, like the kernel reports them on sockets.
+///
+/// from: Who sent the ICMPv6 error
+///
+pub fn icmpv6_to_io_error(error: &Icmpv6Error, from: &Ipv6Addr) -> io::Error {
+    let kind = match (error.icmp_type, error.code) {
+        (ICMPV6_DESTINATION_UNREACHABLE, 0) => ErrorKind::NetworkUnreachable,
+        (ICMPV6_DESTINATION_UNREACHABLE, 3) => ErrorKind::HostUnreachable,
+        (ICMPV6_DESTINATION_UNREACHABLE, 4) => ErrorKind::ConnectionRefused,
+        (ICMPV6_DESTINATION_UNREACHABLE, 1 | 5 | 6) => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+
+    io::Error::new(kind, format!("{} (reported by {})", icmpv6_describe_error(error.icmp_type, error.code), from))
+}
+
+/// Describe a ICMPv6 error message for the user (RFC 4443, Section 3).
+pub fn icmpv6_describe_error(icmp_type: u8, code: u8) -> String {
+    match (icmp_type, code) {
+        (ICMPV6_DESTINATION_UNREACHABLE, 0) => String::from("No route to destination"),
+        (ICMPV6_DESTINATION_UNREACHABLE, 1) => String::from("Communication with destination administratively prohibited"),
+        (ICMPV6_DESTINATION_UNREACHABLE, 2) => String::from("Beyond scope of source address"),
+        (ICMPV6_DESTINATION_UNREACHABLE, 3) => String::from("Address unreachable"),
+        (ICMPV6_DESTINATION_UNREACHABLE, 4) => String::from("Port unreachable"),
+        (ICMPV6_DESTINATION_UNREACHABLE, 5) => String::from("Source address failed ingress/egress policy"),
+        (ICMPV6_DESTINATION_UNREACHABLE, 6) => String::from("Reject route to destination"),
+        (ICMPV6_DESTINATION_UNREACHABLE, code) => format!("Destination unreachable (code {})", code),
+        (ICMPV6_TIME_EXCEEDED, 0) => String::from("Hop limit exceeded in transit"),
+        (ICMPV6_TIME_EXCEEDED, 1) => String::from("Fragment reassembly time exceeded"),
+        (ICMPV6_TIME_EXCEEDED, code) => format!("Time exceeded (code {})", code),
+        (icmp_type, code) => format!("ICMPv6 type {} code {}", icmp_type, code),
+    }
+}
+
+/// Checksum compute function.
+pub fn compute_checksum(data: &[u8]) -> u16 {
+    // Ensure the data length is even (for 16-bit processing)
+    if !data.len().is_multiple_of(2) {
+        panic!("Data length must be even");
+    }
+
+    // Sum all 16-bit words
+    let mut sum: u32 = 0;
+    for chunk in data.chunks_exact(2) {
+        let word = u16::from_be_bytes([chunk[0], chunk[1]]);
+        sum += u32::from(word);
+    }
+
+    // Add the carry bits
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    // One's complement of the sum
+    !(sum as u16)
+}
\ No newline at end of file
@@ -0,0 +1,7 @@
+// The raw IPv6 stack the demos share, from Ethernet frames up to TCP
+
+pub mod firewall; // Firewall guard
+pub mod port; // Port management
+pub mod tcp; // Transport layer
+pub mod ip;  // IP layer
+pub mod link;// Link layer
@@ -1,14 +1,11 @@
 // Link layer (ethernet)
-use std::net::{Ipv6Addr, SocketAddrV6};
+use std::net::Ipv6Addr;
 
 use crate::ip::icmpv6_check_neighbor;
 
-/// Create Ethernet packet over IPv6
+/// Push the ethernet header for an IPv6 payload.
 /// 
-pub fn create_ethernet_packet(src_mac: &[u8; 6], dest_mac: &[u8; 6], src: SocketAddrV6, dest: SocketAddrV6) -> Vec<u8> {
-    // Create ethernet frame
-    let mut frame = Vec::new();
-    
+pub fn push_ethernet_header(frame: &mut Vec<u8>, src_mac: &[u8; 6], dest_mac: &[u8; 6]) {
     // push dest/src MAC
     frame.extend_from_slice(dest_mac);
     frame.extend_from_slice(src_mac);
@@ -16,33 +13,31 @@ pub fn create_ethernet_packet(src_mac: &[u8; 6], dest_mac: &[u8; 6], src: Socket
     // push Ethertype 0x86DD (IPv6)
     frame.push(0x86);
     frame.push(0xDD);
+}
 
-    // Create payload of IPv6 packet
-    crate::ip::create_ip_packet(&mut frame, src, dest);
-
-    frame
+/// Get the ethernet multicast MAC of an IPv6 multicast address (RFC 2464, Section 7).
+/// 
+pub fn multicast_mac(addr: &Ipv6Addr) -> [u8; 6] {
+    let o = addr.octets();
+    [0x33, 0x33, o[12], o[13], o[14], o[15]]
 }
 
-/// Unpack ethernet packet
+/// Unpack ethernet frame to return the IPv6 packet it carries.
 ///
-pub fn unpack_ethernet_packet(frame: &[u8], our_mac: &[u8; 6], gateway_mac: &[u8; 6], src: SocketAddrV6, dest: SocketAddrV6) -> Option<String> {
+/// Returns None if this frame was not sent to us, or is not IPv6.
+///
+pub fn unwrap_ip_packet<'a>(frame: &'a [u8], our_mac: &[u8; 6]) -> Option<&'a [u8]> {
     // check we are destination
     if &frame[0..6] != our_mac {
         return None;
     }
 
-    // check source
-    if &frame[6..12] != gateway_mac {
-        return None;
-    }
-
     // Check ethertype
     if frame[12..14] != [0x86, 0xDD] {
         return None;
     }
 
-    // Okay, delegate to IP layer
-    crate::ip::unpack(&frame[14..], src, dest)
+    Some(&frame[14..])
 }
 
 /// Listen for ICMPv6 packet router advertisement.
@@ -67,19 +62,23 @@ pub fn unpack_icmp(frame: &[u8]) -> Option<[u8; 6]> {
 /// Create ICMPv6 router solicitation packet.
 /// 
 pub fn create_eth_router_solicitation(our_mac: &[u8; 6], ip: &Ipv6Addr) -> Vec<u8> {
-    // Create ethernet frame
+    // Create ethernet frame to all routers (ff02::2)
     let mut frame = Vec::new();
-    
-    // push dest/src MAC
-    frame.extend_from_slice(&[0x33, 0x33, 0x00, 0x00, 0x00, 0x02]);
-    frame.extend_from_slice(our_mac);
-
-    // push Ethertype 0x86DD (IPv6)
-    frame.push(0x86);
-    frame.push(0xDD);
+    push_ethernet_header(&mut frame, our_mac, &[0x33, 0x33, 0x00, 0x00, 0x00, 0x02]);
 
     // Create ip layer ICMPv6 payload
     crate::ip::icmpv6_create_router_solicitation(&mut frame, ip);
 
     frame
-}
\ No newline at end of file
+}
+
+/// Get the MTU of an interface.
+///
+/// Read from sysfs, 1500 (the ethernet default) if that fails.
+///
+pub fn interface_mtu(name: &str) -> usize {
+    std::fs::read_to_string(format!("/sys/class/net/{}/mtu", name))
+        .ok()
+        .and_then(|mtu| mtu.trim().parse().ok())
+        .unwrap_or(1500)
+}
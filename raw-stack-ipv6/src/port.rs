@@ -0,0 +1,96 @@
+// Port management (which ports the kernel uses)
+use std::{collections::HashSet, fs, io::{self, ErrorKind}};
+
+/// Transport protocol of a port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// UDP, the kernel answers unknown ports with port unreachable
+    Udp,
+    /// TCP, the kernel answers unknown connections with RST
+    Tcp,
+}
+
+impl Protocol {
+    /// Name of the protocol, as /proc/net, nft and iptables know it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Udp => "udp",
+            Protocol::Tcp => "tcp",
+        }
+    }
+}
+
+/// Ports the kernel has sockets bound on, from /proc/net/<protocol> and /proc/net/<protocol>6.
+///
+/// Both tables are read, since IPv6 sockets may also receive IPv4 traffic.
+///
+pub fn ports_in_use(protocol: Protocol) -> io::Result<HashSet<u16>> {
+    let mut ports = HashSet::new();
+
+    for path in [format!("/proc/net/{}", protocol.name()), format!("/proc/net/{}6", protocol.name())] {
+        let table = match fs::read_to_string(path) {
+            Ok(table) => table,
+            // kernel without IPv6
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        // skip the header, the local address is the second column as hex ADDRESS:PORT
+        for line in table.lines().skip(1) {
+            let Some(local) = line.split_whitespace().nth(1) else {
+                continue;
+            };
+
+            if let Some((_, port)) = local.rsplit_once(':') && let Ok(port) = u16::from_str_radix(port, 16) {
+                ports.insert(port);
+            }
+        }
+    }
+
+    Ok(ports)
+}
+
+/// The range the kernel chooses ephemeral ports from (usually 32768 to 60999).
+pub fn ephemeral_port_range() -> (u16, u16) {
+    let range = fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range").ok().and_then(|range| {
+        let mut bounds = range.split_whitespace().map(|b| b.parse::<u16>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(low)), Some(Ok(high))) if low <= high => Some((low, high)),
+            _ => None,
+        }
+    });
+
+    range.unwrap_or((32768, 60999))
+}
+
+/// Choose a random port from the ephemeral range the kernel does not use.
+pub fn choose_ephemeral_port(protocol: Protocol) -> io::Result<u16> {
+    let (low, high) = ephemeral_port_range();
+    choose_port(protocol, low, high)
+}
+
+/// Choose a random port from 1024 to 65535 the kernel does not use.
+///
+/// This is the range RFC 6056, Section 3.2 recommends, about twice the
+/// ephemeral range, so the port is harder to guess for someone spoofing
+/// the replies (RFC 9109 asks this of NTP clients).
+///
+pub fn choose_random_port(protocol: Protocol) -> io::Result<u16> {
+    choose_port(protocol, 1024, 65535)
+}
+
+/// Choose a random port from low to high the kernel does not use.
+fn choose_port(protocol: Protocol, low: u16, high: u16) -> io::Result<u16> {
+    let in_use = ports_in_use(protocol)?;
+
+    // the range is usually mostly free, so a few random tries are enough
+    for _ in 0..64 {
+        let port = rand::random_range(low..=high);
+        if !in_use.contains(&port) {
+            return Ok(port);
+        }
+    }
+
+    // else search it through
+    (low..=high).find(|port| !in_use.contains(port)).ok_or_else(|| io::Error::new(ErrorKind::AddrInUse, format!("No free {} port from {} to {}", protocol.name(), low, high)))
+}